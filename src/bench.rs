@@ -0,0 +1,435 @@
+use crate::options::{BenchArgs, BenchReport, JpegChromaSubsampling};
+use crate::progress::init_progress_bar;
+use crate::{parse_jpeg_chroma_subsampling, ExitStatus};
+use bytesize::ByteSize;
+use caesiumclt::{
+    compress_file_in_memory, scan_files, CompressionOptions, FileFilters, OutputFormat, ScanOptions, SilentEvents,
+    SortKey,
+};
+use clap::ValueEnum;
+use image::GrayImage;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+/// Side of the square windows the SSIM is computed on
+const SSIM_WINDOW: u32 = 8;
+const SSIM_C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// One combination of the settings grid
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct BenchSettings {
+    format: OutputFormat,
+    quality: u32,
+    png_opt_level: u8,
+    zopfli: bool,
+    jpeg_chroma_subsampling: JpegChromaSubsampling,
+}
+
+#[derive(Serialize, Debug)]
+struct BenchRow {
+    format: String,
+    quality: u32,
+    png_opt_level: u8,
+    zopfli: bool,
+    jpeg_chroma_subsampling: String,
+    files: usize,
+    errors: usize,
+    original_size: u64,
+    compressed_size: u64,
+    savings_percent: f64,
+    time_ms: f64,
+    ssim: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct BenchOutput<'a> {
+    version: &'static str,
+    results: &'a [BenchRow],
+}
+
+struct FileBenchResult {
+    original_size: u64,
+    compressed_size: Option<u64>,
+    time: Duration,
+    ssim: Option<f64>,
+}
+
+pub fn run_bench(args: &BenchArgs) {
+    let scan_options = ScanOptions {
+        recursive: args.recursive,
+        max_depth: None,
+        follow_symlinks: false,
+        skip_hidden: false,
+        respect_ignore: false,
+        check_extension_only: false,
+        filters: FileFilters::default(),
+        sort_key: SortKey::Path,
+        sample: None,
+    };
+    let scan_spinner = init_progress_bar(false);
+    let (base_path, input_files) = scan_files(&args.files, &scan_options, &SilentEvents);
+    scan_spinner.finish_and_clear();
+    let Some(base_path) = base_path.filter(|_| !input_files.is_empty()) else {
+        eprintln!("No files to compress");
+        exit(ExitStatus::NoFiles.code());
+    };
+
+    let grid = settings_grid(args);
+    let progress_bar = setup_progress_bar((grid.len() * input_files.len()) as u64);
+    let rows: Vec<BenchRow> = grid
+        .iter()
+        .map(|settings| {
+            let options = build_bench_options(settings, &base_path);
+            let results: Vec<FileBenchResult> = input_files
+                .par_iter()
+                .map(|input_file| {
+                    let result = bench_file(input_file, &options, args.ssim);
+                    progress_bar.inc(1);
+                    result
+                })
+                .collect();
+            build_row(settings, &results, args.ssim)
+        })
+        .collect();
+    progress_bar.finish_and_clear();
+
+    let report = match args.report {
+        BenchReport::Table => format_table(&rows),
+        BenchReport::Csv => format_csv(&rows),
+        BenchReport::Json => format_json(&rows),
+    };
+    print!("{report}");
+}
+
+/// Every combination of the requested settings
+fn settings_grid(args: &BenchArgs) -> Vec<BenchSettings> {
+    let mut grid = Vec::new();
+    for &format in &args.formats {
+        for &quality in &args.qualities {
+            for &png_opt_level in &args.png_opt_levels {
+                for &zopfli in &args.zopfli {
+                    for &jpeg_chroma_subsampling in &args.jpeg_chroma_subsamplings {
+                        grid.push(BenchSettings {
+                            format,
+                            quality,
+                            png_opt_level,
+                            zopfli,
+                            jpeg_chroma_subsampling,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    grid
+}
+
+fn build_bench_options(settings: &BenchSettings, base_path: &Path) -> CompressionOptions {
+    CompressionOptions::builder()
+        .quality(Some(settings.quality))
+        .png_opt_level(settings.png_opt_level)
+        .zopfli(settings.zopfli)
+        .base_path(base_path.to_path_buf())
+        .format(settings.format)
+        .jpeg_chroma_subsampling(parse_jpeg_chroma_subsampling(settings.jpeg_chroma_subsampling))
+        .max_input_size(u64::MAX)
+        .build()
+}
+
+fn bench_file(input_file: &PathBuf, options: &CompressionOptions, compute_ssim: bool) -> FileBenchResult {
+    let original_size = fs::metadata(input_file).map(|metadata| metadata.len()).unwrap_or(0);
+
+    let start = Instant::now();
+    let compressed_image = compress_file_in_memory(input_file, options).ok();
+    let time = start.elapsed();
+
+    let ssim = compressed_image
+        .as_deref()
+        .filter(|_| compute_ssim)
+        .and_then(|compressed_image| ssim(&fs::read(input_file).ok()?, compressed_image));
+
+    FileBenchResult {
+        original_size,
+        compressed_size: compressed_image.map(|image| image.len() as u64),
+        time,
+        ssim,
+    }
+}
+
+/// Totals of the files compressed with the settings, errors excluded from the sizes
+fn build_row(settings: &BenchSettings, results: &[FileBenchResult], compute_ssim: bool) -> BenchRow {
+    let compressed: Vec<(u64, u64)> = results
+        .iter()
+        .filter_map(|result| Some((result.original_size, result.compressed_size?)))
+        .collect();
+    let original_size: u64 = compressed.iter().map(|(original, _)| original).sum();
+    let compressed_size: u64 = compressed.iter().map(|(_, compressed)| compressed).sum();
+    let savings_percent = if original_size > 0 {
+        ((original_size as f64 - compressed_size as f64) / original_size as f64) * 100.0
+    } else {
+        0.0
+    };
+
+    let ssim_values: Vec<f64> = results.iter().filter_map(|result| result.ssim).collect();
+    let ssim =
+        (compute_ssim && !ssim_values.is_empty()).then(|| ssim_values.iter().sum::<f64>() / ssim_values.len() as f64);
+
+    BenchRow {
+        format: value_name(settings.format),
+        quality: settings.quality,
+        png_opt_level: settings.png_opt_level,
+        zopfli: settings.zopfli,
+        jpeg_chroma_subsampling: value_name(settings.jpeg_chroma_subsampling),
+        files: results.len(),
+        errors: results.len() - compressed.len(),
+        original_size,
+        compressed_size,
+        savings_percent,
+        time_ms: results.iter().map(|result| result.time).sum::<Duration>().as_secs_f64() * 1000.0,
+        ssim,
+    }
+}
+
+/// Mean SSIM of the luma of the two images, computed on non-overlapping windows.
+/// Returns None if either cannot be decoded or their sizes differ.
+fn ssim(original: &[u8], compressed: &[u8]) -> Option<f64> {
+    let original = image::load_from_memory(original).ok()?.to_luma8();
+    let compressed = image::load_from_memory(compressed).ok()?.to_luma8();
+    if original.dimensions() != compressed.dimensions() {
+        return None;
+    }
+
+    let (width, height) = original.dimensions();
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y in (0..height).step_by(SSIM_WINDOW as usize) {
+        for x in (0..width).step_by(SSIM_WINDOW as usize) {
+            let window_width = SSIM_WINDOW.min(width - x);
+            let window_height = SSIM_WINDOW.min(height - y);
+            total += window_ssim(&original, &compressed, x, y, window_width, window_height);
+            windows += 1;
+        }
+    }
+
+    (windows > 0).then(|| total / windows as f64)
+}
+
+fn window_ssim(original: &GrayImage, compressed: &GrayImage, x: u32, y: u32, width: u32, height: u32) -> f64 {
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for j in y..y + height {
+        for i in x..x + width {
+            let a = original.get_pixel(i, j)[0] as f64;
+            let b = compressed.get_pixel(i, j)[0] as f64;
+            sum_a += a;
+            sum_b += b;
+            sum_aa += a * a;
+            sum_bb += b * b;
+            sum_ab += a * b;
+        }
+    }
+
+    let count = (width * height) as f64;
+    let (mean_a, mean_b) = (sum_a / count, sum_b / count);
+    let variance_a = sum_aa / count - mean_a * mean_a;
+    let variance_b = sum_bb / count - mean_b * mean_b;
+    let covariance = sum_ab / count - mean_a * mean_b;
+
+    ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+        / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1) * (variance_a + variance_b + SSIM_C2))
+}
+
+fn value_name(value: impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+fn format_table(rows: &[BenchRow]) -> String {
+    let header = [
+        "Format",
+        "Quality",
+        "PNG opt",
+        "Zopfli",
+        "Chroma",
+        "Files",
+        "Errors",
+        "Original",
+        "Compressed",
+        "Savings",
+        "Time",
+        "SSIM",
+    ];
+    let mut lines: Vec<Vec<String>> = vec![header.iter().map(|title| title.to_string()).collect()];
+    for row in rows {
+        lines.push(vec![
+            row.format.clone(),
+            row.quality.to_string(),
+            row.png_opt_level.to_string(),
+            row.zopfli.to_string(),
+            row.jpeg_chroma_subsampling.clone(),
+            row.files.to_string(),
+            row.errors.to_string(),
+            ByteSize::b(row.original_size).to_string(),
+            ByteSize::b(row.compressed_size).to_string(),
+            format!("{:.2}%", row.savings_percent),
+            format!("{:.0}ms", row.time_ms),
+            row.ssim
+                .map(|ssim| format!("{ssim:.4}"))
+                .unwrap_or_else(|| "-".to_string()),
+        ]);
+    }
+
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| lines.iter().map(|line| line[column].len()).max().unwrap_or(0))
+        .collect();
+    lines
+        .iter()
+        .map(|line| {
+            let cells: Vec<String> = line
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect();
+            format!("{}\n", cells.join("  ").trim_end())
+        })
+        .collect()
+}
+
+fn format_csv(rows: &[BenchRow]) -> String {
+    let mut csv = String::from(
+        "format,quality,png_opt_level,zopfli,jpeg_chroma_subsampling,files,errors,original_size,compressed_size,\
+         savings_percent,time_ms,ssim\n",
+    );
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{},{:.2},{:.2},{}\n",
+            row.format,
+            row.quality,
+            row.png_opt_level,
+            row.zopfli,
+            row.jpeg_chroma_subsampling,
+            row.files,
+            row.errors,
+            row.original_size,
+            row.compressed_size,
+            row.savings_percent,
+            row.time_ms,
+            row.ssim.map(|ssim| format!("{ssim:.4}")).unwrap_or_default(),
+        ));
+    }
+
+    csv
+}
+
+fn format_json(rows: &[BenchRow]) -> String {
+    let output = BenchOutput {
+        version: "1.0.0",
+        results: rows,
+    };
+    serde_json::to_string(&output).unwrap_or_else(|e| format!("{{\"error\":\"JSON serialization failed: {e}\"}}"))
+        + "\n"
+}
+
+fn setup_progress_bar(len: u64) -> ProgressBar {
+    let progress_bar = ProgressBar::new(len);
+    progress_bar.set_draw_target(ProgressDrawTarget::stderr());
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len}")
+            .unwrap_or(ProgressStyle::default_bar())
+            .progress_chars("#>-"),
+    );
+
+    progress_bar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use image::{ImageFormat, Luma};
+    use std::io::Cursor;
+
+    fn bench_args(args: &[&str]) -> BenchArgs {
+        let args = crate::options::CommandLineArgs::parse_from([&["caesiumclt", "bench"], args].concat());
+        match args.command {
+            Some(crate::options::Command::Bench(bench_args)) => bench_args,
+            None => panic!("bench subcommand not parsed"),
+        }
+    }
+
+    #[test]
+    fn test_settings_grid() {
+        let args = bench_args(&["--qualities", "60,80", "--formats", "jpeg,webp", "samples"]);
+        let grid = settings_grid(&args);
+        assert_eq!(grid.len(), 4);
+        assert_eq!(grid[1].format, OutputFormat::Jpeg);
+        assert_eq!(grid[1].quality, 80);
+        assert!(grid
+            .iter()
+            .all(|settings| settings.png_opt_level == 3 && !settings.zopfli));
+
+        let args = bench_args(&["--zopfli", "false,true", "--png-opt-levels", "2,4,6", "samples"]);
+        assert_eq!(settings_grid(&args).len(), 6);
+    }
+
+    fn encode_png(image: &GrayImage) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_ssim() {
+        let gradient = GrayImage::from_fn(20, 20, |x, y| Luma([(x * 10 + y) as u8]));
+        let noisy = GrayImage::from_fn(20, 20, |x, y| Luma([(x * 10 + y) as u8 ^ ((x + y) % 2 * 40) as u8]));
+
+        let identical = ssim(&encode_png(&gradient), &encode_png(&gradient)).unwrap();
+        assert!((identical - 1.0).abs() < 1e-9);
+        let degraded = ssim(&encode_png(&gradient), &encode_png(&noisy)).unwrap();
+        assert!(degraded < 0.9);
+
+        assert!(ssim(&encode_png(&gradient), &encode_png(&GrayImage::new(10, 10))).is_none());
+    }
+
+    #[test]
+    fn test_bench_rows_and_reports() {
+        let args = bench_args(&[
+            "--qualities",
+            "40,90",
+            "--ssim",
+            "samples/p0.png",
+            "samples/missing.png",
+        ]);
+        let options = build_bench_options(&settings_grid(&args)[0], Path::new("samples"));
+        let results = vec![
+            bench_file(&PathBuf::from("samples/p0.png"), &options, true),
+            bench_file(&PathBuf::from("samples/missing.png"), &options, true),
+        ];
+        let row = build_row(&settings_grid(&args)[0], &results, true);
+        assert_eq!((row.files, row.errors), (2, 1));
+        assert!(row.compressed_size > 0 && row.compressed_size < row.original_size);
+        assert!(row.ssim.is_some_and(|ssim| ssim > 0.5 && ssim <= 1.0));
+
+        let rows = vec![row];
+        let csv = format_csv(&rows);
+        assert!(csv.starts_with("format,quality,png_opt_level,zopfli,jpeg_chroma_subsampling,files,errors,"));
+        assert!(csv.lines().nth(1).unwrap().starts_with("original,40,3,false,auto,2,1,"));
+
+        let table = format_table(&rows);
+        assert_eq!(table.lines().count(), 2);
+        assert!(table.starts_with("Format"));
+
+        let parsed: serde_json::Value = serde_json::from_str(&format_json(&rows)).unwrap();
+        assert_eq!(parsed["results"][0]["quality"], 40);
+        assert_eq!(parsed["results"][0]["errors"], 1);
+    }
+}
@@ -1,11 +1,19 @@
-use crate::options::{MinSavingsThreshold, OutputFormat, OverwritePolicy};
+use crate::events::CompressionEvents;
+use crate::metadata;
+use crate::metadata::{MetadataKind, MetadataValue, StripTag};
+use crate::transform;
+use crate::transform::{Flip, Rotation, SrgbProfile, WatermarkPosition};
 use serde::Serialize;
 // use crate::scan_files::get_file_mime_type;
+use caesium::error::CaesiumError;
 use caesium::parameters::{CSParameters, ChromaSubsampling};
 use caesium::{compress_in_memory, compress_to_size_in_memory, convert_in_memory, SupportedFileTypes};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use rayon::iter::ParallelIterator;
-use rayon::prelude::IntoParallelRefIterator;
+use clap::ValueEnum;
+use image::RgbaImage;
+use rayon::iter::{ParallelBridge, ParallelIterator};
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::ffi::OsString;
 use std::fmt::Display;
@@ -14,9 +22,13 @@ use std::io::{Read, Write};
 #[cfg(target_os = "windows")]
 use std::os::windows::fs::FileTimesExt;
 use std::path::{absolute, Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{mpsc, Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
 use std::{fs, io};
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum CompressionStatus {
     Success,
@@ -33,7 +45,67 @@ impl Display for CompressionStatus {
         }
     }
 }
-#[derive(Debug, Serialize)]
+
+/// Stable reason of a failed or skipped file, unlike the message which is meant to be read
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    IoRead,
+    IoWrite,
+    PermissionDenied,
+    DecodeFailed,
+    UnsupportedFormat,
+    CompressionFailed,
+    MetadataFailed,
+    TooLarge,
+    Timeout,
+    OutputPath,
+    OutputCollision,
+    OutputExists,
+    InsufficientSavings,
+    UnsupportedTransform,
+}
+
+impl ErrorCode {
+    fn from_io_error(error: &io::Error, fallback: ErrorCode) -> Self {
+        match error.kind() {
+            io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            _ => fallback,
+        }
+    }
+
+    fn from_caesium_error(error: &CaesiumError) -> Self {
+        match error.code {
+            CAESIUM_UNKNOWN_FILE_TYPE | CAESIUM_UNSUPPORTED_FORMAT => ErrorCode::UnsupportedFormat,
+            code if CAESIUM_DECODE_ERRORS.contains(&code) => ErrorCode::DecodeFailed,
+            _ => ErrorCode::CompressionFailed,
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = match self {
+            ErrorCode::IoRead => "io_read",
+            ErrorCode::IoWrite => "io_write",
+            ErrorCode::PermissionDenied => "permission_denied",
+            ErrorCode::DecodeFailed => "decode_failed",
+            ErrorCode::UnsupportedFormat => "unsupported_format",
+            ErrorCode::CompressionFailed => "compression_failed",
+            ErrorCode::MetadataFailed => "metadata_failed",
+            ErrorCode::TooLarge => "too_large",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::OutputPath => "output_path",
+            ErrorCode::OutputCollision => "output_collision",
+            ErrorCode::OutputExists => "output_exists",
+            ErrorCode::InsufficientSavings => "insufficient_savings",
+            ErrorCode::UnsupportedTransform => "unsupported_transform",
+        };
+        write!(f, "{code}")
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct CompressionResult {
     pub original_path: String,
     pub output_path: String,
@@ -41,13 +113,109 @@ pub struct CompressionResult {
     pub compressed_size: u64,
     pub status: CompressionStatus,
     pub message: String,
+    pub error_code: Option<ErrorCode>,
+    /// The underlying error, when there is one
+    pub cause: Option<String>,
 }
 
+impl CompressionResult {
+    fn set_error(&mut self, error_code: ErrorCode, message: impl Into<String>, cause: Option<String>) {
+        self.status = CompressionStatus::Error;
+        self.error_code = Some(error_code);
+        self.message = message.into();
+        self.cause = cause;
+    }
+
+    fn set_metadata_error(&mut self, error: impl Display) {
+        let cause = error.to_string();
+        self.set_error(
+            ErrorCode::MetadataFailed,
+            format!("Error writing metadata: {cause}"),
+            Some(cause),
+        );
+    }
+
+    fn set_skipped(&mut self, error_code: ErrorCode, message: impl Into<String>) {
+        self.status = CompressionStatus::Skipped;
+        self.error_code = Some(error_code);
+        self.message = message.into();
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MinSavingsThreshold {
+    /// Percentage-based threshold (0.0-100.0)
+    Percentage(f64),
+    /// Byte-based threshold (absolute size)
+    Bytes(u64),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum OverwritePolicy {
+    /// Always overwrite existing files
+    All,
+    /// Never overwrite existing files
+    Never,
+    /// Overwrite only if the existing file is bigger
+    Bigger,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum DryRunMode {
+    /// Only check the files and the output paths
+    Simulate,
+    /// Compress in memory and report the projected savings
+    Estimate,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum CollisionPolicy {
+    /// Stop before compressing anything
+    Error,
+    /// Add a numbered suffix to the output file name
+    Rename,
+    /// Skip the file
+    Skip,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum FileOrder {
+    /// Largest files first, so that none is left running alone at the end
+    SizeDesc,
+    /// Smallest files first
+    SizeAsc,
+    /// By path
+    Path,
+    /// Least recently modified files first
+    Mtime,
+    /// As they are found, compressing while the scan goes on. Suits very large folders, as the compression starts
+    /// right away instead of waiting for the whole scan. The paths and results of all the files are still kept. Only
+    /// with the `Rename` and `Skip` collision policies and without sampling, which need all the files first
+    Scan,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Tiff,
+    Original,
+}
+
+/// Built with [`CompressionOptions::builder`], the fields being readable only
+#[derive(Clone)]
+#[non_exhaustive]
 pub struct CompressionOptions {
     pub quality: Option<u32>,
     pub max_size: Option<usize>,
     pub lossless: bool,
     pub exif: bool,
+    pub keep_metadata: Vec<MetadataKind>,
+    pub strip_tags: Vec<StripTag>,
+    pub set_meta: Vec<MetadataValue>,
+    pub dpi: Option<u32>,
     pub png_opt_level: u8,
     pub zopfli: bool,
     pub width: Option<u32>,
@@ -67,40 +235,388 @@ pub struct CompressionOptions {
     pub no_upscale: bool,
     pub strip_icc: bool,
     pub min_savings: Option<MinSavingsThreshold>,
+    pub max_input_size: u64,
+    pub memory_limit: Option<u64>,
+    pub order: FileOrder,
+    pub(crate) collisions: HashMap<PathBuf, CollisionResolution>,
+    pub file_timeout: Option<Duration>,
+    pub timeout_fallback: bool,
+    pub auto_orient: bool,
+    pub convert_to_srgb: bool,
+    pub srgb_profile: SrgbProfile,
+    pub grayscale: bool,
+    pub colors: Option<u32>,
+    pub dithering: f32,
+    pub watermark: Option<WatermarkOptions>,
+    pub rotate: Option<Rotation>,
+    pub flip: Option<Flip>,
 }
 
-const MAX_FILE_SIZE: u64 = 500 * 1024 * 1024;
+#[derive(Clone)]
+pub struct WatermarkOptions {
+    pub image: RgbaImage,
+    pub position: WatermarkPosition,
+    pub opacity: f32,
+    pub scale: f32,
+}
 
-pub fn start_compression(
+impl CompressionOptions {
+    /// Starts from the defaults of the command line, the quality being 80 unless set
+    pub fn builder() -> CompressionOptionsBuilder {
+        CompressionOptionsBuilder {
+            options: CompressionOptions {
+                quality: None,
+                max_size: None,
+                lossless: false,
+                exif: false,
+                keep_metadata: vec![],
+                strip_tags: vec![],
+                set_meta: vec![],
+                dpi: None,
+                png_opt_level: DEFAULT_PNG_OPT_LEVEL,
+                zopfli: false,
+                width: None,
+                height: None,
+                long_edge: None,
+                short_edge: None,
+                output_folder: None,
+                same_folder_as_input: false,
+                base_path: PathBuf::new(),
+                suffix: None,
+                overwrite_policy: OverwritePolicy::All,
+                format: OutputFormat::Original,
+                keep_dates: false,
+                keep_structure: false,
+                jpeg_chroma_subsampling: ChromaSubsampling::Auto,
+                jpeg_baseline: false,
+                no_upscale: false,
+                strip_icc: false,
+                min_savings: None,
+                max_input_size: DEFAULT_MAX_INPUT_SIZE,
+                memory_limit: None,
+                order: FileOrder::SizeDesc,
+                collisions: HashMap::new(),
+                file_timeout: None,
+                timeout_fallback: false,
+                auto_orient: false,
+                convert_to_srgb: false,
+                srgb_profile: SrgbProfile::Strip,
+                grayscale: false,
+                colors: None,
+                dithering: DEFAULT_DITHERING,
+                watermark: None,
+                rotate: None,
+                flip: None,
+            },
+        }
+    }
+}
+
+/// Sets the options one by one, each setter taking the value of the field of the same name in `CompressionOptions`
+pub struct CompressionOptionsBuilder {
+    options: CompressionOptions,
+}
+
+macro_rules! setters {
+    ($($field:ident: $type:ty),* $(,)?) => {
+        $(
+            pub fn $field(mut self, $field: $type) -> Self {
+                self.options.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+impl CompressionOptionsBuilder {
+    setters! {
+        quality: Option<u32>,
+        max_size: Option<usize>,
+        lossless: bool,
+        exif: bool,
+        keep_metadata: Vec<MetadataKind>,
+        strip_tags: Vec<StripTag>,
+        set_meta: Vec<MetadataValue>,
+        dpi: Option<u32>,
+        png_opt_level: u8,
+        zopfli: bool,
+        width: Option<u32>,
+        height: Option<u32>,
+        long_edge: Option<u32>,
+        short_edge: Option<u32>,
+        output_folder: Option<PathBuf>,
+        same_folder_as_input: bool,
+        base_path: PathBuf,
+        suffix: Option<String>,
+        overwrite_policy: OverwritePolicy,
+        format: OutputFormat,
+        keep_dates: bool,
+        keep_structure: bool,
+        jpeg_chroma_subsampling: ChromaSubsampling,
+        jpeg_baseline: bool,
+        no_upscale: bool,
+        strip_icc: bool,
+        min_savings: Option<MinSavingsThreshold>,
+        max_input_size: u64,
+        memory_limit: Option<u64>,
+        order: FileOrder,
+        file_timeout: Option<Duration>,
+        timeout_fallback: bool,
+        auto_orient: bool,
+        convert_to_srgb: bool,
+        srgb_profile: SrgbProfile,
+        grayscale: bool,
+        colors: Option<u32>,
+        dithering: f32,
+        watermark: Option<WatermarkOptions>,
+        rotate: Option<Rotation>,
+        flip: Option<Flip>,
+    }
+
+    pub fn build(self) -> CompressionOptions {
+        self.options
+    }
+}
+
+const DEFAULT_PNG_OPT_LEVEL: u8 = 3;
+const DEFAULT_MAX_INPUT_SIZE: u64 = 500 * 1024 * 1024;
+const DEFAULT_DITHERING: f32 = 1.0;
+const FALLBACK_PNG_OPT_LEVEL: u8 = 1;
+const STREAM_QUEUED_FILES_PER_THREAD: u64 = 2;
+// Threads compressing a file with a timeout, per thread of the pool: the one being waited for and one left behind
+const TIMEOUT_WORKERS_PER_THREAD: usize = 2;
+const DECODED_BYTES_PER_PIXEL: u64 = 4;
+const DECODED_COPIES: u64 = 2;
+// Codes of the libcaesium errors meaning that the file type cannot be handled
+const CAESIUM_UNKNOWN_FILE_TYPE: u32 = 10000;
+const CAESIUM_UNSUPPORTED_FORMAT: u32 = 10200;
+// Codes of the libcaesium errors raised while decoding the input: when resizing, when converting, then by format
+const CAESIUM_DECODE_ERRORS: [u32; 9] = [10301, 10403, 20104, 20204, 20210, 20304, 20404, 20406, 20504];
+
+pub(crate) fn start_compression(
     input_files: &[PathBuf],
     options: &CompressionOptions,
-    multi_progress: &MultiProgress,
-    progress_bar: &ProgressBar,
-    dry_run: bool,
+    dry_run: Option<DryRunMode>,
+    events: &dyn CompressionEvents,
 ) -> Vec<CompressionResult> {
-    input_files
-        .par_iter()
-        .map(|input_file| {
-            let spinner = multi_progress.add(ProgressBar::new_spinner());
-            spinner.set_style(
-                ProgressStyle::default_spinner()
-                    .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
-                    .template("{spinner:.cyan} {msg}")
-                    .unwrap_or(ProgressStyle::default_spinner()),
-            );
-            spinner.set_message(format!("{}", input_file.display()));
-            spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+    // Shared with the threads compressing with a timeout, which may outlive the run
+    let options = &Arc::new(options.clone());
+    let compress = |input_file: &PathBuf, reservation: Option<MemoryReservation>| {
+        compress_file(
+            input_file,
+            options,
+            options.collisions.get(input_file),
+            reservation,
+            dry_run,
+            events,
+        )
+    };
+
+    let execution_order = execution_order(input_files, options.order);
+    let results: Vec<CompressionResult> = match options.memory_limit {
+        None => {
+            // The files are handed to the workers one at a time in the execution order, then put back in the input
+            // order
+            let mut results: Vec<(usize, CompressionResult)> = execution_order
+                .into_iter()
+                .par_bridge()
+                .map(|index| (index, compress(&input_files[index], None)))
+                .collect();
+            results.sort_unstable_by_key(|(index, _)| *index);
+            results.into_iter().map(|(_, result)| result).collect()
+        }
+        Some(memory_limit) => {
+            // Files are admitted one after the other from this thread, which is not part of the pool, so that the
+            // workers never wait for memory while holding some
+            let budget = Arc::new(MemoryBudget::new(memory_limit));
+            let results: Vec<Mutex<Option<CompressionResult>>> = input_files.iter().map(|_| Mutex::new(None)).collect();
+            rayon::in_place_scope(|scope| {
+                for index in execution_order {
+                    let (input_file, slot) = (&input_files[index], &results[index]);
+                    let reservation = budget.reserve(estimate_memory_usage(input_file, options, dry_run));
+                    let compress = &compress;
+                    scope.spawn(move |_| {
+                        let result = compress(input_file, Some(reservation));
+                        *slot.lock().unwrap_or_else(PoisonError::into_inner) = Some(result);
+                    });
+                }
+            });
+
+            results
+                .into_iter()
+                .filter_map(|slot| slot.into_inner().unwrap_or_else(PoisonError::into_inner))
+                .collect()
+        }
+    };
+
+    events.batch_finished(&results);
+    results
+}
+
+/// Compresses the files as they are received, while they are still being found, and returns the results in the order
+/// the files were received, each one with the key it was received with. Each file received is reported as found before
+/// being queued
+pub(crate) fn compress_stream<K: Send>(
+    input_files: Receiver<(PathBuf, K)>,
+    options: &CompressionOptions,
+    output_claims: &OutputClaims,
+    dry_run: Option<DryRunMode>,
+    events: &dyn CompressionEvents,
+) -> Vec<(K, CompressionResult)> {
+    let options = &Arc::new(options.clone());
+    let budget = options
+        .memory_limit
+        .map(|memory_limit| Arc::new(MemoryBudget::new(memory_limit)));
+    // Counts the files waiting for a thread, one each, so that they are left in the channel and the scan waits
+    let queue = Arc::new(MemoryBudget::new(
+        rayon::current_num_threads() as u64 * STREAM_QUEUED_FILES_PER_THREAD,
+    ));
+    let results: Mutex<Vec<(usize, K, CompressionResult)>> = Mutex::new(vec![]);
+
+    // As with a memory limit in start_compression, files are admitted from this thread, which is not part of the pool
+    rayon::in_place_scope(|scope| {
+        for (index, (input_file, key)) in input_files.into_iter().enumerate() {
+            events.file_found(&input_file);
+            let collision = output_claims.claim(&input_file, options);
+            let queued = queue.reserve(1);
+            let reservation = budget
+                .as_ref()
+                .map(|budget| budget.reserve(estimate_memory_usage(&input_file, options, dry_run)));
+            let results = &results;
+            scope.spawn(move |_| {
+                drop(queued);
+                let result = compress_file(&input_file, options, collision.as_ref(), reservation, dry_run, events);
+                results
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push((index, key, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap_or_else(PoisonError::into_inner);
+    results.sort_unstable_by_key(|(index, _, _)| *index);
+    let (keys, results): (Vec<K>, Vec<CompressionResult>) =
+        results.into_iter().map(|(_, key, result)| (key, result)).unzip();
+    events.batch_finished(&results);
+    keys.into_iter().zip(results).collect()
+}
+
+/// The memory reserved for the file is released once it is done, or once its compression thread ends if it timed out
+fn compress_file(
+    input_file: &PathBuf,
+    options: &Arc<CompressionOptions>,
+    collision: Option<&CollisionResolution>,
+    reservation: Option<MemoryReservation>,
+    dry_run: Option<DryRunMode>,
+    events: &dyn CompressionEvents,
+) -> CompressionResult {
+    let reservation = reservation.map(Arc::new);
+    events.file_started(input_file);
+    let result = perform_compression(input_file, options, collision, reservation.as_ref(), dry_run);
+    events.file_finished(&result);
+    result
+}
+
+/// Indices of the files in the order they are compressed, ties keeping the input order
+fn execution_order(input_files: &[PathBuf], order: FileOrder) -> Vec<usize> {
+    let file_size = |index: &usize| fs::metadata(&input_files[*index]).map_or(0, |metadata| metadata.len());
+    let mut indices: Vec<usize> = (0..input_files.len()).collect();
+    match order {
+        FileOrder::SizeDesc => indices.sort_by_cached_key(|index| Reverse(file_size(index))),
+        FileOrder::SizeAsc => indices.sort_by_cached_key(file_size),
+        FileOrder::Path => indices.sort_by_key(|index| &input_files[*index]),
+        FileOrder::Mtime => indices.sort_by_cached_key(|index| {
+            fs::metadata(&input_files[*index])
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        }),
+        FileOrder::Scan => {}
+    }
+
+    indices
+}
 
-            let result = perform_compression(input_file, options, dry_run);
+/// Memory shared by the files being compressed
+struct MemoryBudget {
+    limit: u64,
+    used: Mutex<u64>,
+    released: Condvar,
+}
+
+struct MemoryReservation {
+    budget: Arc<MemoryBudget>,
+    amount: u64,
+}
 
-            spinner.finish_and_clear();
-            progress_bar.inc(1);
-            result
+impl MemoryBudget {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits until the amount fits in the budget. A file needing more than the whole budget runs alone
+    fn reserve(self: &Arc<Self>, amount: u64) -> MemoryReservation {
+        let mut used = self.used.lock().unwrap_or_else(PoisonError::into_inner);
+        while *used > 0 && used.saturating_add(amount) > self.limit {
+            used = self.released.wait(used).unwrap_or_else(PoisonError::into_inner);
+        }
+        *used += amount;
+
+        MemoryReservation {
+            budget: Arc::clone(self),
+            amount,
+        }
+    }
+
+    /// Takes the amount only if it fits in the budget right away, for the threads already holding some
+    fn try_reserve(self: &Arc<Self>, amount: u64) -> Option<MemoryReservation> {
+        let mut used = self.used.lock().unwrap_or_else(PoisonError::into_inner);
+        if *used > 0 && used.saturating_add(amount) > self.limit {
+            return None;
+        }
+        *used += amount;
+
+        Some(MemoryReservation {
+            budget: Arc::clone(self),
+            amount,
         })
-        .collect()
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        let mut used = self.budget.used.lock().unwrap_or_else(PoisonError::into_inner);
+        *used -= self.amount;
+        self.budget.released.notify_all();
+    }
+}
+
+/// Rough peak memory used to compress the file: the input and output buffers, plus the decoded pixels and a working
+/// copy of them
+fn estimate_memory_usage(input_file: &Path, options: &CompressionOptions, dry_run: Option<DryRunMode>) -> u64 {
+    let file_size = input_file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+    if dry_run == Some(DryRunMode::Simulate) || file_size > options.max_input_size {
+        return 0;
+    }
+
+    let pixels = imagesize::size(input_file)
+        .map(|size| size.width as u64 * size.height as u64)
+        .unwrap_or(0);
+
+    file_size * 2 + pixels * DECODED_BYTES_PER_PIXEL * DECODED_COPIES
 }
 
-fn perform_compression(input_file: &PathBuf, options: &CompressionOptions, dry_run: bool) -> CompressionResult {
+fn perform_compression(
+    input_file: &PathBuf,
+    options: &Arc<CompressionOptions>,
+    collision: Option<&CollisionResolution>,
+    reservation: Option<&Arc<MemoryReservation>>,
+    dry_run: Option<DryRunMode>,
+) -> CompressionResult {
     let mut compression_result = CompressionResult {
         original_path: input_file.display().to_string(),
         output_path: String::new(),
@@ -108,30 +624,69 @@ fn perform_compression(input_file: &PathBuf, options: &CompressionOptions, dry_r
         compressed_size: 0,
         status: CompressionStatus::Error,
         message: String::new(),
+        error_code: None,
+        cause: None,
     };
 
     let input_file_metadata = match input_file.metadata() {
         Ok(metadata) => metadata,
-        Err(_) => {
-            compression_result.message = "Error reading file metadata".to_string();
+        Err(e) => {
+            compression_result.set_error(
+                ErrorCode::from_io_error(&e, ErrorCode::IoRead),
+                "Error reading file metadata",
+                Some(e.to_string()),
+            );
             return compression_result;
         }
     };
 
     let original_file_size = input_file_metadata.len();
 
-    if original_file_size > MAX_FILE_SIZE {
-        compression_result.message = "File exceeds 500Mb, skipping.".to_string();
-        compression_result.status = CompressionStatus::Skipped;
+    if original_file_size > options.max_input_size {
+        compression_result.set_skipped(
+            ErrorCode::TooLarge,
+            format!(
+                "File exceeds {}, skipping.",
+                bytesize::ByteSize::b(options.max_input_size)
+            ),
+        );
         return compression_result;
     }
 
     compression_result.original_size = original_file_size;
 
-    let output_full_path = match setup_output_path(input_file, options, &mut compression_result, dry_run) {
+    match collision {
+        Some(CollisionResolution::Skip { other_input_file }) => {
+            compression_result.compressed_size = original_file_size;
+            compression_result.set_skipped(
+                ErrorCode::OutputCollision,
+                format!("Output path already used by {}, skipped", other_input_file.display()),
+            );
+            return compression_result;
+        }
+        Some(CollisionResolution::Fail { other_input_file }) => {
+            compression_result.set_error(
+                ErrorCode::OutputCollision,
+                format!("Output path already used by {}", other_input_file.display()),
+                None,
+            );
+            return compression_result;
+        }
+        _ => {}
+    }
+
+    let output_full_path = match setup_output_path(
+        input_file,
+        options,
+        collision,
+        &mut compression_result,
+        dry_run.is_some(),
+    ) {
         Some(path) => path,
         None => {
-            compression_result.message = "Error setting up output path".to_string();
+            // The cause, if any, was recorded while setting up the path
+            let cause = compression_result.cause.take();
+            compression_result.set_error(ErrorCode::OutputPath, "Error setting up output path", cause);
             return compression_result;
         }
     };
@@ -141,16 +696,17 @@ fn perform_compression(input_file: &PathBuf, options: &CompressionOptions, dry_r
         return compression_result;
     }
 
-    if dry_run {
+    if dry_run == Some(DryRunMode::Simulate) {
         compression_result.status = CompressionStatus::Success;
         compression_result.compressed_size = original_file_size;
         return compression_result;
     }
 
-    let compressed_image = match perform_image_compression(input_file, options, &mut compression_result) {
-        Some(image) => image,
-        None => return compression_result,
-    };
+    let compressed_image =
+        match perform_image_compression_with_timeout(input_file, options, reservation, &mut compression_result) {
+            Some(image) => image,
+            None => return compression_result,
+        };
 
     let output_file_size = compressed_image.len() as u64;
 
@@ -173,8 +729,20 @@ fn perform_compression(input_file: &PathBuf, options: &CompressionOptions, dry_r
         return compression_result;
     }
 
-    if let Err(msg) = write_compressed_file(&output_full_path, &compressed_image, options, &input_file_metadata) {
-        compression_result.message = msg;
+    if dry_run == Some(DryRunMode::Estimate) {
+        compression_result.status = CompressionStatus::Success;
+        compression_result.compressed_size = output_file_size;
+        return compression_result;
+    }
+
+    if let Err((message, e)) =
+        write_compressed_file(&output_full_path, &compressed_image, options, &input_file_metadata)
+    {
+        compression_result.set_error(
+            ErrorCode::from_io_error(&e, ErrorCode::IoWrite),
+            message,
+            Some(e.to_string()),
+        );
         return compression_result;
     }
 
@@ -183,17 +751,173 @@ fn perform_compression(input_file: &PathBuf, options: &CompressionOptions, dry_r
     compression_result
 }
 
-fn is_resize_needed(options: &CompressionOptions) -> bool {
+/// The compression cannot be interrupted, so it runs on its own thread which is left behind if it does not finish
+/// in time, keeping the memory of the file reserved until it ends. A file written as PNG that timed out is retried once
+/// with faster PNG settings if requested, when the memory of the retry fits next to the attempt left behind. The
+/// other formats would be retried with the same settings, so they are skipped right away
+fn perform_image_compression_with_timeout(
+    input_file: &PathBuf,
+    options: &Arc<CompressionOptions>,
+    reservation: Option<&Arc<MemoryReservation>>,
+    compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    let Some(timeout) = options.file_timeout else {
+        return perform_image_compression(input_file, options, compression_result);
+    };
+
+    let mut reservation = reservation.cloned();
+    let mut png_effort = None;
+    loop {
+        let worker_limit = rayon::current_num_threads() * TIMEOUT_WORKERS_PER_THREAD;
+        let Some(worker) = TIMEOUT_WORKERS.start(worker_limit, timeout) else {
+            compression_result.compressed_size = compression_result.original_size;
+            compression_result.set_skipped(
+                ErrorCode::Timeout,
+                "Too many timed out compressions still running, skipped",
+            );
+            return None;
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let (attempt_file, attempt_options) = (input_file.clone(), Arc::clone(options));
+        let attempt_reservation = reservation.clone();
+        let mut attempt_result = compression_result.clone();
+        thread::spawn(move || {
+            // Held until the compression ends, even after the file timed out
+            let _held = (worker, attempt_reservation);
+            let compressed_image = compress_image(&attempt_file, &attempt_options, png_effort, &mut attempt_result);
+            let _ = sender.send((compressed_image, attempt_result));
+        });
+
+        if let Ok((compressed_image, attempt_result)) = receiver.recv_timeout(timeout) {
+            *compression_result = attempt_result;
+            if png_effort.is_some() && compressed_image.is_some() {
+                compression_result.message = "Timed out, compressed with the fallback settings".to_string();
+            }
+            return compressed_image;
+        }
+
+        if png_effort.is_some() || !options.timeout_fallback || !is_png_output(input_file, options) {
+            break;
+        }
+        if let Some(abandoned) = &reservation {
+            match abandoned.budget.try_reserve(abandoned.amount) {
+                Some(retry) => reservation = Some(Arc::new(retry)),
+                None => break,
+            }
+        }
+        png_effort = Some(PngEffort {
+            opt_level: options.png_opt_level.min(FALLBACK_PNG_OPT_LEVEL),
+            zopfli: false,
+        });
+    }
+
+    compression_result.compressed_size = compression_result.original_size;
+    compression_result.set_skipped(
+        ErrorCode::Timeout,
+        format!("Compression timed out after {timeout:?}, skipped"),
+    );
+    None
+}
+
+fn is_png_output(input_file: &Path, options: &CompressionOptions) -> bool {
+    match options.format {
+        OutputFormat::Original => infer::get_from_path(input_file)
+            .ok()
+            .flatten()
+            .is_some_and(|kind| kind.mime_type() == "image/png"),
+        format => format == OutputFormat::Png,
+    }
+}
+
+/// PNG settings overriding the options, for the retry of a file that timed out
+#[derive(Clone, Copy)]
+struct PngEffort {
+    opt_level: u8,
+    zopfli: bool,
+}
+
+/// Threads compressing a file with a timeout, including the ones left behind, for the whole process as they may
+/// outlive a run
+static TIMEOUT_WORKERS: TimeoutWorkers = TimeoutWorkers {
+    running: Mutex::new(0),
+    finished: Condvar::new(),
+};
+
+struct TimeoutWorkers {
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+struct TimeoutWorker<'a> {
+    workers: &'a TimeoutWorkers,
+}
+
+impl TimeoutWorkers {
+    /// Waits up to the timeout for a thread to end when the limit is reached
+    fn start(&self, limit: usize, timeout: Duration) -> Option<TimeoutWorker<'_>> {
+        let running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        let (mut running, wait) = self
+            .finished
+            .wait_timeout_while(running, timeout, |running| *running >= limit)
+            .unwrap_or_else(PoisonError::into_inner);
+        if wait.timed_out() {
+            return None;
+        }
+        *running += 1;
+
+        Some(TimeoutWorker { workers: self })
+    }
+}
+
+impl Drop for TimeoutWorker<'_> {
+    fn drop(&mut self) {
+        let mut running = self.workers.running.lock().unwrap_or_else(PoisonError::into_inner);
+        *running -= 1;
+        self.workers.finished.notify_all();
+    }
+}
+
+pub(crate) fn is_resize_needed(options: &CompressionOptions) -> bool {
     options.width.is_some() || options.height.is_some() || options.long_edge.is_some() || options.short_edge.is_some()
 }
 
 fn setup_output_path(
     input_file: &Path,
     options: &CompressionOptions,
+    collision: Option<&CollisionResolution>,
     compression_result: &mut CompressionResult,
     dry_run: bool,
 ) -> Option<PathBuf> {
-    let output_directory = determine_output_directory(input_file, options, compression_result)?;
+    let output_path = match collision {
+        Some(CollisionResolution::Rename(output_path)) => output_path.clone(),
+        _ => match compute_output_path(input_file, options) {
+            Ok(output_path) => output_path,
+            Err(e) => {
+                compression_result.cause = Some(e.to_string());
+                return None;
+            }
+        },
+    };
+
+    if dry_run {
+        return Some(output_path);
+    }
+
+    let output_directory = output_path.parent().unwrap_or(Path::new(""));
+    if !output_directory.as_os_str().is_empty() && !output_directory.exists() {
+        if let Err(e) = fs::create_dir_all(output_directory) {
+            compression_result.cause = Some(format!("Error creating output directory: {e}"));
+            return None;
+        }
+    }
+
+    Some(output_path)
+}
+
+/// Where the file is written, before any collision with the output of another file is resolved
+fn compute_output_path(input_file: &Path, options: &CompressionOptions) -> Result<PathBuf, &'static str> {
+    let output_directory = determine_output_directory(input_file, options)?;
     let (output_directory, filename) = compute_output_full_path(
         output_directory,
         input_file,
@@ -202,42 +926,162 @@ fn setup_output_path(
         options.suffix.as_ref().unwrap_or(&String::new()).as_ref(),
         options.format,
         options.same_folder_as_input || output_directory == options.base_path,
-    )?;
-
-    if dry_run {
-        return Some(output_directory.join(filename));
-    }
-
-    if !output_directory.exists() && fs::create_dir_all(&output_directory).is_err() {
-        compression_result.message = "Error creating output directory".to_string();
-        return None;
-    }
+    )
+    .ok_or("Error computing output path")?;
 
-    Some(output_directory.join(filename))
+    Ok(output_directory.join(filename))
 }
 
 fn determine_output_directory<'a>(
     input_file: &'a Path,
     options: &'a CompressionOptions,
-    compression_result: &mut CompressionResult,
-) -> Option<&'a Path> {
+) -> Result<&'a Path, &'static str> {
     if options.same_folder_as_input {
-        match input_file.parent() {
-            Some(p) => Some(p),
-            None => {
-                compression_result.message = "Error getting parent directory".to_string();
-                None
-            }
-        }
+        input_file.parent().ok_or("Error getting parent directory")
     } else {
-        match options.output_folder.as_ref() {
-            Some(p) => Some(p),
-            None => {
-                compression_result.message = "Error getting output directory".to_string();
-                None
+        options.output_folder.as_deref().ok_or("Error getting output directory")
+    }
+}
+
+/// A file whose output path is already taken by a previous file
+#[derive(Debug, PartialEq)]
+pub struct OutputCollision {
+    pub input_file: PathBuf,
+    pub other_input_file: PathBuf,
+    pub output_path: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum CollisionResolution {
+    Rename(PathBuf),
+    Skip {
+        other_input_file: PathBuf,
+    },
+    /// Only when compressing while scanning, as the collisions cannot be reported before starting
+    Fail {
+        other_input_file: PathBuf,
+    },
+}
+
+/// Files mapped to the same output path, e.g. `a/x.png` and `b/x.png` without --keep-structure, the first one in
+/// input order keeping the path
+pub(crate) fn find_output_collisions(input_files: &[PathBuf], options: &CompressionOptions) -> Vec<OutputCollision> {
+    let mut taken: HashMap<String, &PathBuf> = HashMap::new();
+    let mut collisions = vec![];
+    for input_file in input_files {
+        let Ok(output_path) = compute_output_path(input_file, options) else {
+            continue;
+        };
+        match taken.entry(collision_key(&output_path)) {
+            Entry::Occupied(entry) => collisions.push(OutputCollision {
+                input_file: input_file.clone(),
+                other_input_file: entry.get().to_path_buf(),
+                output_path,
+            }),
+            Entry::Vacant(entry) => {
+                entry.insert(input_file);
             }
         }
     }
+
+    collisions
+}
+
+/// Renamed files get the first free `-1`, `-2`... suffix, free meaning not used by the output of any other file.
+/// The resolutions are kept in the options, for the compression to follow them
+pub(crate) fn resolve_output_collisions(
+    collisions: Vec<OutputCollision>,
+    policy: CollisionPolicy,
+    input_files: &[PathBuf],
+    options: &mut CompressionOptions,
+) {
+    let mut taken: HashSet<String> = input_files
+        .iter()
+        .filter_map(|input_file| compute_output_path(input_file, options).ok())
+        .map(|output_path| collision_key(&output_path))
+        .collect();
+
+    options.collisions = collisions
+        .into_iter()
+        .map(|collision| {
+            let resolution = match policy {
+                CollisionPolicy::Rename => {
+                    let output_path = (1..)
+                        .map(|n| renamed_output_path(&collision.output_path, n))
+                        .find(|output_path| taken.insert(collision_key(output_path)))
+                        .unwrap_or_default();
+                    CollisionResolution::Rename(output_path)
+                }
+                CollisionPolicy::Skip | CollisionPolicy::Error => CollisionResolution::Skip {
+                    other_input_file: collision.other_input_file,
+                },
+            };
+            (collision.input_file, resolution)
+        })
+        .collect();
+}
+
+/// Output paths claimed by the files compressed while the scan is still running, the first file to claim a path
+/// keeping it
+pub(crate) struct OutputClaims {
+    policy: CollisionPolicy,
+    taken: Mutex<HashMap<String, PathBuf>>,
+}
+
+impl OutputClaims {
+    pub fn new(policy: CollisionPolicy) -> Self {
+        Self {
+            policy,
+            taken: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How the output of the file is handled if its path is already claimed, renamed files claiming the new path
+    fn claim(&self, input_file: &Path, options: &CompressionOptions) -> Option<CollisionResolution> {
+        let output_path = compute_output_path(input_file, options).ok()?;
+        let mut taken = self.taken.lock().unwrap_or_else(PoisonError::into_inner);
+        let other_input_file = match taken.entry(collision_key(&output_path)) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => {
+                entry.insert(input_file.to_path_buf());
+                return None;
+            }
+        };
+
+        Some(match self.policy {
+            CollisionPolicy::Rename => {
+                let output_path = (1..)
+                    .map(|n| renamed_output_path(&output_path, n))
+                    .find(|output_path| !taken.contains_key(&collision_key(output_path)))
+                    .unwrap_or_default();
+                taken.insert(collision_key(&output_path), input_file.to_path_buf());
+                CollisionResolution::Rename(output_path)
+            }
+            CollisionPolicy::Skip => CollisionResolution::Skip { other_input_file },
+            CollisionPolicy::Error => CollisionResolution::Fail { other_input_file },
+        })
+    }
+}
+
+/// `x.png` becomes `x-1.png`, `x-2.png`...
+fn renamed_output_path(output_path: &Path, n: usize) -> PathBuf {
+    let mut file_name = output_path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!("-{n}"));
+    if let Some(extension) = output_path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    output_path.with_file_name(file_name)
+}
+
+/// File names differing only by case are the same file on Windows and macOS
+fn collision_key(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if cfg!(any(target_os = "windows", target_os = "macos")) {
+        path.to_lowercase()
+    } else {
+        path.into_owned()
+    }
 }
 
 fn skip_due_to_overwrite_policy(
@@ -247,9 +1091,11 @@ fn skip_due_to_overwrite_policy(
     compression_result: &mut CompressionResult,
 ) -> bool {
     if options.overwrite_policy == OverwritePolicy::Never && output_path.exists() {
-        compression_result.status = CompressionStatus::Skipped;
         compression_result.compressed_size = original_size;
-        compression_result.message = "File already exists, skipped due overwrite policy".to_string();
+        compression_result.set_skipped(
+            ErrorCode::OutputExists,
+            "File already exists, skipped due overwrite policy",
+        );
         return true;
     }
 
@@ -263,15 +1109,52 @@ fn get_file_mime_type_from_buffer(buffer: &[u8]) -> Option<String> {
     }
 }
 
-fn perform_image_compression(
+pub(crate) fn perform_image_compression(
     input_file: &PathBuf,
     options: &CompressionOptions,
     compression_result: &mut CompressionResult,
+) -> Option<Vec<u8>> {
+    compress_image(input_file, options, None, compression_result)
+}
+
+fn compress_image(
+    input_file: &PathBuf,
+    options: &CompressionOptions,
+    png_effort: Option<PngEffort>,
+    compression_result: &mut CompressionResult,
 ) -> Option<Vec<u8>> {
     let input_file_buffer = match read_file_to_vec(input_file) {
         Ok(b) => b,
-        Err(_) => {
-            compression_result.message = "Error reading input file".to_string();
+        Err(e) => {
+            compression_result.set_error(
+                ErrorCode::from_io_error(&e, ErrorCode::IoRead),
+                "Error reading input file",
+                Some(e.to_string()),
+            );
+            return None;
+        }
+    };
+
+    let kept_metadata = metadata::read_kept_metadata(&input_file_buffer, options);
+    let converts_to_srgb = transform::is_srgb_conversion_needed(&input_file_buffer, options);
+    let resized_by_transform = transform::is_resize_done_by_transform(&input_file_buffer, options);
+
+    let (input_file_buffer, format) = match transform::apply_transforms(&input_file_buffer, options) {
+        Ok(Some(transformed_buffer)) => {
+            let format = format_after_transform(options.format, &input_file_buffer, &transformed_buffer);
+            (transformed_buffer, format)
+        }
+        Ok(None) => (input_file_buffer, options.format),
+        Err(e) if e.is::<transform::UnsupportedTransform>() => {
+            compression_result.set_error(ErrorCode::UnsupportedTransform, e.to_string(), None);
+            return None;
+        }
+        Err(e) => {
+            compression_result.set_error(
+                ErrorCode::DecodeFailed,
+                format!("Error transforming image: {e}"),
+                Some(e.to_string()),
+            );
             return None;
         }
     };
@@ -279,21 +1162,32 @@ fn perform_image_compression(
     let mut compression_parameters = match build_compression_parameters(options, &input_file_buffer) {
         Ok(p) => p,
         Err(e) => {
-            compression_result.message = format!("Error building compression parameters: {e}");
+            compression_result.set_error(
+                ErrorCode::DecodeFailed,
+                format!("Error building compression parameters: {e}"),
+                Some(e.to_string()),
+            );
             return None;
         }
     };
+    if resized_by_transform {
+        compression_parameters.width = 0;
+        compression_parameters.height = 0;
+    }
+    if let Some(png_effort) = png_effort {
+        compression_parameters.png.optimization_level = png_effort.opt_level;
+        compression_parameters.png.force_zopfli = png_effort.zopfli;
+    }
 
-    let compression_result_data = match (options.max_size, options.format) {
-        (Some(max_size), format) if format != OutputFormat::Original => {
-            let converted_image = convert_in_memory(
-                input_file_buffer,
-                &compression_parameters,
-                map_supported_formats(format),
-            )
-            .ok()?;
+    let compression_result_data = match (options.max_size, format) {
+        (Some(max_size), format) if format != OutputFormat::Original => convert_in_memory(
+            input_file_buffer,
+            &compression_parameters,
+            map_supported_formats(format),
+        )
+        .and_then(|converted_image| {
             compress_to_size_in_memory(converted_image, &mut compression_parameters, max_size, true)
-        }
+        }),
         (Some(max_size), _) => {
             compress_to_size_in_memory(input_file_buffer, &mut compression_parameters, max_size, true)
         }
@@ -305,12 +1199,84 @@ fn perform_image_compression(
         _ => compress_in_memory(input_file_buffer, &compression_parameters),
     };
 
-    match compression_result_data {
-        Ok(compressed_image) => Some(compressed_image),
+    let compressed_image = match compression_result_data {
+        Ok(compressed_image) => compressed_image,
         Err(e) => {
-            compression_result.message = format!("Error compressing file: {e}");
-            None
+            compression_result.set_error(
+                ErrorCode::from_caesium_error(&e),
+                format!("Error compressing file: {e}"),
+                Some(e.message),
+            );
+            return None;
         }
+    };
+
+    let compressed_image = match kept_metadata {
+        Some(kept_metadata) => match metadata::write_metadata(compressed_image, &kept_metadata) {
+            Ok(image) => image,
+            Err(e) => {
+                compression_result.set_metadata_error(e);
+                return None;
+            }
+        },
+        None => compressed_image,
+    };
+
+    // The original profile no longer describes the converted pixels, whatever metadata was kept
+    let compressed_image = if converts_to_srgb {
+        match metadata::set_icc_profile(compressed_image, transform::srgb_output_profile(options)) {
+            Ok(image) => image,
+            Err(e) => {
+                compression_result.set_metadata_error(e);
+                return None;
+            }
+        }
+    } else {
+        compressed_image
+    };
+
+    let compressed_image = match metadata::set_metadata_fields(compressed_image, &options.set_meta, input_file) {
+        Ok(image) => image,
+        Err(e) => {
+            compression_result.set_metadata_error(e);
+            return None;
+        }
+    };
+
+    match options.dpi {
+        Some(dpi) => match metadata::set_density(compressed_image, dpi) {
+            Ok(image) => Some(image),
+            Err(e) => {
+                compression_result.set_metadata_error(e);
+                None
+            }
+        },
+        None => Some(compressed_image),
+    }
+}
+
+/// Transformed images may be handed over in an intermediate format, so the final one must be made explicit
+fn format_after_transform(format: OutputFormat, original_buffer: &[u8], transformed_buffer: &[u8]) -> OutputFormat {
+    let format = match format {
+        OutputFormat::Original => output_format_from_buffer(original_buffer),
+        f => f,
+    };
+
+    if format == output_format_from_buffer(transformed_buffer) {
+        OutputFormat::Original
+    } else {
+        format
+    }
+}
+
+pub(crate) fn output_format_from_buffer(buffer: &[u8]) -> OutputFormat {
+    match get_file_mime_type_from_buffer(buffer).as_deref() {
+        Some("image/jpeg") => OutputFormat::Jpeg,
+        Some("image/png") => OutputFormat::Png,
+        Some("image/gif") => OutputFormat::Gif,
+        Some("image/webp") => OutputFormat::Webp,
+        Some("image/tiff") => OutputFormat::Tiff,
+        _ => OutputFormat::Original,
     }
 }
 
@@ -335,10 +1301,11 @@ fn skip_due_to_insufficient_savings(
             let savings_percent = (actual_savings as f64 / original_size as f64) * 100.0;
 
             if savings_percent < percent {
-                compression_result.status = CompressionStatus::Skipped;
                 compression_result.compressed_size = original_size;
-                compression_result.message =
-                    format!("Insufficient savings: {savings_percent:.2}% < {percent:.2}%, skipped",);
+                compression_result.set_skipped(
+                    ErrorCode::InsufficientSavings,
+                    format!("Insufficient savings: {savings_percent:.2}% < {percent:.2}%, skipped"),
+                );
                 true
             } else {
                 false
@@ -346,12 +1313,14 @@ fn skip_due_to_insufficient_savings(
         }
         MinSavingsThreshold::Bytes(min_bytes) => {
             if actual_savings < min_bytes {
-                compression_result.status = CompressionStatus::Skipped;
                 compression_result.compressed_size = original_size;
-                compression_result.message = format!(
-                    "Insufficient savings: {} < {}, skipped",
-                    bytesize::ByteSize::b(actual_savings),
-                    bytesize::ByteSize::b(min_bytes)
+                compression_result.set_skipped(
+                    ErrorCode::InsufficientSavings,
+                    format!(
+                        "Insufficient savings: {} < {}, skipped",
+                        bytesize::ByteSize::b(actual_savings),
+                        bytesize::ByteSize::b(min_bytes)
+                    ),
                 );
                 true
             } else {
@@ -372,15 +1341,21 @@ fn skip_due_to_bigger_policy(
         match output_path.metadata() {
             Ok(existing_metadata) => {
                 if existing_metadata.len() <= output_size {
-                    compression_result.status = CompressionStatus::Skipped;
                     compression_result.compressed_size = original_size;
-                    compression_result.message = "File already exists, skipped due overwrite policy".to_string();
+                    compression_result.set_skipped(
+                        ErrorCode::OutputExists,
+                        "File already exists, skipped due overwrite policy",
+                    );
                     return true;
                 }
             }
-            Err(_) => {
-                compression_result.message = "Error reading existing file metadata".to_string();
-                return false;
+            Err(e) => {
+                compression_result.set_error(
+                    ErrorCode::from_io_error(&e, ErrorCode::IoRead),
+                    "Error reading existing file metadata",
+                    Some(e.to_string()),
+                );
+                return true;
             }
         }
     }
@@ -393,16 +1368,15 @@ fn write_compressed_file(
     compressed_image: &[u8],
     options: &CompressionOptions,
     input_file_metadata: &Metadata,
-) -> Result<(), String> {
-    let mut output_file = File::create(output_path).map_err(|_| "Error creating output file".to_string())?;
+) -> Result<(), (&'static str, io::Error)> {
+    let mut output_file = File::create(output_path).map_err(|e| ("Error creating output file", e))?;
 
     output_file
         .write_all(compressed_image)
-        .map_err(|_| "Error writing output file".to_string())?;
+        .map_err(|e| ("Error writing output file", e))?;
 
     if options.keep_dates {
-        preserve_file_times(&output_file, input_file_metadata)
-            .map_err(|_| "Error preserving file times".to_string())?;
+        preserve_file_times(&output_file, input_file_metadata).map_err(|e| ("Error preserving file times", e))?;
     }
 
     Ok(())
@@ -422,7 +1396,8 @@ fn build_compression_parameters(options: &CompressionOptions, buffer: &[u8]) ->
     } else {
         quality
     };
-    parameters.jpeg.preserve_icc = !options.strip_icc;
+    // With selective metadata the ICC profile is written back afterwards, only if requested
+    parameters.jpeg.preserve_icc = !options.strip_icc && options.keep_metadata.is_empty();
 
     parameters.jpeg.optimize = options.lossless;
     parameters.png.optimize = options.lossless;
@@ -506,33 +1481,42 @@ fn build_resize_parameters(
     buffer: &[u8],
     mime_type: Option<String>,
 ) -> Result<(), Box<dyn Error>> {
-    let (width, height) = get_real_resolution(buffer, mime_type, options.exif)?;
+    let (width, height) = get_real_resolution(buffer, mime_type, metadata::keeps_exif(options))?;
+    (parameters.width, parameters.height) = resize_target(options, width, height);
+
+    Ok(())
+}
+
+/// Width and height to resize an image of the given resolution to, 0 meaning to follow the aspect ratio.
+/// Both are 0 if no resize is needed.
+pub(crate) fn resize_target(options: &CompressionOptions, width: usize, height: usize) -> (u32, u32) {
+    let (mut target_width, mut target_height) = (0, 0);
 
     if options.width.is_some() || options.height.is_some() {
-        parameters.width = options.width.unwrap_or(0);
-        parameters.height = options.height.unwrap_or(0);
+        target_width = options.width.unwrap_or(0);
+        target_height = options.height.unwrap_or(0);
     } else if options.long_edge.is_some() {
         let long_edge = options.long_edge.unwrap_or(0);
         if width > height {
-            parameters.width = long_edge;
+            target_width = long_edge;
         } else {
-            parameters.height = long_edge;
+            target_height = long_edge;
         }
     } else if options.short_edge.is_some() {
         let short_edge = options.short_edge.unwrap_or(0);
         if width < height {
-            parameters.width = short_edge;
+            target_width = short_edge;
         } else {
-            parameters.height = short_edge;
+            target_height = short_edge;
         }
     }
 
-    if options.no_upscale && (parameters.width >= width as u32 || parameters.height >= height as u32) {
-        parameters.width = 0;
-        parameters.height = 0;
+    if options.no_upscale && (target_width >= width as u32 || target_height >= height as u32) {
+        target_width = 0;
+        target_height = 0;
     }
 
-    Ok(())
+    (target_width, target_height)
 }
 
 fn get_real_resolution(
@@ -605,9 +1589,10 @@ fn read_file_to_vec(file_path: &PathBuf) -> io::Result<Vec<u8>> {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use indicatif::ProgressDrawTarget;
+    use crate::events::SilentEvents;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::time::UNIX_EPOCH;
     use tempfile::tempdir;
 
@@ -776,13 +1761,10 @@ mod tests {
 
         let mut options = setup_options();
         options.base_path = absolute(PathBuf::from("samples")).unwrap();
-        let multi_progress = indicatif::MultiProgress::new();
-        multi_progress.set_draw_target(ProgressDrawTarget::hidden());
-        let progress_bar = multi_progress.add(ProgressBar::new(input_files.len() as u64));
         let temp_dir = tempdir().unwrap().path().to_path_buf();
         options.output_folder = Some(temp_dir.clone());
 
-        let mut results = start_compression(&input_files, &options, &multi_progress, &progress_bar, false);
+        let mut results = start_compression(&input_files, &options, None, &SilentEvents);
         assert_eq!(results.len(), 4);
         assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
         assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
@@ -805,7 +1787,7 @@ mod tests {
         let temp_dir = tempdir().unwrap().path().to_path_buf();
         options.output_folder = Some(temp_dir.clone());
         options.keep_structure = true;
-        results = start_compression(&input_files, &options, &multi_progress, &progress_bar, false);
+        results = start_compression(&input_files, &options, None, &SilentEvents);
         assert_eq!(results.len(), 8);
         assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
         assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
@@ -833,19 +1815,19 @@ mod tests {
         options.quality = Some(100);
 
         options.overwrite_policy = OverwritePolicy::Never;
-        results = start_compression(&input_files, &options, &multi_progress, &progress_bar, false);
+        results = start_compression(&input_files, &options, None, &SilentEvents);
         assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
         assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
 
         options.quality = Some(100);
         options.overwrite_policy = OverwritePolicy::Bigger;
-        results = start_compression(&input_files, &options, &multi_progress, &progress_bar, false);
+        results = start_compression(&input_files, &options, None, &SilentEvents);
         assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
         assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
 
         options.quality = Some(100);
         options.overwrite_policy = OverwritePolicy::All;
-        results = start_compression(&input_files, &options, &multi_progress, &progress_bar, true);
+        results = start_compression(&input_files, &options, Some(DryRunMode::Simulate), &SilentEvents);
         assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
         assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
 
@@ -853,13 +1835,13 @@ mod tests {
         options.png_opt_level = 6;
         options.lossless = true;
         options.overwrite_policy = OverwritePolicy::All;
-        results = start_compression(&input_files, &options, &multi_progress, &progress_bar, true);
+        results = start_compression(&input_files, &options, Some(DryRunMode::Simulate), &SilentEvents);
         assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
         assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
 
         options.quality = Some(80);
         options.keep_dates = true;
-        results = start_compression(&input_files, &options, &multi_progress, &progress_bar, false);
+        results = start_compression(&input_files, &options, None, &SilentEvents);
 
         assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
         assert!(results.iter().all(|r| {
@@ -895,6 +1877,340 @@ mod tests {
         assert!(results.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
     }
 
+    #[test]
+    fn test_dry_run_estimate() {
+        let input_files = vec![
+            absolute(PathBuf::from("samples/p0.png")).unwrap(),
+            absolute(PathBuf::from("samples/level_1_0/j1.jpg")).unwrap(),
+        ];
+
+        let mut options = setup_options();
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.quality = Some(60);
+        let temp_dir = tempdir().unwrap().path().join("output");
+        options.output_folder = Some(temp_dir.clone());
+
+        let results = start_compression(&input_files, &options, Some(DryRunMode::Estimate), &SilentEvents);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+        assert!(results
+            .iter()
+            .all(|r| r.compressed_size > 0 && r.compressed_size < r.original_size));
+        assert!(!temp_dir.exists());
+
+        let results = start_compression(&input_files, &options, Some(DryRunMode::Simulate), &SilentEvents);
+        assert!(results.iter().all(|r| r.compressed_size == r.original_size));
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let temp_dir = tempdir().unwrap();
+        let not_an_image = temp_dir.path().join("not_an_image.jpg");
+        fs::write(&not_an_image, b"not an image").unwrap();
+        let truncated = temp_dir.path().join("truncated.png");
+        fs::write(&truncated, &fs::read("samples/p0.png").unwrap()[..5000]).unwrap();
+        let input_files = vec![
+            not_an_image,
+            temp_dir.path().join("missing.jpg"),
+            absolute(PathBuf::from("samples/p0.png")).unwrap(),
+            truncated,
+        ];
+
+        let mut options = setup_options();
+        options.base_path = temp_dir.path().to_path_buf();
+        options.output_folder = Some(temp_dir.path().join("out"));
+
+        let results = start_compression(&input_files, &options, None, &SilentEvents);
+        assert_eq!(results[0].error_code, Some(ErrorCode::UnsupportedFormat));
+        assert!(results[0].cause.is_some());
+        assert_eq!(results[1].error_code, Some(ErrorCode::IoRead));
+        assert!(results[1].cause.is_some());
+        assert!(matches!(results[2].status, CompressionStatus::Success));
+        assert_eq!(results[2].error_code, None);
+        assert_eq!(results[3].error_code, Some(ErrorCode::DecodeFailed));
+
+        let json = serde_json::to_value(&results[0]).unwrap();
+        assert_eq!(json["error_code"], "unsupported_format");
+        assert_eq!(ErrorCode::PermissionDenied.to_string(), "permission_denied");
+
+        let animated = temp_dir.path().join("animated.gif");
+        fs::write(&animated, transform::tests::gif_with_frames(2)).unwrap();
+        options.grayscale = true;
+        let result = perform_compression(&animated, &Arc::new(options), None, None, None);
+        assert!(matches!(result.status, CompressionStatus::Error));
+        assert_eq!(result.error_code, Some(ErrorCode::UnsupportedTransform));
+    }
+
+    #[test]
+    fn test_max_input_size_and_memory_limit() {
+        let input_files = vec![
+            absolute(PathBuf::from("samples/level_1_0/j1.jpg")).unwrap(),
+            absolute(PathBuf::from("samples/p0.png")).unwrap(),
+            absolute(PathBuf::from("samples/w0.webp")).unwrap(),
+        ];
+        let temp_dir = tempdir().unwrap();
+        let mut options = setup_options();
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+
+        options.max_input_size = fs::metadata(&input_files[1]).unwrap().len();
+        options.memory_limit = Some(1);
+        let results = start_compression(&input_files, &options, None, &SilentEvents);
+        assert_eq!(results.len(), 3);
+        for (result, input_file) in results.iter().zip(&input_files) {
+            assert_eq!(PathBuf::from(&result.original_path), *input_file);
+        }
+        assert_eq!(results[0].error_code, Some(ErrorCode::TooLarge));
+        assert!(matches!(results[0].status, CompressionStatus::Skipped));
+        assert!(matches!(results[1].status, CompressionStatus::Success));
+
+        let estimate = estimate_memory_usage(&input_files[1], &options, None);
+        let (width, height) = image::image_dimensions(&input_files[1]).unwrap();
+        assert!(estimate >= width as u64 * height as u64 * DECODED_BYTES_PER_PIXEL);
+        assert_eq!(estimate_memory_usage(&input_files[0], &options, None), 0);
+        assert_eq!(
+            estimate_memory_usage(&input_files[1], &options, Some(DryRunMode::Simulate)),
+            0
+        );
+    }
+
+    #[test]
+    fn test_file_timeout() {
+        let input_files = vec![absolute(PathBuf::from("samples/level_1_0/j1.jpg")).unwrap()];
+        let temp_dir = tempdir().unwrap();
+        let mut options = setup_options();
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+
+        options.file_timeout = Some(Duration::from_nanos(1));
+        options.timeout_fallback = true;
+        let results = start_compression(&input_files, &options, None, &SilentEvents);
+        assert!(matches!(results[0].status, CompressionStatus::Skipped));
+        assert_eq!(results[0].error_code, Some(ErrorCode::Timeout));
+        assert_eq!(results[0].compressed_size, results[0].original_size);
+        assert!(!fs::exists(&results[0].output_path).unwrap());
+
+        options.file_timeout = Some(Duration::from_secs(600));
+        let results = start_compression(&input_files, &options, None, &SilentEvents);
+        assert!(matches!(results[0].status, CompressionStatus::Success));
+        assert!(results[0].compressed_size < results[0].original_size);
+        assert!(fs::exists(&results[0].output_path).unwrap());
+
+        // Only PNG outputs have faster settings to retry with
+        assert!(!is_png_output(&input_files[0], &options));
+        assert!(is_png_output(Path::new("samples/p0.png"), &options));
+        options.format = OutputFormat::Png;
+        assert!(is_png_output(&input_files[0], &options));
+        options.format = OutputFormat::Webp;
+        assert!(!is_png_output(Path::new("samples/p0.png"), &options));
+    }
+
+    #[test]
+    fn test_execution_order() {
+        let input_files = vec![
+            PathBuf::from("samples/w0.webp"),
+            PathBuf::from("samples/level_1_0/j1.jpg"),
+            PathBuf::from("samples/p0.png"),
+            PathBuf::from("samples/missing.png"),
+        ];
+
+        assert_eq!(execution_order(&input_files, FileOrder::SizeDesc), vec![1, 2, 0, 3]);
+        assert_eq!(execution_order(&input_files, FileOrder::SizeAsc), vec![3, 0, 2, 1]);
+        assert_eq!(execution_order(&input_files, FileOrder::Path), vec![1, 3, 2, 0]);
+        assert_eq!(execution_order(&input_files, FileOrder::Mtime).len(), 4);
+
+        // Whatever the order, the results follow the input files
+        let temp_dir = tempdir().unwrap();
+        let mut options = setup_options();
+        options.base_path = absolute(PathBuf::from("samples")).unwrap();
+        options.output_folder = Some(temp_dir.path().to_path_buf());
+        let results = start_compression(&input_files, &options, Some(DryRunMode::Simulate), &SilentEvents);
+        let paths: Vec<PathBuf> = results.iter().map(|r| PathBuf::from(&r.original_path)).collect();
+        assert_eq!(paths, input_files);
+    }
+
+    #[test]
+    fn test_output_collisions() {
+        let temp_dir = tempdir().unwrap();
+        let input_files: Vec<PathBuf> = ["a/x.png", "b/x.png", "c/x.jpg", "c/y.png"]
+            .iter()
+            .map(|path| temp_dir.path().join("in").join(path))
+            .collect();
+        for input_file in &input_files {
+            fs::create_dir_all(input_file.parent().unwrap()).unwrap();
+        }
+        fs::copy("samples/p0.png", &input_files[0]).unwrap();
+        fs::copy("samples/p0.png", &input_files[1]).unwrap();
+        fs::copy("samples/level_1_0/j1.jpg", &input_files[2]).unwrap();
+        fs::copy("samples/p0.png", &input_files[3]).unwrap();
+
+        let mut options = setup_options();
+        options.base_path = temp_dir.path().join("in");
+        options.output_folder = Some(temp_dir.path().join("out"));
+        options.format = OutputFormat::Webp;
+        let output_folder = temp_dir.path().join("out");
+
+        let collisions = find_output_collisions(&input_files, &options);
+        assert_eq!(
+            collisions,
+            vec![
+                OutputCollision {
+                    input_file: input_files[1].clone(),
+                    other_input_file: input_files[0].clone(),
+                    output_path: output_folder.join("x.webp"),
+                },
+                OutputCollision {
+                    input_file: input_files[2].clone(),
+                    other_input_file: input_files[0].clone(),
+                    output_path: output_folder.join("x.webp"),
+                },
+            ]
+        );
+        options.keep_structure = true;
+        assert!(find_output_collisions(&input_files, &options).is_empty());
+        options.keep_structure = false;
+
+        resolve_output_collisions(collisions, CollisionPolicy::Rename, &input_files, &mut options);
+        assert_eq!(
+            options.collisions[&input_files[1]],
+            CollisionResolution::Rename(output_folder.join("x-1.webp"))
+        );
+        assert_eq!(
+            options.collisions[&input_files[2]],
+            CollisionResolution::Rename(output_folder.join("x-2.webp"))
+        );
+
+        let results = start_compression(&input_files, &options, None, &SilentEvents);
+        assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
+        for name in ["x.webp", "x-1.webp", "x-2.webp", "y.webp"] {
+            assert!(output_folder.join(name).exists());
+        }
+
+        let collisions = find_output_collisions(&input_files, &options);
+        resolve_output_collisions(collisions, CollisionPolicy::Skip, &input_files, &mut options);
+        let results = start_compression(&input_files, &options, None, &SilentEvents);
+        assert!(matches!(results[0].status, CompressionStatus::Success));
+        assert!(matches!(results[1].status, CompressionStatus::Skipped));
+        assert_eq!(results[1].error_code, Some(ErrorCode::OutputCollision));
+        assert_eq!(results[2].error_code, Some(ErrorCode::OutputCollision));
+    }
+
+    /// Counts the files found and the files done
+    #[derive(Default)]
+    struct CountingEvents {
+        found: AtomicUsize,
+        finished: AtomicUsize,
+    }
+
+    impl CompressionEvents for CountingEvents {
+        fn file_found(&self, _input_file: &Path) {
+            self.found.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn file_finished(&self, _result: &CompressionResult) {
+            self.finished.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_compress_stream() {
+        let temp_dir = tempdir().unwrap();
+        let input_files: Vec<PathBuf> = ["a/x.png", "b/x.png", "b/x-1.png", "c/y.png"]
+            .iter()
+            .map(|path| temp_dir.path().join("in").join(path))
+            .collect();
+        for input_file in &input_files {
+            fs::create_dir_all(input_file.parent().unwrap()).unwrap();
+            fs::copy("samples/p0.png", input_file).unwrap();
+        }
+        let mut options = setup_options();
+        options.base_path = temp_dir.path().join("in");
+        options.output_folder = Some(temp_dir.path().join("out"));
+        let output_folder = temp_dir.path().join("out");
+
+        // The first file to claim a path keeps it, renamed files taking the next free path
+        let claims = OutputClaims::new(CollisionPolicy::Rename);
+        let resolutions: Vec<Option<CollisionResolution>> =
+            input_files.iter().map(|file| claims.claim(file, &options)).collect();
+        assert_eq!(
+            resolutions,
+            vec![
+                None,
+                Some(CollisionResolution::Rename(output_folder.join("x-1.png"))),
+                Some(CollisionResolution::Rename(output_folder.join("x-1-1.png"))),
+                None,
+            ]
+        );
+
+        let progress = CountingEvents::default();
+        let (sender, receiver) = mpsc::channel();
+        for input_file in &input_files {
+            sender.send((input_file.clone(), ())).unwrap();
+        }
+        drop(sender);
+        let claims = OutputClaims::new(CollisionPolicy::Error);
+        let results: Vec<CompressionResult> =
+            compress_stream(receiver, &options, &claims, Some(DryRunMode::Simulate), &progress)
+                .into_iter()
+                .map(|(_, result)| result)
+                .collect();
+        assert_eq!(progress.found.load(Ordering::Relaxed), 4);
+        assert_eq!(progress.finished.load(Ordering::Relaxed), 4);
+        let paths: Vec<&str> = results.iter().map(|result| result.original_path.as_str()).collect();
+        assert_eq!(
+            paths,
+            input_files
+                .iter()
+                .map(|file| file.to_str().unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert!(matches!(results[0].status, CompressionStatus::Success));
+        assert!(matches!(results[1].status, CompressionStatus::Error));
+        assert_eq!(results[1].error_code, Some(ErrorCode::OutputCollision));
+        assert!(matches!(results[2].status, CompressionStatus::Success));
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let reservation = budget.reserve(60);
+        assert!(budget.try_reserve(60).is_none());
+        assert!(budget.try_reserve(40).is_some());
+        let admitted = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _reservation = budget.reserve(60);
+                admitted.store(true, std::sync::atomic::Ordering::SeqCst);
+            });
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(!admitted.load(std::sync::atomic::Ordering::SeqCst));
+            drop(reservation);
+        });
+        assert!(admitted.load(std::sync::atomic::Ordering::SeqCst));
+
+        // More than the whole budget still runs once alone
+        let _reservation = budget.reserve(1000);
+        assert_eq!(*budget.used.lock().unwrap(), 1000);
+    }
+
+    #[test]
+    fn test_timeout_workers() {
+        let workers = TimeoutWorkers {
+            running: Mutex::new(0),
+            finished: Condvar::new(),
+        };
+        let worker = workers.start(1, Duration::from_millis(10)).unwrap();
+        assert!(workers.start(1, Duration::from_millis(10)).is_none());
+
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| workers.start(1, Duration::from_secs(600)).is_some());
+            std::thread::sleep(Duration::from_millis(100));
+            drop(worker);
+            assert!(waiting.join().unwrap());
+        });
+        assert_eq!(*workers.running.lock().unwrap(), 0);
+    }
+
     #[test]
     fn test_no_upscale_prevents_resize() {
         // Use an existing sample image and determine its real resolution
@@ -931,6 +2247,33 @@ mod tests {
         assert_eq!(params.height, 0);
     }
 
+    #[test]
+    fn test_colors_survive_resize_and_compression() {
+        let input_path = absolute(PathBuf::from("samples/p0.png")).unwrap();
+        let mut options = setup_options();
+        options.colors = Some(8);
+        options.width = Some(100);
+
+        let mut compression_result = CompressionResult {
+            original_path: String::new(),
+            output_path: String::new(),
+            original_size: 0,
+            compressed_size: 0,
+            status: CompressionStatus::Error,
+            message: String::new(),
+            error_code: None,
+            cause: None,
+        };
+        let output = perform_image_compression(&input_path, &options, &mut compression_result).unwrap();
+
+        let image = image::load_from_memory(&output).unwrap();
+        assert_eq!(image.width(), 100);
+        let mut colors: Vec<[u8; 4]> = image.to_rgba8().pixels().map(|p| p.0).collect();
+        colors.sort_unstable();
+        colors.dedup();
+        assert!(colors.len() <= 8);
+    }
+
     #[test]
     fn test_build_resize_parameters() {
         let input_path = absolute(PathBuf::from("samples/j0.JPG")).unwrap();
@@ -1009,14 +2352,37 @@ mod tests {
         assert_eq!(params.gif.quality, 75);
     }
 
+    #[test]
+    fn test_format_after_transform() {
+        let jpeg = std::fs::read("samples/j0.JPG").unwrap();
+        let png = std::fs::read("samples/p0.png").unwrap();
+
+        assert_eq!(
+            format_after_transform(OutputFormat::Original, &jpeg, &png),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(
+            format_after_transform(OutputFormat::Original, &jpeg, &jpeg),
+            OutputFormat::Original
+        );
+        assert_eq!(
+            format_after_transform(OutputFormat::Original, &png, &png),
+            OutputFormat::Original
+        );
+        assert_eq!(
+            format_after_transform(OutputFormat::Webp, &jpeg, &png),
+            OutputFormat::Webp
+        );
+        assert_eq!(
+            format_after_transform(OutputFormat::Png, &jpeg, &png),
+            OutputFormat::Original
+        );
+    }
+
     #[test]
     fn test_min_savings_skips_files() {
         let input_files = vec![absolute(PathBuf::from("samples/j0.JPG")).unwrap()];
 
-        let multi_progress = indicatif::MultiProgress::new();
-        multi_progress.set_draw_target(ProgressDrawTarget::hidden());
-        let progress_bar = multi_progress.add(ProgressBar::new(input_files.len() as u64));
-
         // Test with very high percentage threshold - should skip files
         let temp_dir = tempdir().unwrap().path().to_path_buf();
         let mut options = setup_options();
@@ -1025,9 +2391,12 @@ mod tests {
         options.quality = Some(95); // High quality = small savings
         options.min_savings = Some(MinSavingsThreshold::Percentage(99.0)); // Require 99% savings (unrealistic)
 
-        let results = start_compression(&input_files, &options, &multi_progress, &progress_bar, false);
+        let results = start_compression(&input_files, &options, None, &SilentEvents);
         assert!(results.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
         assert!(results.iter().all(|r| r.message.contains("Insufficient savings")));
+        assert!(results
+            .iter()
+            .all(|r| r.error_code == Some(ErrorCode::InsufficientSavings)));
         // Files should NOT be written when skipped
         assert!(results.iter().all(|r| !fs::exists(&r.output_path).unwrap_or(true)));
 
@@ -1039,7 +2408,7 @@ mod tests {
         options2.quality = Some(95);
         options2.min_savings = Some(MinSavingsThreshold::Bytes(100_000_000)); // Require 100MB savings (unrealistic)
 
-        let results2 = start_compression(&input_files, &options2, &multi_progress, &progress_bar, false);
+        let results2 = start_compression(&input_files, &options2, None, &SilentEvents);
         assert!(results2.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
         assert!(results2.iter().all(|r| r.message.contains("Insufficient savings")));
 
@@ -1051,7 +2420,7 @@ mod tests {
         options3.quality = Some(50); // Lower quality = more savings
         options3.min_savings = Some(MinSavingsThreshold::Percentage(0.1)); // Very low threshold
 
-        let results3 = start_compression(&input_files, &options3, &multi_progress, &progress_bar, false);
+        let results3 = start_compression(&input_files, &options3, None, &SilentEvents);
         assert!(results3.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
         assert!(results3.iter().all(|r| fs::exists(&r.output_path).unwrap_or(false)));
 
@@ -1063,7 +2432,7 @@ mod tests {
         options4.quality = Some(95);
         options4.min_savings = Some(MinSavingsThreshold::Percentage(50.5)); // 50.5% threshold
 
-        let results4 = start_compression(&input_files, &options4, &multi_progress, &progress_bar, false);
+        let results4 = start_compression(&input_files, &options4, None, &SilentEvents);
         // With high quality (95), savings should be less than 50.5%, so files should be skipped
         assert!(results4.iter().all(|r| matches!(r.status, CompressionStatus::Skipped)));
 
@@ -1075,11 +2444,11 @@ mod tests {
         options5.quality = Some(95);
         options5.min_savings = None;
 
-        let results5 = start_compression(&input_files, &options5, &multi_progress, &progress_bar, false);
+        let results5 = start_compression(&input_files, &options5, None, &SilentEvents);
         assert!(results5.iter().all(|r| matches!(r.status, CompressionStatus::Success)));
     }
 
-    fn setup_options() -> CompressionOptions {
+    pub(crate) fn setup_options() -> CompressionOptions {
         CompressionOptions {
             quality: Some(80),
             lossless: false,
@@ -1096,6 +2465,10 @@ mod tests {
             max_size: None,
             keep_dates: false,
             exif: true,
+            keep_metadata: vec![],
+            strip_tags: vec![],
+            set_meta: vec![],
+            dpi: None,
             png_opt_level: 0,
             jpeg_chroma_subsampling: ChromaSubsampling::Auto,
             jpeg_baseline: false,
@@ -1104,6 +2477,21 @@ mod tests {
             no_upscale: false,
             strip_icc: false,
             min_savings: None,
+            max_input_size: u64::MAX,
+            memory_limit: None,
+            order: FileOrder::SizeDesc,
+            collisions: HashMap::new(),
+            file_timeout: None,
+            timeout_fallback: false,
+            auto_orient: false,
+            convert_to_srgb: false,
+            srgb_profile: SrgbProfile::Strip,
+            grayscale: false,
+            colors: None,
+            dithering: 1.0,
+            watermark: None,
+            rotate: None,
+            flip: None,
         }
     }
 }
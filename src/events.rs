@@ -0,0 +1,123 @@
+use crate::compressor::CompressionResult;
+use std::path::{Path, PathBuf};
+
+/// Receives the progress of a compression run, called from the compression threads. Every event is optional
+pub trait CompressionEvents: Sync {
+    /// The inputs are being listed, before any file is compressed
+    fn scan_started(&self) {}
+
+    /// All the files were found, `input_files` holding them sorted by the sort key of the scan
+    fn scan_finished(&self, _input_files: &[PathBuf]) {}
+
+    /// A file is to be compressed, while the scan is still going on when compressing while scanning
+    fn file_found(&self, _input_file: &Path) {}
+
+    fn file_started(&self, _input_file: &Path) {}
+
+    fn file_finished(&self, _result: &CompressionResult) {}
+
+    /// All the files are done, the results being in the order they are returned
+    fn batch_finished(&self, _results: &[CompressionResult]) {}
+}
+
+/// Ignores every event
+pub struct SilentEvents;
+
+impl CompressionEvents for SilentEvents {}
+
+impl<T: CompressionEvents + ?Sized> CompressionEvents for &T {
+    fn scan_started(&self) {
+        (**self).scan_started();
+    }
+
+    fn scan_finished(&self, input_files: &[PathBuf]) {
+        (**self).scan_finished(input_files);
+    }
+
+    fn file_found(&self, input_file: &Path) {
+        (**self).file_found(input_file);
+    }
+
+    fn file_started(&self, input_file: &Path) {
+        (**self).file_started(input_file);
+    }
+
+    fn file_finished(&self, result: &CompressionResult) {
+        (**self).file_finished(result);
+    }
+
+    fn batch_finished(&self, results: &[CompressionResult]) {
+        (**self).batch_finished(results);
+    }
+}
+
+impl<T: CompressionEvents> CompressionEvents for Option<T> {
+    fn scan_started(&self) {
+        if let Some(events) = self {
+            events.scan_started();
+        }
+    }
+
+    fn scan_finished(&self, input_files: &[PathBuf]) {
+        if let Some(events) = self {
+            events.scan_finished(input_files);
+        }
+    }
+
+    fn file_found(&self, input_file: &Path) {
+        if let Some(events) = self {
+            events.file_found(input_file);
+        }
+    }
+
+    fn file_started(&self, input_file: &Path) {
+        if let Some(events) = self {
+            events.file_started(input_file);
+        }
+    }
+
+    fn file_finished(&self, result: &CompressionResult) {
+        if let Some(events) = self {
+            events.file_finished(result);
+        }
+    }
+
+    fn batch_finished(&self, results: &[CompressionResult]) {
+        if let Some(events) = self {
+            events.batch_finished(results);
+        }
+    }
+}
+
+/// Both receive every event, the first one first
+impl<A: CompressionEvents, B: CompressionEvents> CompressionEvents for (A, B) {
+    fn scan_started(&self) {
+        self.0.scan_started();
+        self.1.scan_started();
+    }
+
+    fn scan_finished(&self, input_files: &[PathBuf]) {
+        self.0.scan_finished(input_files);
+        self.1.scan_finished(input_files);
+    }
+
+    fn file_found(&self, input_file: &Path) {
+        self.0.file_found(input_file);
+        self.1.file_found(input_file);
+    }
+
+    fn file_started(&self, input_file: &Path) {
+        self.0.file_started(input_file);
+        self.1.file_started(input_file);
+    }
+
+    fn file_finished(&self, result: &CompressionResult) {
+        self.0.file_finished(result);
+        self.1.file_finished(result);
+    }
+
+    fn batch_finished(&self, results: &[CompressionResult]) {
+        self.0.batch_finished(results);
+        self.1.batch_finished(results);
+    }
+}
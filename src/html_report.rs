@@ -0,0 +1,376 @@
+use crate::options::HtmlCompare;
+use crate::stats::CompressionStats;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytesize::ByteSize;
+use caesiumclt::{CompressionEvents, CompressionResult, CompressionStatus};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageFormat, ImageReader};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::{fs, io};
+
+/// Longest side of the embedded thumbnails, in pixels
+const THUMBNAIL_SIZE: u32 = 320;
+const THUMBNAIL_QUALITY: u8 = 85;
+
+const STYLE: &str = r#"
+body { font-family: system-ui, sans-serif; margin: 2em; color: #222; }
+.summary { display: flex; flex-wrap: wrap; gap: 1em; margin-bottom: 2em; }
+.summary div { background: #f3f4f6; border-radius: 6px; padding: 0.8em 1.2em; }
+.summary strong { display: block; font-size: 1.4em; }
+table { border-collapse: collapse; width: 100%; }
+th, td { border-bottom: 1px solid #ddd; padding: 0.4em 0.6em; text-align: left; vertical-align: top; }
+th { cursor: pointer; user-select: none; background: #f9fafb; position: sticky; top: 0; }
+th::after { content: " \2195"; color: #aaa; }
+.success { color: #15803d; } .skipped { color: #b45309; } .error { color: #b91c1c; }
+.side-by-side { display: flex; gap: 0.5em; }
+figure { margin: 0; } figcaption { font-size: 0.8em; color: #666; }
+.slider { position: relative; }
+.slider img { display: block; }
+.slider .output { position: absolute; top: 0; left: 0; width: 50%; height: 100%; overflow: hidden; }
+.slider input { width: 100%; }
+"#;
+
+/// Sorts the table by the clicked column, using the data-sort value of the cells, numerically when possible
+const SCRIPT: &str = r#"
+document.querySelectorAll("th").forEach((header, column) => {
+  header.addEventListener("click", () => {
+    const body = header.closest("table").tBodies[0];
+    const ascending = header.dataset.order !== "asc";
+    header.dataset.order = ascending ? "asc" : "desc";
+    const value = row => row.cells[column].dataset.sort ?? row.cells[column].textContent;
+    const rows = Array.from(body.rows).sort((a, b) => {
+      const [x, y] = [value(a), value(b)];
+      const result = isNaN(x) || isNaN(y) ? x.localeCompare(y) : x - y;
+      return ascending ? result : -result;
+    });
+    rows.forEach(row => body.appendChild(row));
+  });
+});
+"#;
+
+struct Thumbnail {
+    data_uri: String,
+    width: u32,
+}
+
+/// Thumbnails of the original and of the output
+type Thumbnails = (Option<Thumbnail>, Option<Thumbnail>);
+
+/// Thumbnails of the original and the output of each file, by path as shown in the results. They are built by the
+/// compression threads, so that they count in the memory of the file being compressed, and the original is read
+/// before the output is written, as it may be written over the original
+pub struct HtmlThumbnails {
+    dry_run: bool,
+    max_input_size: u64,
+    thumbnails: Mutex<HashMap<String, Thumbnails>>,
+}
+
+impl HtmlThumbnails {
+    pub fn new(dry_run: bool, max_input_size: u64) -> Self {
+        Self {
+            dry_run,
+            max_input_size,
+            thumbnails: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Thumbnails>> {
+        self.thumbnails.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl CompressionEvents for HtmlThumbnails {
+    fn file_started(&self, input_file: &Path) {
+        // Files over the size limit are skipped without being read
+        let fits = fs::metadata(input_file).is_ok_and(|metadata| metadata.len() <= self.max_input_size);
+        let original = fits.then(|| build_thumbnail(input_file)).flatten();
+        self.lock().insert(input_file.display().to_string(), (original, None));
+    }
+
+    fn file_finished(&self, result: &CompressionResult) {
+        // Outputs of skipped files and dry runs may be left from previous runs
+        if self.dry_run || !matches!(result.status, CompressionStatus::Success) {
+            return;
+        }
+        let output = build_thumbnail(Path::new(&result.output_path));
+        self.lock().entry(result.original_path.clone()).or_default().1 = output;
+    }
+}
+
+pub fn write_html_report(
+    path: &Path,
+    compression_results: &[CompressionResult],
+    comparison: Option<(HtmlCompare, &HtmlThumbnails)>,
+) -> io::Result<()> {
+    fs::write(path, build_html_report(compression_results, comparison))
+}
+
+fn build_html_report(
+    compression_results: &[CompressionResult],
+    comparison: Option<(HtmlCompare, &HtmlThumbnails)>,
+) -> String {
+    let stats = CompressionStats::from_results(compression_results);
+    let compare = comparison.map(|(compare, _)| compare);
+    let thumbnails = comparison.map(|(_, thumbnails)| thumbnails.lock());
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str("<title>Caesium compression report</title>\n");
+    let _ = writeln!(html, "<style>{STYLE}</style>\n</head>\n<body>");
+    html.push_str("<h1>Caesium compression report</h1>\n");
+
+    let summary = [
+        ("Files", compression_results.len().to_string()),
+        ("Success", stats.success.to_string()),
+        ("Skipped", stats.skipped.to_string()),
+        ("Errors", stats.errors.to_string()),
+        ("Original size", ByteSize::b(stats.total_original_size).to_string()),
+        ("Compressed size", ByteSize::b(stats.total_compressed_size).to_string()),
+        (
+            "Savings",
+            format!(
+                "{} ({:.2}%)",
+                format_signed_size(stats.savings_bytes()),
+                stats.savings_percent()
+            ),
+        ),
+    ];
+    html.push_str("<section class=\"summary\">\n");
+    for (label, value) in summary {
+        let _ = writeln!(html, "<div><strong>{}</strong>{label}</div>", escape_html(&value));
+    }
+    html.push_str("</section>\n");
+
+    html.push_str("<table>\n<thead><tr>");
+    let mut headers = vec![
+        "File",
+        "Output",
+        "Status",
+        "Original",
+        "Compressed",
+        "Savings",
+        "Message",
+    ];
+    if compare.is_some() {
+        headers.push("Comparison");
+    }
+    for header in headers {
+        let _ = write!(html, "<th>{header}</th>");
+    }
+    html.push_str("</tr></thead>\n<tbody>\n");
+
+    for result in compression_results {
+        let savings = result.original_size as i64 - result.compressed_size as i64;
+        let savings_percent = if result.original_size > 0 {
+            (savings as f64 / result.original_size as f64) * 100.0
+        } else {
+            0.0
+        };
+        let status = result.status.to_string();
+
+        html.push_str("<tr>");
+        let _ = write!(html, "<td>{}</td>", escape_html(&result.original_path));
+        let _ = write!(html, "<td>{}</td>", escape_html(&result.output_path));
+        let _ = write!(html, "<td class=\"{}\">{status}</td>", status.to_lowercase());
+        let _ = write!(
+            html,
+            "<td data-sort=\"{}\">{}</td>",
+            result.original_size,
+            ByteSize::b(result.original_size)
+        );
+        let _ = write!(
+            html,
+            "<td data-sort=\"{}\">{}</td>",
+            result.compressed_size,
+            ByteSize::b(result.compressed_size)
+        );
+        let _ = write!(
+            html,
+            "<td data-sort=\"{savings_percent:.4}\">{} ({savings_percent:.2}%)</td>",
+            format_signed_size(savings)
+        );
+        let _ = write!(html, "<td>{}</td>", escape_html(&result.message));
+        if let (Some(compare), Some(thumbnails)) = (compare, &thumbnails) {
+            let (original, output) = thumbnails
+                .get(&result.original_path)
+                .map_or((None, None), |(original, output)| (original.as_ref(), output.as_ref()));
+            let _ = write!(html, "<td>{}</td>", render_comparison(compare, original, output));
+        }
+        html.push_str("</tr>\n");
+    }
+
+    html.push_str("</tbody>\n</table>\n");
+    let _ = writeln!(html, "<script>{SCRIPT}</script>\n</body>\n</html>");
+
+    html
+}
+
+fn render_comparison(compare: HtmlCompare, original: Option<&Thumbnail>, output: Option<&Thumbnail>) -> String {
+    let figure = |thumbnail: &Thumbnail, caption: &str| {
+        format!(
+            "<figure><img src=\"{}\" width=\"{}\" alt=\"{caption}\"><figcaption>{caption}</figcaption></figure>",
+            thumbnail.data_uri, thumbnail.width
+        )
+    };
+
+    match (compare, original, output) {
+        (_, None, None) => String::new(),
+        (HtmlCompare::SideBySide, original, output) => {
+            let figures: Vec<String> = [(original, "Original"), (output, "Compressed")]
+                .into_iter()
+                .filter_map(|(thumbnail, caption)| Some(figure(thumbnail?, caption)))
+                .collect();
+            format!("<div class=\"side-by-side\">{}</div>", figures.concat())
+        }
+        // Both images are shown at the width of the original, the output revealed from the left by the slider
+        (HtmlCompare::Slider, Some(original), Some(output)) => format!(
+            "<div class=\"slider\" style=\"width: {width}px\"><img src=\"{}\" width=\"{width}\" alt=\"Original\">\
+             <div class=\"output\"><img src=\"{}\" width=\"{width}\" alt=\"Compressed\"></div>\
+             <input type=\"range\" min=\"0\" max=\"100\" value=\"50\" \
+             oninput=\"this.previousElementSibling.style.width = this.value + '%'\"></div>\
+             <figcaption>Compressed | Original</figcaption>",
+            original.data_uri,
+            output.data_uri,
+            width = original.width
+        ),
+        (HtmlCompare::Slider, Some(thumbnail), None) => figure(thumbnail, "Original"),
+        (HtmlCompare::Slider, None, Some(thumbnail)) => figure(thumbnail, "Compressed"),
+    }
+}
+
+/// Downscaled copy of the image, embedded as a JPEG data URI, or PNG to keep the transparency
+/// The format is guessed from the content, as the extension of the file may not match it
+fn build_thumbnail(path: &Path) -> Option<Thumbnail> {
+    let image = ImageReader::open(path)
+        .and_then(ImageReader::with_guessed_format)
+        .ok()?
+        .decode()
+        .ok()?
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+
+    let mut bytes = Vec::new();
+    let mime_type = if image.color().has_alpha() {
+        image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).ok()?;
+        "image/png"
+    } else {
+        let encoder = JpegEncoder::new_with_quality(&mut bytes, THUMBNAIL_QUALITY);
+        DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(encoder)
+            .ok()?;
+        "image/jpeg"
+    };
+
+    Some(Thumbnail {
+        data_uri: format!("data:{mime_type};base64,{}", STANDARD.encode(bytes)),
+        width: image.width(),
+    })
+}
+
+fn format_signed_size(size: i64) -> String {
+    let sign = if size > 0 { "-" } else { "+" };
+    match size {
+        0 => "0 B".to_string(),
+        _ => format!("{sign}{}", ByteSize::b(size.unsigned_abs())),
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::result;
+
+    #[test]
+    fn test_build_html_report() {
+        let mut success = result("samples/p0.png", CompressionStatus::Success);
+        success.output_path = "samples/p0.png".to_string();
+        let mut error = result("samples/missing.png", CompressionStatus::Error);
+        error.message = "<none>".to_string();
+        let results = vec![success, error];
+
+        let html = build_html_report(&results, None);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<strong>2</strong>Files"));
+        assert!(html.contains("<strong>-800 B (40.00%)</strong>Savings"));
+        assert!(html.contains("<td data-sort=\"40.0000\">-400 B (40.00%)</td>"));
+        assert!(html.contains("<td class=\"error\">Error</td>"));
+        assert!(html.contains("&lt;none&gt;"));
+        assert!(!html.contains("<img"));
+
+        let thumbnails = HtmlThumbnails::new(false, u64::MAX);
+        for result in &results {
+            thumbnails.file_started(Path::new(&result.original_path));
+            thumbnails.file_finished(result);
+        }
+        let html = build_html_report(&results, Some((HtmlCompare::Slider, &thumbnails)));
+        assert_eq!(html.matches("<div class=\"slider\"").count(), 1);
+        assert!(html.contains(";base64,"));
+
+        let thumbnails = HtmlThumbnails::new(true, u64::MAX);
+        for result in &results {
+            thumbnails.file_started(Path::new(&result.original_path));
+            thumbnails.file_finished(result);
+        }
+        let html = build_html_report(&results, Some((HtmlCompare::SideBySide, &thumbnails)));
+        assert_eq!(html.matches("<figure>").count(), 1);
+        assert!(html.contains("alt=\"Original\""));
+    }
+
+    #[test]
+    fn test_html_thumbnails_in_place() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("a.png");
+        fs::copy("samples/p0.png", &path).unwrap();
+        let path_string = path.to_string_lossy().to_string();
+
+        // The original is kept from before the file is written over
+        let thumbnails = HtmlThumbnails::new(false, u64::MAX);
+        thumbnails.file_started(&path);
+        fs::copy("samples/level_1_0/j1.jpg", &path).unwrap();
+        let mut compressed = result(&path_string, CompressionStatus::Success);
+        compressed.output_path = path_string.clone();
+        thumbnails.file_finished(&compressed);
+        {
+            let thumbnails = thumbnails.lock();
+            let (original, output) = &thumbnails[&path_string];
+            let expected_original = build_thumbnail(Path::new("samples/p0.png")).unwrap();
+            let expected_output = build_thumbnail(Path::new("samples/level_1_0/j1.jpg")).unwrap();
+            assert_eq!(original.as_ref().unwrap().data_uri, expected_original.data_uri);
+            assert_eq!(output.as_ref().unwrap().data_uri, expected_output.data_uri);
+        }
+
+        // Too large to be compressed, so not read either
+        let thumbnails = HtmlThumbnails::new(false, 10);
+        thumbnails.file_started(&path);
+        assert!(thumbnails.lock()[&path_string].0.is_none());
+    }
+
+    #[test]
+    fn test_build_thumbnail() {
+        let thumbnail = build_thumbnail(Path::new("samples/level_1_0/j1.jpg")).unwrap();
+        assert!(thumbnail.data_uri.starts_with("data:image/jpeg;base64,"));
+        assert!(thumbnail.width <= THUMBNAIL_SIZE);
+        assert!(build_thumbnail(Path::new("samples/missing.png")).is_none());
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html("<a href=\"x\">Tom & Jerry's</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+    }
+}
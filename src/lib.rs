@@ -0,0 +1,357 @@
+//! Batch image compression on top of libcaesium, as run by the `caesiumclt` command line: scanning folders,
+//! compressing the files found on the rayon thread pool and reporting the progress through [`CompressionEvents`].
+//!
+//! ```no_run
+//! use caesiumclt::{compress_inputs, CollisionPolicy, CompressionOptions, ScanOptions, SilentEvents};
+//!
+//! let options = CompressionOptions::builder()
+//!     .quality(Some(80))
+//!     .output_folder(Some("out".into()));
+//! let results = compress_inputs(
+//!     &["photos".to_string()],
+//!     &ScanOptions::default(),
+//!     options,
+//!     CollisionPolicy::Rename,
+//!     None,
+//!     &SilentEvents,
+//! )
+//! .expect("the inputs have a common folder");
+//! println!("{}", serde_json::to_string(&results).unwrap());
+//! ```
+
+mod compressor;
+mod events;
+mod metadata;
+mod scan_files;
+mod transform;
+
+pub use compressor::{
+    CollisionPolicy, CompressionOptions, CompressionOptionsBuilder, CompressionResult, CompressionStatus, DryRunMode,
+    ErrorCode, FileOrder, MinSavingsThreshold, OutputCollision, OutputFormat, OverwritePolicy, WatermarkOptions,
+};
+pub use events::{CompressionEvents, SilentEvents};
+pub use metadata::{MetadataField, MetadataKind, MetadataValue, StripTag};
+pub use scan_files::{scan_files, Dimensions, FileFilters, ScanOptions, SortKey};
+pub use transform::{Flip, Rotation, SrgbProfile, WatermarkPosition};
+
+use crate::compressor::{
+    compress_stream, find_output_collisions, perform_image_compression, resolve_output_collisions, start_compression,
+    OutputClaims,
+};
+use crate::scan_files::{input_base_path, sample_files, stream_files};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+/// Files found by the scan and not yet picked up for compression, past which the scan waits
+const SCAN_QUEUE_LENGTH: usize = 256;
+
+/// Why a batch could not be started
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BatchError {
+    /// No folder holding all the inputs could be found, so the output paths cannot be computed
+    NoBasePath,
+    /// Several files would be written to the same output path, with the `Error` collision policy
+    OutputCollisions(Vec<OutputCollision>),
+}
+
+impl Display for BatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchError::NoBasePath => write!(f, "Unable to compute the base path for the files."),
+            BatchError::OutputCollisions(collisions) => {
+                write!(f, "Output path collisions:")?;
+                for collision in collisions {
+                    write!(
+                        f,
+                        "\n{} and {} would both be written to {}",
+                        collision.other_input_file.display(),
+                        collision.input_file.display(),
+                        collision.output_path.display()
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for BatchError {}
+
+/// Why a file could not be compressed, as in its result
+#[derive(Debug)]
+pub struct CompressionError {
+    pub error_code: ErrorCode,
+    pub message: String,
+    /// The underlying error, when there is one
+    pub cause: Option<String>,
+}
+
+impl Display for CompressionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for CompressionError {}
+
+/// Scans the inputs and compresses the files found, the base path of the options being the folder holding all the
+/// inputs. The files are compressed while the scan goes on with `FileOrder::Scan`, unless the collision policy is
+/// `Error` or files are sampled, as the collisions must be listed before anything is written and the sample is picked
+/// among all the files. Otherwise they are all found first, and the run stops on the first collision found with
+/// `Error`. The results are sorted by the sort key of the scan options, and are empty when no file was found
+pub fn compress_inputs(
+    inputs: &[String],
+    scan_options: &ScanOptions,
+    options: CompressionOptionsBuilder,
+    on_collision: CollisionPolicy,
+    dry_run: Option<DryRunMode>,
+    events: &dyn CompressionEvents,
+) -> Result<Vec<CompressionResult>, BatchError> {
+    let Some(base_path) = input_base_path(inputs) else {
+        return if inputs.iter().any(|input| Path::new(input).exists()) {
+            Err(BatchError::NoBasePath)
+        } else {
+            Ok(Vec::new())
+        };
+    };
+    let mut options = options.base_path(base_path).build();
+    if options.order == FileOrder::Scan && scan_options.sample.is_none() && on_collision != CollisionPolicy::Error {
+        return Ok(compress_while_scanning(
+            inputs,
+            scan_options,
+            &options,
+            on_collision,
+            dry_run,
+            events,
+        ));
+    }
+
+    let (_, input_files) = scan_files(inputs, scan_options, events);
+    let sampled_files = scan_options.sample.map(|count| sample_files(&input_files, count));
+    let files_to_compress = sampled_files.as_deref().unwrap_or(&input_files);
+    let collisions = find_output_collisions(files_to_compress, &options);
+    if !collisions.is_empty() && on_collision == CollisionPolicy::Error {
+        return Err(BatchError::OutputCollisions(collisions));
+    }
+    resolve_output_collisions(collisions, on_collision, files_to_compress, &mut options);
+
+    for input_file in files_to_compress {
+        events.file_found(input_file);
+    }
+    Ok(start_compression(files_to_compress, &options, dry_run, events))
+}
+
+/// Compresses one file in memory, without writing anything, e.g. to compare settings on the same files
+pub fn compress_file_in_memory(input_file: &Path, options: &CompressionOptions) -> Result<Vec<u8>, CompressionError> {
+    let mut result = CompressionResult {
+        original_path: input_file.display().to_string(),
+        output_path: String::new(),
+        original_size: 0,
+        compressed_size: 0,
+        status: CompressionStatus::Error,
+        message: String::new(),
+        error_code: None,
+        cause: None,
+    };
+
+    perform_image_compression(&input_file.to_path_buf(), options, &mut result).ok_or_else(|| CompressionError {
+        error_code: result.error_code.unwrap_or(ErrorCode::CompressionFailed),
+        message: result.message,
+        cause: result.cause,
+    })
+}
+
+/// Files that would be written to the same output path are handled as they are found, the first one found keeping the
+/// path
+fn compress_while_scanning(
+    inputs: &[String],
+    scan_options: &ScanOptions,
+    options: &CompressionOptions,
+    on_collision: CollisionPolicy,
+    dry_run: Option<DryRunMode>,
+    events: &dyn CompressionEvents,
+) -> Vec<CompressionResult> {
+    let output_claims = OutputClaims::new(on_collision);
+    let (sender, receiver) = mpsc::sync_channel(SCAN_QUEUE_LENGTH);
+    let mut results = thread::scope(|scope| {
+        scope.spawn(move || stream_files(inputs, scan_options, sender));
+        compress_stream(receiver, options, &output_claims, dry_run, events)
+    });
+    // By the rank of the files when they were found, as compressing them in place changes their size and date
+    results.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan_files::SortKey;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_compress_inputs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_folder = temp_dir.path().join("input");
+        fs::create_dir_all(input_folder.join("sub")).unwrap();
+        fs::copy("samples/p0.png", input_folder.join("sub/b.png")).unwrap();
+        fs::copy("samples/p0.png", input_folder.join("a.png")).unwrap();
+        fs::write(input_folder.join("c.png"), b"not an image").unwrap();
+
+        let scan_options = ScanOptions {
+            recursive: true,
+            check_extension_only: true,
+            ..ScanOptions::default()
+        };
+        let options = CompressionOptions::builder()
+            .format(OutputFormat::Webp)
+            .keep_structure(true)
+            .output_folder(Some(temp_dir.path().join("output")));
+        let results = compress_inputs(
+            &[input_folder.to_string_lossy().to_string()],
+            &scan_options,
+            options,
+            CollisionPolicy::Error,
+            None,
+            &SilentEvents,
+        )
+        .unwrap();
+
+        let paths: Vec<&str> = results.iter().map(|result| result.original_path.as_str()).collect();
+        assert!(paths[0].ends_with("a.png") && paths[1].ends_with("c.png") && paths[2].ends_with("sub/b.png"));
+        assert!(matches!(results[1].status, CompressionStatus::Error));
+        assert!(matches!(results[2].status, CompressionStatus::Success));
+        assert!(temp_dir.path().join("output/sub/b.webp").exists());
+
+        assert!(compress_inputs(
+            &["/non/existent/path".to_string()],
+            &scan_options,
+            CompressionOptions::builder(),
+            CollisionPolicy::Error,
+            None,
+            &SilentEvents,
+        )
+        .unwrap()
+        .is_empty());
+    }
+
+    #[test]
+    fn test_compress_inputs_in_place_order() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (a, b) = (temp_dir.path().join("a.png"), temp_dir.path().join("b.png"));
+        fs::copy("samples/p0.png", &a).unwrap();
+        // Fails, so it is not written over
+        fs::write(&b, b"not an image").unwrap();
+        let now = SystemTime::now();
+        let set_modified = |path: &Path, modified: SystemTime| {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        set_modified(&a, now - Duration::from_secs(7200));
+        set_modified(&b, now - Duration::from_secs(3600));
+
+        let scan_options = ScanOptions {
+            check_extension_only: true,
+            sort_key: SortKey::Mtime,
+            ..ScanOptions::default()
+        };
+        let options = CompressionOptions::builder()
+            .same_folder_as_input(true)
+            .order(FileOrder::Scan);
+        let results = compress_inputs(
+            &[temp_dir.path().to_string_lossy().to_string()],
+            &scan_options,
+            options,
+            CollisionPolicy::Skip,
+            None,
+            &SilentEvents,
+        )
+        .unwrap();
+
+        // a.png is now the latest modified file, but it is still sorted as it was found
+        assert!(fs::metadata(&a).unwrap().modified().unwrap() > now - Duration::from_secs(3600));
+        let paths: Vec<&str> = results.iter().map(|result| result.original_path.as_str()).collect();
+        assert!(paths[0].ends_with("a.png") && paths[1].ends_with("b.png"));
+    }
+
+    #[test]
+    fn test_compress_inputs_scan_order() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input_folder = temp_dir.path().join("input");
+        fs::create_dir_all(input_folder.join("sub")).unwrap();
+        fs::copy("samples/p0.png", input_folder.join("a.png")).unwrap();
+        fs::copy("samples/p0.png", input_folder.join("sub/a.png")).unwrap();
+        fs::copy("samples/p0.png", input_folder.join("sub/b.png")).unwrap();
+        fs::write(input_folder.join("c.png"), b"not an image").unwrap();
+
+        let scan_options = ScanOptions {
+            recursive: true,
+            check_extension_only: true,
+            ..ScanOptions::default()
+        };
+        let compress = |order: FileOrder, keep_structure: bool, on_collision: CollisionPolicy| {
+            let output_folder = temp_dir.path().join(format!("{order:?}-{on_collision:?}"));
+            let options = CompressionOptions::builder()
+                .order(order)
+                .keep_structure(keep_structure)
+                .output_folder(Some(output_folder.clone()));
+            compress_inputs(
+                &[input_folder.to_string_lossy().to_string()],
+                &scan_options,
+                options,
+                on_collision,
+                None,
+                &SilentEvents,
+            )
+            .map(|results| {
+                results
+                    .into_iter()
+                    .map(|mut result| {
+                        result.output_path = result.output_path.replace(&*output_folder.to_string_lossy(), "");
+                        serde_json::to_value(result).unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        // Compressing while scanning gives the same results as compressing once all the files are found
+        let results = compress(FileOrder::Scan, true, CollisionPolicy::Skip).unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results, compress(FileOrder::Path, true, CollisionPolicy::Skip).unwrap());
+
+        // And both stop before compressing anything on collisions
+        for order in [FileOrder::Scan, FileOrder::Path] {
+            let Err(BatchError::OutputCollisions(collisions)) = compress(order, false, CollisionPolicy::Error) else {
+                panic!("the collisions are not reported with {order:?}");
+            };
+            assert_eq!(collisions.len(), 1);
+            assert_eq!(collisions[0].input_file, input_folder.join("sub/a.png"));
+            assert!(!temp_dir.path().join(format!("{order:?}-Error")).exists());
+        }
+    }
+
+    #[test]
+    fn test_compress_file_in_memory() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let options = CompressionOptions::builder().quality(Some(80)).build();
+        let compressed = compress_file_in_memory(Path::new("samples/p0.png"), &options).unwrap();
+        assert!(!compressed.is_empty());
+
+        let invalid_file = temp_dir.path().join("invalid.png");
+        fs::write(&invalid_file, b"not an image").unwrap();
+        let error = compress_file_in_memory(&invalid_file, &options).unwrap_err();
+        assert_eq!(error.to_string(), error.message);
+        assert!(matches!(
+            error.error_code,
+            ErrorCode::DecodeFailed | ErrorCode::UnsupportedFormat
+        ));
+    }
+}
@@ -1,15 +1,24 @@
-use crate::compressor::{start_compression, CompressionOptions, CompressionResult, CompressionStatus};
-use crate::options::{CommandLineArgs, JpegChromaSubsampling};
-use crate::scan_files::scan_files;
+use crate::html_report::{write_html_report, HtmlThumbnails};
+use crate::options::{Command, CommandLineArgs, FailOn, JpegChromaSubsampling, ProgressMode, ReportFormat};
+use crate::progress::{JsonEvents, TerminalProgress};
+use crate::report::{build_csv_report, Report};
+use crate::stats::CompressionStats;
 use bytesize::ByteSize;
 use caesium::parameters::ChromaSubsampling;
-use clap::Parser;
-use colored::Colorize;
+use caesiumclt::{
+    compress_inputs, BatchError, CompressionEvents, CompressionOptions, CompressionOptionsBuilder, CompressionResult,
+    CompressionStatus, DryRunMode, ErrorCode, FileFilters, MetadataKind, ScanOptions, SilentEvents, WatermarkOptions,
+};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use colored::{ColoredString, Colorize};
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use serde::Serialize;
+use std::io;
 use std::num::NonZero;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::process::exit;
+use std::sync::{Mutex, PoisonError};
 use std::time::Duration;
 
 #[derive(Serialize)]
@@ -24,6 +33,30 @@ struct JsonSummary {
     savings_percent: f64,
 }
 
+#[derive(Serialize)]
+struct Estimate {
+    measured_files: usize,
+    errored_files: usize,
+    total_files: usize,
+    original_size: u64,
+    /// Size of the input files within --max-input-size, the only ones the savings are extrapolated to
+    compressible_size: u64,
+    projected_size: u64,
+    projected_savings_bytes: i64,
+    projected_savings_percent: f64,
+}
+
+/// Last line of NDJSON reports, after the one of every file
+#[derive(Serialize)]
+struct NdjsonSummary<'a> {
+    version: &'static str,
+    dry_run: bool,
+    error: Option<&'a str>,
+    summary: JsonSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimate: Option<&'a Estimate>,
+}
+
 #[derive(Serialize)]
 struct JsonOutput<'a> {
     version: &'static str,
@@ -31,27 +64,106 @@ struct JsonOutput<'a> {
     error: Option<&'a str>,
     files: &'a [CompressionResult],
     summary: JsonSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimate: Option<&'a Estimate>,
 }
 
-mod compressor;
+mod bench;
+mod html_report;
 mod options;
-mod scan_files;
+mod progress;
+mod report;
+mod stats;
 
 const PROGRESS_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
 const FALLBACK_THREAD_COUNT: usize = 1;
 
-fn main() {
-    let args = CommandLineArgs::parse();
+/// Process exit codes, so that scripts can tell a partial failure from a complete one
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum ExitStatus {
+    Success,
+    SomeFailed,
+    AllFailed,
+    NoFiles,
+    InvalidUsage,
+}
 
-    if args.files.is_empty() {
-        if args.json {
-            write_json_output(&[], args.dry_run, Some("No files to compress"));
+impl ExitStatus {
+    fn code(self) -> i32 {
+        match self {
+            ExitStatus::Success => 0,
+            ExitStatus::SomeFailed => 1,
+            ExitStatus::AllFailed => 2,
+            ExitStatus::NoFiles => 3,
+            ExitStatus::InvalidUsage => 4,
+        }
+    }
+
+    fn from_results(compression_results: &[CompressionResult], fail_on: FailOn) -> Self {
+        let failed = compression_results
+            .iter()
+            .filter(|result| match result.status {
+                CompressionStatus::Success => false,
+                CompressionStatus::Skipped => fail_on == FailOn::Skipped,
+                CompressionStatus::Error => true,
+            })
+            .count();
+
+        if compression_results.is_empty() {
+            ExitStatus::NoFiles
+        } else if failed == 0 {
+            ExitStatus::Success
+        } else if failed < compression_results.len() {
+            ExitStatus::SomeFailed
         } else {
-            eprintln!("No files to compress");
+            ExitStatus::AllFailed
         }
+    }
+}
+
+fn main() {
+    let args = CommandLineArgs::try_parse().unwrap_or_else(|e| exit_with_usage_error(e));
+
+    if let Some(Command::Bench(bench_args)) = &args.command {
+        bench::run_bench(bench_args);
         return;
     }
 
+    if args.sample.is_some() && args.dry_run != Some(DryRunMode::Estimate) {
+        exit_with_usage_error(CommandLineArgs::command().error(
+            ErrorKind::ArgumentConflict,
+            "--sample can only be used with --dry-run=estimate",
+        ));
+    }
+
+    let options = compression_options_builder(&args).unwrap_or_else(|e| exit_with_usage_error(e));
+
+    let report_format = if args.json {
+        Some(ReportFormat::Json)
+    } else {
+        args.report
+    };
+    let report = match report_format.map(|format| Report::new(format, args.report_file.as_deref())) {
+        Some(Ok(report)) => Some(report),
+        Some(Err(e)) => {
+            eprintln!("Error creating the report file: {e}");
+            exit(ExitStatus::InvalidUsage.code());
+        }
+        None => None,
+    };
+    // A report on stdout takes the place of the terminal output
+    let report_on_stdout = report.is_some() && args.report_file.is_none();
+
+    if args.files.is_empty() {
+        write_error(
+            report.as_ref(),
+            report_on_stdout,
+            args.dry_run.is_some(),
+            "No files to compress",
+        );
+        exit(ExitStatus::NoFiles.code());
+    }
+
     let threads_number = get_parallelism_count(
         args.threads,
         std::thread::available_parallelism()
@@ -66,124 +178,259 @@ fn main() {
 
     let quiet = args.quiet || args.verbose == 0;
     let verbose = if quiet { 0 } else { args.verbose };
-    let (base_path, input_files) = scan_files(
-        &args.files,
-        args.recursive,
-        quiet || args.json,
-        args.check_extension_only,
-    );
-    let base_path = match base_path {
-        Some(bp) => bp,
-        None => {
-            if args.json {
-                write_json_output(
-                    &[],
-                    args.dry_run,
-                    Some("Unable to compute the base path for the files."),
-                );
-            } else {
-                eprintln!("Unable to compute the base path for the files.");
-            }
-            exit(-1);
-        }
-    };
-    let total_files = input_files.len();
-
-    let progress_target = if args.json {
+    let progress_target = if report_on_stdout {
         ProgressDrawTarget::stderr()
     } else {
         ProgressDrawTarget::stdout()
     };
-    let (multi_progress, progress_bar) = setup_progress_bar(total_files, verbose, progress_target);
-    let compression_options = build_compression_options(&args, &base_path);
-    let compression_results = start_compression(
-        &input_files,
-        &compression_options,
-        &multi_progress,
-        &progress_bar,
+    // Built while compressing, as the originals may be written over
+    let thumbnails = args
+        .html_report
+        .as_ref()
+        .and(args.html_compare)
+        .map(|_| HtmlThumbnails::new(args.dry_run.is_some(), args.max_input_size));
+    let progress = build_progress(args.progress, verbose, progress_target);
+    let scanned_files = ScannedFiles::default();
+    let compression_results = compress_inputs(
+        &args.files,
+        &build_scan_options(&args),
+        options,
+        args.on_collision,
         args.dry_run,
-    );
-    progress_bar.finish();
-
-    if args.json {
-        write_json_output(&compression_results, args.dry_run, None);
-    } else {
+        &(
+            &scanned_files,
+            (progress.as_ref(), (report.as_ref(), thumbnails.as_ref())),
+        ),
+    )
+    .unwrap_or_else(|e| {
+        let message = match &e {
+            BatchError::OutputCollisions(collisions) => {
+                let collisions: Vec<String> = collisions
+                    .iter()
+                    .map(|collision| {
+                        format!(
+                            "{} and {} would both be written to {}",
+                            collision.other_input_file.display(),
+                            collision.input_file.display(),
+                            collision.output_path.display()
+                        )
+                    })
+                    .collect();
+                format!(
+                    "Output path collisions, use --on-collision to rename or skip the files:\n{}",
+                    collisions.join("\n")
+                )
+            }
+            e => e.to_string(),
+        };
+        write_error(report.as_ref(), report_on_stdout, args.dry_run.is_some(), &message);
+        exit(ExitStatus::InvalidUsage.code());
+    });
+    if compression_results.is_empty() {
+        write_error(
+            report.as_ref(),
+            report_on_stdout,
+            args.dry_run.is_some(),
+            "No files to compress",
+        );
+        exit(ExitStatus::NoFiles.code());
+    }
+    // Compressing while scanning leaves no file out, so the results hold all the files found
+    let input_files = scanned_files
+        .0
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner)
+        .unwrap_or_else(|| {
+            compression_results
+                .iter()
+                .map(|result| PathBuf::from(&result.original_path))
+                .collect()
+        });
+
+    let estimate = (args.dry_run == Some(DryRunMode::Estimate)).then(|| {
+        let sizes: Vec<u64> = input_files
+            .iter()
+            .map(|file| file.metadata().map(|metadata| metadata.len()).unwrap_or(0))
+            .collect();
+        let compressible_size = sizes.iter().filter(|size| **size <= args.max_input_size).sum();
+        Estimate::from_results(
+            &compression_results,
+            input_files.len(),
+            sizes.iter().sum(),
+            compressible_size,
+        )
+    });
+
+    if let Some(report) = &report {
+        write_report(
+            report,
+            &compression_results,
+            args.dry_run.is_some(),
+            None,
+            estimate.as_ref(),
+        );
+    }
+    if let Some(path) = &args.html_report {
+        let comparison = args.html_compare.zip(thumbnails.as_ref());
+        if let Err(e) = write_html_report(path, &compression_results, comparison) {
+            eprintln!("Error writing the HTML report: {e}");
+        }
+    }
+    if !report_on_stdout {
         write_recap_message(&compression_results, verbose);
+        // Without sampling the recap already holds the measured totals
+        if let Some(estimate) = estimate.as_ref().filter(|_| verbose > 0 && args.sample.is_some()) {
+            write_estimate_message(estimate);
+        }
     }
-}
 
-struct CompressionStats {
-    total_original_size: u64,
-    total_compressed_size: u64,
-    success: usize,
-    skipped: usize,
-    errors: usize,
+    exit(ExitStatus::from_results(&compression_results, args.fail_on).code());
 }
 
-impl CompressionStats {
-    fn from_results(results: &[CompressionResult]) -> Self {
-        let (total_original_size, total_compressed_size, success, skipped, errors) = results.iter().fold(
-            (0u64, 0u64, 0usize, 0usize, 0usize),
-            |(orig, comp, success, skipped, errors), result| {
-                let (new_success, new_skipped, new_errors) = match result.status {
-                    CompressionStatus::Success => (success + 1, skipped, errors),
-                    CompressionStatus::Skipped => (success, skipped + 1, errors),
-                    CompressionStatus::Error => (success, skipped, errors + 1),
-                };
-                (
-                    orig + result.original_size,
-                    comp + result.compressed_size,
-                    new_success,
-                    new_skipped,
-                    new_errors,
-                )
-            },
-        );
-        Self {
-            total_original_size,
-            total_compressed_size,
-            success,
-            skipped,
-            errors,
-        }
+/// Keeps the files found by the scan, which include the ones left out by --sample. Not set when compressing while
+/// scanning
+#[derive(Default)]
+struct ScannedFiles(Mutex<Option<Vec<PathBuf>>>);
+
+impl CompressionEvents for ScannedFiles {
+    fn scan_finished(&self, input_files: &[PathBuf]) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Some(input_files.to_vec());
     }
+}
 
-    fn savings_bytes(&self) -> i64 {
-        self.total_original_size as i64 - self.total_compressed_size as i64
+/// Prints the error, exiting with the invalid usage code unless it is the help or the version being shown
+fn exit_with_usage_error(error: clap::Error) -> ! {
+    if !error.use_stderr() {
+        error.exit();
     }
+    let _ = error.print();
+    exit(ExitStatus::InvalidUsage.code());
+}
 
-    fn savings_percent(&self) -> f64 {
-        if self.total_original_size > 0 {
-            (self.savings_bytes() as f64 / self.total_original_size as f64) * 100.0
+impl Estimate {
+    /// Extrapolates the savings measured on the compressed files to the size of the input files that can be compressed.
+    /// Files that failed or were skipped are left as they are, so they count as measured bytes without savings, while
+    /// the files over --max-input-size are left out on both sides
+    fn from_results(
+        results: &[CompressionResult],
+        total_files: usize,
+        original_size: u64,
+        compressible_size: u64,
+    ) -> Self {
+        let mut measured_files = 0;
+        let mut errored_files = 0;
+        let (mut measured_size, mut measured_savings) = (0u64, 0i64);
+        for result in results {
+            if result.error_code == Some(ErrorCode::TooLarge) {
+                continue;
+            }
+            measured_size += result.original_size;
+            match result.status {
+                CompressionStatus::Success => {
+                    measured_files += 1;
+                    measured_savings += result.original_size as i64 - result.compressed_size as i64;
+                }
+                CompressionStatus::Skipped => measured_files += 1,
+                CompressionStatus::Error => errored_files += 1,
+            }
+        }
+
+        let projected_savings_bytes = if measured_size > 0 {
+            (compressible_size as f64 * measured_savings as f64 / measured_size as f64).round() as i64
+        } else {
+            0
+        };
+        let projected_size = (original_size as i64 - projected_savings_bytes) as u64;
+        let projected_savings_percent = if original_size > 0 {
+            (projected_savings_bytes as f64 / original_size as f64) * 100.0
         } else {
             0.0
+        };
+
+        Self {
+            measured_files,
+            errored_files,
+            total_files,
+            original_size,
+            compressible_size,
+            projected_size,
+            projected_savings_bytes,
+            projected_savings_percent,
         }
     }
 }
 
-fn build_json_output_string(compression_results: &[CompressionResult], dry_run: bool, error: Option<&str>) -> String {
-    let stats = CompressionStats::from_results(compression_results);
+fn build_json_output_string(
+    compression_results: &[CompressionResult],
+    dry_run: bool,
+    error: Option<&str>,
+    estimate: Option<&Estimate>,
+) -> String {
     let output = JsonOutput {
         version: "1.0.0",
         dry_run,
         error,
         files: compression_results,
-        summary: JsonSummary {
-            total_files: compression_results.len(),
-            success: stats.success,
-            skipped: stats.skipped,
-            errors: stats.errors,
-            original_size: stats.total_original_size,
-            compressed_size: stats.total_compressed_size,
-            savings_bytes: stats.savings_bytes(),
-            savings_percent: stats.savings_percent(),
-        },
+        summary: build_json_summary(compression_results),
+        estimate,
     };
     serde_json::to_string(&output).unwrap_or_else(|e| format!("{{\"error\":\"JSON serialization failed: {e}}}"))
 }
 
-fn write_json_output(compression_results: &[CompressionResult], dry_run: bool, error: Option<&str>) {
-    println!("{}", build_json_output_string(compression_results, dry_run, error));
+fn build_ndjson_summary_string(
+    compression_results: &[CompressionResult],
+    dry_run: bool,
+    error: Option<&str>,
+    estimate: Option<&Estimate>,
+) -> String {
+    let output = NdjsonSummary {
+        version: "1.0.0",
+        dry_run,
+        error,
+        summary: build_json_summary(compression_results),
+        estimate,
+    };
+    serde_json::to_string(&output).unwrap_or_else(|e| format!("{{\"error\":\"JSON serialization failed: {e}}}"))
+}
+
+fn build_json_summary(compression_results: &[CompressionResult]) -> JsonSummary {
+    let stats = CompressionStats::from_results(compression_results);
+    JsonSummary {
+        total_files: compression_results.len(),
+        success: stats.success,
+        skipped: stats.skipped,
+        errors: stats.errors,
+        original_size: stats.total_original_size,
+        compressed_size: stats.total_compressed_size,
+        savings_bytes: stats.savings_bytes(),
+        savings_percent: stats.savings_percent(),
+    }
+}
+
+/// Writes what is left of the report once all the files are done
+fn write_report(
+    report: &Report,
+    compression_results: &[CompressionResult],
+    dry_run: bool,
+    error: Option<&str>,
+    estimate: Option<&Estimate>,
+) {
+    let text = match report.format {
+        ReportFormat::Json => build_json_output_string(compression_results, dry_run, error, estimate),
+        ReportFormat::Ndjson => build_ndjson_summary_string(compression_results, dry_run, error, estimate),
+        ReportFormat::Csv => build_csv_report(compression_results),
+    };
+    report.write(&text);
+}
+
+fn write_error(report: Option<&Report>, report_on_stdout: bool, dry_run: bool, error: &str) {
+    if let Some(report) = report {
+        write_report(report, &[], dry_run, Some(error), None);
+    }
+    // JSON reports on stdout already hold the error, CSV ones have nowhere to put it
+    if !report_on_stdout || report.is_some_and(|report| report.format == ReportFormat::Csv) {
+        eprintln!("{error}");
+    }
 }
 
 fn write_recap_message(compression_results: &[CompressionResult], verbose: u8) {
@@ -213,18 +460,7 @@ fn write_recap_message(compression_results: &[CompressionResult], verbose: u8) {
                 0.0
             };
 
-            let savings_size_abs = savings_size.unsigned_abs();
-            let (formatted_savings_size, formatted_savings_percentage) = if savings_size >= 0 {
-                (
-                    format!("-{}", ByteSize::b(savings_size_abs)).green(),
-                    format!("-{savings_percent:.2}%").green(),
-                )
-            } else {
-                (
-                    format!("+{}", ByteSize::b(savings_size_abs)).red(),
-                    format!("+{:.2}%", -savings_percent).red(),
-                )
-            };
+            let (formatted_savings_size, formatted_savings_percentage) = format_savings(savings_size, savings_percent);
 
             let status_message = match result.status {
                 CompressionStatus::Success => "Success".green(),
@@ -255,20 +491,8 @@ fn write_recap_message(compression_results: &[CompressionResult], verbose: u8) {
     }
 
     if verbose > 0 {
-        let total_saved = stats.savings_bytes();
-        let total_saved_percent = stats.savings_percent();
-        let total_saved_abs = total_saved.unsigned_abs();
-        let (formatted_total_saved_size, formatted_total_saved_percentage) = if total_saved >= 0 {
-            (
-                format!("-{}", ByteSize::b(total_saved_abs)).green(),
-                format!("-{total_saved_percent:.2}%").green(),
-            )
-        } else {
-            (
-                format!("+{}", ByteSize::b(total_saved_abs)).red(),
-                format!("+{:.2}%", -total_saved_percent).red(),
-            )
-        };
+        let (formatted_total_saved_size, formatted_total_saved_percentage) =
+            format_savings(stats.savings_bytes(), stats.savings_percent());
 
         println!(
             "Compressed {} files ({} success, {} skipped, {} errors)\n{} -> {} [{} | {}]",
@@ -284,6 +508,38 @@ fn write_recap_message(compression_results: &[CompressionResult], verbose: u8) {
     }
 }
 
+fn write_estimate_message(estimate: &Estimate) {
+    let (formatted_savings_size, formatted_savings_percentage) =
+        format_savings(estimate.projected_savings_bytes, estimate.projected_savings_percent);
+
+    println!(
+        "Estimated on {} of {} files ({} failed)\n{} -> {} [{} | {}]",
+        estimate.measured_files,
+        estimate.total_files,
+        estimate.errored_files,
+        ByteSize::b(estimate.original_size),
+        ByteSize::b(estimate.projected_size),
+        formatted_savings_size,
+        formatted_savings_percentage
+    );
+}
+
+/// Savings are shown as a reduction, in green, or as an increase, in red
+fn format_savings(savings_size: i64, savings_percent: f64) -> (ColoredString, ColoredString) {
+    let savings_size_abs = savings_size.unsigned_abs();
+    if savings_size >= 0 {
+        (
+            format!("-{}", ByteSize::b(savings_size_abs)).green(),
+            format!("-{savings_percent:.2}%").green(),
+        )
+    } else {
+        (
+            format!("+{}", ByteSize::b(savings_size_abs)).red(),
+            format!("+{:.2}%", -savings_percent).red(),
+        )
+    }
+}
+
 fn get_parallelism_count(requested_threads: u32, available_threads: usize) -> usize {
     match requested_threads {
         0 => available_threads,
@@ -291,6 +547,19 @@ fn get_parallelism_count(requested_threads: u32, available_threads: usize) -> us
     }
 }
 
+/// Receiver of the progress events, as chosen with --progress. The length of the bar is the number of files found so
+/// far
+fn build_progress(mode: ProgressMode, verbose: u8, target: ProgressDrawTarget) -> Box<dyn CompressionEvents> {
+    match mode {
+        ProgressMode::Bar => {
+            let (multi_progress, progress_bar) = setup_progress_bar(0, verbose, target);
+            Box::new(TerminalProgress::new(multi_progress, progress_bar))
+        }
+        ProgressMode::Json => Box::new(JsonEvents::new(Box::new(io::stderr()))),
+        ProgressMode::None => Box::new(SilentEvents),
+    }
+}
+
 fn setup_progress_bar(len: usize, verbose: u8, target: ProgressDrawTarget) -> (MultiProgress, ProgressBar) {
     let multi_progress = MultiProgress::new();
     let progress_bar = multi_progress.add(ProgressBar::new(len as u64));
@@ -311,34 +580,109 @@ fn setup_progress_bar(len: usize, verbose: u8, target: ProgressDrawTarget) -> (M
     (multi_progress, progress_bar)
 }
 
-fn build_compression_options(args: &CommandLineArgs, base_path: &Path) -> CompressionOptions {
-    CompressionOptions {
-        quality: args.compression.quality,
-        lossless: args.compression.lossless,
-        output_folder: args.output_destination.output.clone(),
-        same_folder_as_input: args.output_destination.same_folder_as_input,
-        overwrite_policy: args.overwrite,
-        format: args.format,
-        suffix: args.suffix.clone(),
-        keep_structure: args.keep_structure,
-        width: args.resize.width,
-        height: args.resize.height,
-        long_edge: args.resize.long_edge,
-        short_edge: args.resize.short_edge,
-        max_size: args.compression.max_size,
-        keep_dates: args.keep_dates,
-        exif: args.exif,
-        png_opt_level: args.png_opt_level,
-        jpeg_chroma_subsampling: parse_jpeg_chroma_subsampling(args.jpeg_chroma_subsampling),
-        jpeg_baseline: args.jpeg_baseline,
-        zopfli: args.zopfli,
-        base_path: PathBuf::from(base_path),
-        no_upscale: args.resize.no_upscale,
-        strip_icc: args.strip_icc,
-        min_savings: args.min_savings,
+fn build_scan_options(args: &CommandLineArgs) -> ScanOptions {
+    ScanOptions {
+        recursive: args.recursive,
+        max_depth: args.max_depth,
+        follow_symlinks: args.follow_symlinks,
+        skip_hidden: args.skip_hidden,
+        respect_ignore: args.respect_ignore,
+        check_extension_only: args.check_extension_only,
+        filters: FileFilters {
+            min_file_size: args.min_file_size,
+            max_file_size: args.max_file_size,
+            min_dimensions: args.min_dimensions,
+            max_dimensions: args.max_dimensions,
+            newer_than: args.newer_than,
+        },
+        sort_key: args.sort,
+        sample: args.sample,
     }
 }
 
+/// Every option of the run but the base path, which depends on the files found. The watermark is loaded once here, so
+/// that every file can reuse it
+fn compression_options_builder(args: &CommandLineArgs) -> Result<CompressionOptionsBuilder, clap::Error> {
+    let keep_metadata = resolve_kept_metadata(args);
+    let watermark = match &args.watermark.image {
+        Some(path) => Some(image::open(path).map(|image| image.to_rgba8()).map_err(|e| {
+            CommandLineArgs::command().error(
+                ErrorKind::ValueValidation,
+                format!("Cannot read watermark image '{}': {e}", path.display()),
+            )
+        })?),
+        None => None,
+    };
+
+    Ok(CompressionOptions::builder()
+        .quality(args.compression.quality)
+        .lossless(args.compression.lossless)
+        .output_folder(args.output_destination.output.clone())
+        .same_folder_as_input(args.output_destination.same_folder_as_input)
+        .overwrite_policy(args.overwrite)
+        .format(args.format)
+        .suffix(args.suffix.clone())
+        .keep_structure(args.keep_structure)
+        .width(args.resize.width)
+        .height(args.resize.height)
+        .long_edge(args.resize.long_edge)
+        .short_edge(args.resize.short_edge)
+        .max_size(args.compression.max_size)
+        .keep_dates(args.keep_dates)
+        .exif(args.exif && keep_metadata.is_empty())
+        .keep_metadata(keep_metadata)
+        .strip_tags(args.strip_tags.clone())
+        .set_meta(args.set_meta.clone())
+        .dpi(args.dpi)
+        .png_opt_level(args.png_opt_level)
+        .jpeg_chroma_subsampling(parse_jpeg_chroma_subsampling(args.jpeg_chroma_subsampling))
+        .jpeg_baseline(args.jpeg_baseline)
+        .zopfli(args.zopfli)
+        .no_upscale(args.resize.no_upscale)
+        .strip_icc(args.strip_icc)
+        .min_savings(args.min_savings)
+        .max_input_size(args.max_input_size)
+        .memory_limit(args.memory_limit)
+        .order(args.order)
+        .file_timeout(args.file_timeout)
+        .timeout_fallback(args.timeout_fallback)
+        .auto_orient(args.auto_orient)
+        .convert_to_srgb(args.convert_to_srgb)
+        .grayscale(args.grayscale)
+        .colors(args.colors)
+        .dithering(args.dithering)
+        .watermark(watermark.map(|image| WatermarkOptions {
+            image,
+            position: args.watermark.watermark_position,
+            opacity: args.watermark.watermark_opacity,
+            scale: args.watermark.watermark_scale,
+        }))
+        .srgb_profile(args.srgb_profile)
+        .rotate(args.rotate)
+        .flip(args.flip))
+}
+
+/// Selective metadata handling kicks in with --keep-metadata, or when tags must be stripped from what -e keeps
+fn resolve_kept_metadata(args: &CommandLineArgs) -> Vec<MetadataKind> {
+    let kinds = if !args.keep_metadata.is_empty() {
+        args.keep_metadata.clone()
+    } else if args.exif && !args.strip_tags.is_empty() {
+        vec![
+            MetadataKind::Exif,
+            MetadataKind::Iptc,
+            MetadataKind::Xmp,
+            MetadataKind::Icc,
+        ]
+    } else {
+        return Vec::new();
+    };
+
+    kinds
+        .into_iter()
+        .filter(|kind| !(args.strip_icc && *kind == MetadataKind::Icc))
+        .collect()
+}
+
 fn parse_jpeg_chroma_subsampling(arg: JpegChromaSubsampling) -> ChromaSubsampling {
     match arg {
         JpegChromaSubsampling::ChromaSubsampling444 => ChromaSubsampling::CS444,
@@ -352,10 +696,11 @@ fn parse_jpeg_chroma_subsampling(arg: JpegChromaSubsampling) -> ChromaSubsamplin
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::options::{
-        Compression, JpegChromaSubsampling, OutputDestination, OutputFormat, OverwritePolicy, Resize,
+    use crate::options::{Compression, OutputDestination, Resize, Watermark};
+    use caesiumclt::{
+        CollisionPolicy, FileOrder, OutputFormat, OverwritePolicy, SortKey, SrgbProfile, StripTag, WatermarkPosition,
     };
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
 
     #[test]
     fn test_get_parallelism_count() {
@@ -451,6 +796,8 @@ mod tests {
                 compressed_size: 800,
                 status: CompressionStatus::Success,
                 message: "".to_string(),
+                error_code: None,
+                cause: None,
             },
             CompressionResult {
                 original_path: "test2.jpg".to_string(),
@@ -459,6 +806,8 @@ mod tests {
                 compressed_size: 1500,
                 status: CompressionStatus::Skipped,
                 message: "File skipped".to_string(),
+                error_code: None,
+                cause: None,
             },
             CompressionResult {
                 original_path: "test3.jpg".to_string(),
@@ -467,6 +816,8 @@ mod tests {
                 compressed_size: 0,
                 status: CompressionStatus::Error,
                 message: "Compression failed".to_string(),
+                error_code: None,
+                cause: None,
             },
         ];
 
@@ -492,6 +843,8 @@ mod tests {
             compressed_size: 0,
             status: CompressionStatus::Success,
             message: "".to_string(),
+            error_code: None,
+            cause: None,
         }];
 
         // Should not panic with zero original sizes
@@ -501,6 +854,7 @@ mod tests {
     // Helper function to create test CommandLineArgs
     fn create_test_args() -> CommandLineArgs {
         CommandLineArgs {
+            command: None,
             compression: Compression {
                 quality: Some(80),
                 lossless: false,
@@ -517,29 +871,92 @@ mod tests {
                 output: Some(PathBuf::from("/output")),
                 same_folder_as_input: false,
             },
+            watermark: Watermark {
+                image: None,
+                watermark_position: WatermarkPosition::BottomRight,
+                watermark_opacity: 1.0,
+                watermark_scale: 0.2,
+            },
+            rotate: None,
+            flip: None,
             format: OutputFormat::Jpeg,
             png_opt_level: 5,
             jpeg_chroma_subsampling: JpegChromaSubsampling::ChromaSubsampling420,
             jpeg_baseline: true,
             zopfli: true,
+            convert_to_srgb: false,
+            srgb_profile: SrgbProfile::Strip,
+            grayscale: false,
+            colors: None,
+            dithering: 1.0,
             exif: true,
+            keep_metadata: vec![],
+            strip_tags: vec![],
+            set_meta: vec![],
+            dpi: None,
+            auto_orient: false,
             keep_dates: true,
             suffix: Some("_compressed".to_string()),
             recursive: true,
             keep_structure: true,
-            dry_run: false,
+            max_depth: None,
+            follow_symlinks: false,
+            include_hidden: false,
+            skip_hidden: false,
+            respect_ignore: false,
+            min_file_size: None,
+            max_file_size: None,
+            min_dimensions: None,
+            max_dimensions: None,
+            newer_than: None,
+            dry_run: None,
+            sample: None,
             threads: 4,
+            max_input_size: u64::MAX,
+            memory_limit: None,
+            sort: SortKey::Path,
+            order: FileOrder::SizeDesc,
+            on_collision: CollisionPolicy::Error,
+            file_timeout: None,
+            timeout_fallback: false,
             overwrite: OverwritePolicy::All,
             min_savings: None,
             quiet: false,
             verbose: 2,
+            progress: ProgressMode::Bar,
             json: false,
+            report: None,
+            report_file: None,
+            html_report: None,
+            html_compare: None,
+            fail_on: FailOn::Error,
             files: vec!["test1.jpg".to_string(), "test2.png".to_string()],
             strip_icc: false,
             check_extension_only: false,
         }
     }
 
+    fn build_compression_options(args: &CommandLineArgs, base_path: &Path) -> CompressionOptions {
+        compression_options_builder(args)
+            .unwrap()
+            .base_path(PathBuf::from(base_path))
+            .build()
+    }
+
+    // Result of a compressed file, shared by the report tests
+    pub(crate) fn result(original_path: &str, status: CompressionStatus) -> CompressionResult {
+        CompressionResult {
+            original_path: original_path.to_string(),
+            output_path: "out/a.jpg".to_string(),
+            original_size: 1000,
+            compressed_size: 600,
+            status,
+            message: String::new(),
+            error_code: None,
+            cause: None,
+        }
+    }
+
     #[test]
     fn test_build_compression_options_with_defaults() {
         let mut args = create_test_args();
@@ -572,73 +989,6 @@ mod tests {
         assert!(options.no_upscale);
     }
 
-    #[test]
-    fn test_compression_stats_from_results() {
-        let results = vec![
-            CompressionResult {
-                original_path: "a.jpg".to_string(),
-                output_path: "a_out.jpg".to_string(),
-                original_size: 1000,
-                compressed_size: 800,
-                status: CompressionStatus::Success,
-                message: "".to_string(),
-            },
-            CompressionResult {
-                original_path: "b.jpg".to_string(),
-                output_path: "b_out.jpg".to_string(),
-                original_size: 2000,
-                compressed_size: 2000,
-                status: CompressionStatus::Skipped,
-                message: "".to_string(),
-            },
-            CompressionResult {
-                original_path: "c.jpg".to_string(),
-                output_path: "c_out.jpg".to_string(),
-                original_size: 500,
-                compressed_size: 0,
-                status: CompressionStatus::Error,
-                message: "".to_string(),
-            },
-        ];
-
-        let stats = CompressionStats::from_results(&results);
-        assert_eq!(stats.success, 1);
-        assert_eq!(stats.skipped, 1);
-        assert_eq!(stats.errors, 1);
-        assert_eq!(stats.total_original_size, 3500);
-        assert_eq!(stats.total_compressed_size, 2800);
-        assert_eq!(stats.savings_bytes(), 700);
-        assert!((stats.savings_percent() - 20.0).abs() < 0.01);
-    }
-
-    #[test]
-    fn test_compression_stats_empty() {
-        let stats = CompressionStats::from_results(&[]);
-        assert_eq!(stats.success, 0);
-        assert_eq!(stats.skipped, 0);
-        assert_eq!(stats.errors, 0);
-        assert_eq!(stats.total_original_size, 0);
-        assert_eq!(stats.total_compressed_size, 0);
-        assert_eq!(stats.savings_bytes(), 0);
-        assert_eq!(stats.savings_percent(), 0.0);
-    }
-
-    #[test]
-    fn test_compression_stats_size_increase() {
-        let results = vec![CompressionResult {
-            original_path: "a.jpg".to_string(),
-            output_path: "a_out.jpg".to_string(),
-            original_size: 800,
-            compressed_size: 1000,
-            status: CompressionStatus::Success,
-            message: "".to_string(),
-        }];
-
-        let stats = CompressionStats::from_results(&results);
-        assert_eq!(stats.savings_bytes(), -200);
-        assert!(stats.savings_percent() < 0.0);
-    }
-
     #[test]
     fn test_build_json_output_success() {
         let results = vec![CompressionResult {
@@ -648,9 +998,11 @@ mod tests {
             compressed_size: 600,
             status: CompressionStatus::Success,
             message: "".to_string(),
+            error_code: None,
+            cause: None,
         }];
 
-        let json = build_json_output_string(&results, false, None);
+        let json = build_json_output_string(&results, false, None, None);
         let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
 
         assert_eq!(parsed["version"], "1.0.0");
@@ -671,7 +1023,7 @@ mod tests {
 
     #[test]
     fn test_build_json_output_with_error() {
-        let json = build_json_output_string(&[], false, Some("No files to compress"));
+        let json = build_json_output_string(&[], false, Some("No files to compress"), None);
         let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
 
         assert_eq!(parsed["error"], "No files to compress");
@@ -681,7 +1033,7 @@ mod tests {
 
     #[test]
     fn test_build_json_output_dry_run() {
-        let json = build_json_output_string(&[], true, None);
+        let json = build_json_output_string(&[], true, None, None);
         let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
         assert_eq!(parsed["dry_run"], true);
     }
@@ -696,6 +1048,8 @@ mod tests {
                 compressed_size: 80,
                 status: CompressionStatus::Success,
                 message: "".to_string(),
+                error_code: None,
+                cause: None,
             },
             CompressionResult {
                 original_path: "b.jpg".to_string(),
@@ -704,6 +1058,8 @@ mod tests {
                 compressed_size: 100,
                 status: CompressionStatus::Skipped,
                 message: "min savings not met".to_string(),
+                error_code: None,
+                cause: None,
             },
             CompressionResult {
                 original_path: "c.jpg".to_string(),
@@ -712,10 +1068,12 @@ mod tests {
                 compressed_size: 0,
                 status: CompressionStatus::Error,
                 message: "read error".to_string(),
+                error_code: None,
+                cause: None,
             },
         ];
 
-        let json = build_json_output_string(&results, false, None);
+        let json = build_json_output_string(&results, false, None, None);
         let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
 
         assert_eq!(parsed["files"][0]["status"], "success");
@@ -726,6 +1084,92 @@ mod tests {
         assert_eq!(parsed["summary"]["errors"], 1);
     }
 
+    #[test]
+    fn test_build_ndjson_summary() {
+        let results = vec![CompressionResult {
+            original_path: "input.jpg".to_string(),
+            output_path: "output.jpg".to_string(),
+            original_size: 1000,
+            compressed_size: 600,
+            status: CompressionStatus::Success,
+            message: "".to_string(),
+            error_code: None,
+            cause: None,
+        }];
+
+        let json = build_ndjson_summary_string(&results, false, None, None);
+        assert!(!json.contains('\n'));
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(parsed.get("files").is_none());
+        assert_eq!(parsed["summary"]["total_files"], 1);
+        assert_eq!(parsed["summary"]["savings_bytes"], 400);
+        assert_eq!(parsed["dry_run"], false);
+    }
+
+    #[test]
+    fn test_estimate_from_results() {
+        let measured = |original_size, compressed_size, status, error_code| CompressionResult {
+            original_size,
+            compressed_size,
+            error_code,
+            ..result("a.jpg", status)
+        };
+        let results = vec![
+            measured(1000, 400, CompressionStatus::Success, None),
+            measured(
+                1000,
+                1000,
+                CompressionStatus::Skipped,
+                Some(ErrorCode::InsufficientSavings),
+            ),
+            measured(1000, 0, CompressionStatus::Error, Some(ErrorCode::DecodeFailed)),
+            measured(5000, 0, CompressionStatus::Skipped, Some(ErrorCode::TooLarge)),
+        ];
+
+        // 600 bytes saved out of 3000 measured, extrapolated to the files within --max-input-size only
+        let estimate = Estimate::from_results(&results, 10, 25000, 20000);
+        assert_eq!(estimate.measured_files, 2);
+        assert_eq!(estimate.errored_files, 1);
+        assert_eq!(estimate.total_files, 10);
+        assert_eq!(estimate.compressible_size, 20000);
+        assert_eq!(estimate.projected_savings_bytes, 4000);
+        assert_eq!(estimate.projected_size, 21000);
+        assert!((estimate.projected_savings_percent - 16.0).abs() < 0.01);
+
+        let json = build_json_output_string(&results, true, None, Some(&estimate));
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["estimate"]["projected_size"], 21000);
+        assert_eq!(parsed["estimate"]["measured_files"], 2);
+        assert_eq!(parsed["estimate"]["errored_files"], 1);
+
+        let json = build_json_output_string(&results, true, None, None);
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(parsed.get("estimate").is_none());
+
+        let estimate = Estimate::from_results(&[], 0, 0, 0);
+        assert_eq!(estimate.projected_size, 0);
+        assert_eq!(estimate.projected_savings_percent, 0.0);
+    }
+
+    #[test]
+    fn test_exit_status_from_results() {
+        use CompressionStatus::{Error, Skipped, Success};
+
+        let status = |statuses: Vec<CompressionStatus>, fail_on| {
+            let results: Vec<CompressionResult> = statuses.into_iter().map(|status| result("a.jpg", status)).collect();
+            ExitStatus::from_results(&results, fail_on)
+        };
+
+        assert_eq!(status(vec![], FailOn::Error), ExitStatus::NoFiles);
+        assert_eq!(status(vec![Success, Skipped], FailOn::Error), ExitStatus::Success);
+        assert_eq!(status(vec![Success, Skipped], FailOn::Skipped), ExitStatus::SomeFailed);
+        assert_eq!(status(vec![Success, Error], FailOn::Error), ExitStatus::SomeFailed);
+        assert_eq!(status(vec![Error, Error], FailOn::Error), ExitStatus::AllFailed);
+        assert_eq!(status(vec![Skipped, Error], FailOn::Error), ExitStatus::SomeFailed);
+        assert_eq!(status(vec![Skipped, Error], FailOn::Skipped), ExitStatus::AllFailed);
+        assert_eq!(ExitStatus::InvalidUsage.code(), 4);
+    }
+
     #[test]
     fn test_build_compression_options_edge_cases() {
         let mut args = create_test_args();
@@ -738,4 +1182,43 @@ mod tests {
         assert!(options.jpeg_chroma_subsampling == ChromaSubsampling::Auto);
         assert_eq!(options.base_path, PathBuf::from(""));
     }
+
+    #[test]
+    fn test_build_compression_options_selective_metadata() {
+        let mut args = create_test_args();
+        let options = build_compression_options(&args, Path::new(""));
+        assert!(options.exif);
+        assert!(options.keep_metadata.is_empty());
+
+        args.strip_tags = vec![StripTag::Gps];
+        args.strip_icc = true;
+        let options = build_compression_options(&args, Path::new(""));
+        assert!(!options.exif);
+        assert_eq!(
+            options.keep_metadata,
+            vec![MetadataKind::Exif, MetadataKind::Iptc, MetadataKind::Xmp]
+        );
+        assert_eq!(options.strip_tags, vec![StripTag::Gps]);
+
+        args.exif = false;
+        args.strip_icc = false;
+        args.keep_metadata = vec![MetadataKind::Exif, MetadataKind::Icc];
+        let options = build_compression_options(&args, Path::new(""));
+        assert!(!options.exif);
+        assert_eq!(options.keep_metadata, vec![MetadataKind::Exif, MetadataKind::Icc]);
+    }
+
+    #[test]
+    fn test_compression_options_builder_watermark() {
+        let mut args = create_test_args();
+        args.watermark.image = Some(PathBuf::from("samples/p0.png"));
+        let options = build_compression_options(&args, Path::new(""));
+        let watermark = options.watermark.unwrap();
+        assert!(watermark.image.width() > 0);
+        assert_eq!(watermark.position, WatermarkPosition::BottomRight);
+
+        args.watermark.image = Some(PathBuf::from("samples/missing.png"));
+        let error = compression_options_builder(&args).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::ValueValidation);
+    }
 }
@@ -0,0 +1,1215 @@
+use crate::compressor::CompressionOptions;
+use clap::ValueEnum;
+use image::metadata::Orientation;
+use image::{ImageDecoder, ImageReader};
+use img_parts::jpeg::{markers, Jpeg, JpegSegment};
+use img_parts::png::{Png, PngChunk};
+use img_parts::riff::{RiffChunk, RiffContent};
+use img_parts::webp::WebP;
+use img_parts::{Bytes, ImageEXIF, ImageICC};
+use std::error::Error;
+use std::io::Cursor;
+use std::path::Path;
+
+const JPEG_XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const JPEG_IPTC_PREFIX: &[u8] = b"Photoshop 3.0\0";
+const JPEG_JFIF_PREFIX: &[u8] = b"JFIF\0";
+const PNG_EXIF_CHUNK: [u8; 4] = *b"eXIf";
+const PNG_TEXT_CHUNK: [u8; 4] = *b"iTXt";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+const PNG_DENSITY_CHUNK: [u8; 4] = *b"pHYs";
+const WEBP_VP8X_CHUNK: [u8; 4] = *b"VP8X";
+const WEBP_VP8L_CHUNK: [u8; 4] = *b"VP8L";
+const WEBP_ALPHA_CHUNK: [u8; 4] = *b"ALPH";
+const WEBP_ICC_CHUNK: [u8; 4] = *b"ICCP";
+const WEBP_EXIF_CHUNK: [u8; 4] = *b"EXIF";
+const WEBP_XMP_CHUNK: [u8; 4] = *b"XMP ";
+
+const EXIF_GPS_IFD_TAG: u16 = 0x8825;
+const EXIF_IFD_TAG: u16 = 0x8769;
+const EXIF_MAKER_NOTE_TAG: u16 = 0x927c;
+const EXIF_BODY_SERIAL_NUMBER_TAG: u16 = 0xa431;
+const EXIF_LENS_SERIAL_NUMBER_TAG: u16 = 0xa435;
+const EXIF_CAMERA_SERIAL_NUMBER_TAG: u16 = 0xc62f;
+const EXIF_IMAGE_DESCRIPTION_TAG: u16 = 0x010e;
+const EXIF_SOFTWARE_TAG: u16 = 0x0131;
+const EXIF_ARTIST_TAG: u16 = 0x013b;
+const EXIF_COPYRIGHT_TAG: u16 = 0x8298;
+const EXIF_X_RESOLUTION_TAG: u16 = 0x011a;
+const EXIF_Y_RESOLUTION_TAG: u16 = 0x011b;
+const EXIF_RESOLUTION_UNIT_TAG: u16 = 0x0128;
+const EXIF_ASCII_TYPE: u16 = 2;
+const EXIF_SHORT_TYPE: u16 = 3;
+const EXIF_RATIONAL_TYPE: u16 = 5;
+const EXIF_INCH_UNIT: u16 = 2;
+const INCHES_PER_METER: f64 = 39.3701;
+
+const XMP_DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+const XMP_BASIC_NAMESPACE: &str = "http://ns.adobe.com/xap/1.0/";
+const XMP_RDF_END: &str = "</rdf:RDF>";
+const XMP_SERIAL_NUMBER_PROPERTIES: [&str; 4] = [
+    "SerialNumber",
+    "BodySerialNumber",
+    "LensSerialNumber",
+    "CameraSerialNumber",
+];
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum MetadataKind {
+    Exif,
+    Iptc,
+    Xmp,
+    Icc,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum StripTag {
+    /// GPS coordinates and the rest of the GPS IFD
+    Gps,
+    /// Camera body and lens serial numbers
+    Serial,
+    /// Vendor specific maker notes
+    MakerNotes,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MetadataField {
+    Artist,
+    Copyright,
+    ImageDescription,
+    Software,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MetadataValue {
+    pub field: MetadataField,
+    /// May contain `{name}`, `{ext}` and `{dir}` placeholders, filled from the input file
+    pub template: String,
+}
+
+/// Metadata blocks in their raw form: a TIFF structure for EXIF, the XMP packet and the IPTC resource block
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImageMetadata {
+    pub exif: Option<Vec<u8>>,
+    pub icc_profile: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+    pub iptc: Option<Vec<u8>>,
+}
+
+impl ImageMetadata {
+    pub fn read(buffer: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut decoder = ImageReader::new(Cursor::new(buffer))
+            .with_guessed_format()?
+            .into_decoder()?;
+
+        Ok(Self {
+            exif: decoder.exif_metadata().unwrap_or(None),
+            icc_profile: decoder.icc_profile().unwrap_or(None),
+            xmp: decoder.xmp_metadata().unwrap_or(None),
+            // Other formats store IPTC as text with no standard layout, so only JPEG blocks can be carried over
+            iptc: if infer::image::is_jpeg(buffer) {
+                decoder.iptc_metadata().unwrap_or(None)
+            } else {
+                None
+            },
+        })
+    }
+
+    pub fn retain(&mut self, kinds: &[MetadataKind]) {
+        if !kinds.contains(&MetadataKind::Exif) {
+            self.exif = None;
+        }
+        if !kinds.contains(&MetadataKind::Icc) {
+            self.icc_profile = None;
+        }
+        if !kinds.contains(&MetadataKind::Xmp) {
+            self.xmp = None;
+        }
+        if !kinds.contains(&MetadataKind::Iptc) {
+            self.iptc = None;
+        }
+    }
+}
+
+pub fn keeps_exif(options: &CompressionOptions) -> bool {
+    options.exif || options.keep_metadata.contains(&MetadataKind::Exif)
+}
+
+/// Collects the metadata to write back after compression when only some of it must be kept.
+/// Returns `None` if metadata is either kept as a whole or dropped as a whole, which the compressor does on its own.
+pub fn read_kept_metadata(buffer: &[u8], options: &CompressionOptions) -> Option<ImageMetadata> {
+    if options.keep_metadata.is_empty() {
+        return None;
+    }
+
+    let mut metadata = ImageMetadata::read(buffer).unwrap_or_default();
+    metadata.retain(&options.keep_metadata);
+
+    if let Some(exif) = metadata.exif.as_mut() {
+        if options.auto_orient {
+            let _ = Orientation::remove_from_exif_chunk(exif);
+        }
+        // Better to lose the whole block than to leak a tag that was asked to be removed
+        if strip_exif_tags(exif, &options.strip_tags).is_none() {
+            metadata.exif = None;
+        }
+    }
+    if let Some(xmp) = metadata.xmp.as_deref() {
+        metadata.xmp = strip_xmp_tags(xmp, &options.strip_tags);
+    }
+
+    Some(metadata)
+}
+
+/// Writes the metadata into the JPEG, PNG or WebP image in `buffer`, replacing the existing blocks and removing
+/// the ones missing from `metadata`.
+/// Other formats are returned untouched.
+pub fn write_metadata(buffer: Vec<u8>, metadata: &ImageMetadata) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut output = Vec::new();
+    if infer::image::is_jpeg(&buffer) {
+        let mut jpeg = Jpeg::from_bytes(Bytes::from(buffer))?;
+        write_jpeg_metadata(&mut jpeg, metadata);
+        jpeg.encoder().write_to(&mut output)?;
+    } else if infer::image::is_png(&buffer) {
+        let mut png = Png::from_bytes(Bytes::from(buffer))?;
+        write_png_metadata(&mut png, metadata);
+        png.encoder().write_to(&mut output)?;
+    } else if infer::image::is_webp(&buffer) {
+        let mut webp = WebP::from_bytes(Bytes::from(buffer))?;
+        write_webp_metadata(&mut webp, metadata);
+        webp.encoder().write_to(&mut output)?;
+    } else {
+        return Ok(buffer);
+    }
+
+    Ok(output)
+}
+
+fn write_jpeg_metadata(jpeg: &mut Jpeg, metadata: &ImageMetadata) {
+    jpeg.set_exif(metadata.exif.clone().map(Bytes::from));
+    jpeg.set_icc_profile(metadata.icc_profile.clone().map(Bytes::from));
+    jpeg.segments_mut().retain(|segment| {
+        !(segment.marker() == markers::APP1 && segment.contents().starts_with(JPEG_XMP_PREFIX)
+            || segment.marker() == markers::APP13 && segment.contents().starts_with(JPEG_IPTC_PREFIX))
+    });
+
+    // Application segments must come before the image data, right after the existing ones
+    let position = jpeg
+        .segments()
+        .iter()
+        .position(|segment| !(markers::APP0..=markers::APP15).contains(&segment.marker()))
+        .unwrap_or(0);
+    let segments = [
+        (markers::APP1, JPEG_XMP_PREFIX, &metadata.xmp),
+        (markers::APP13, JPEG_IPTC_PREFIX, &metadata.iptc),
+    ];
+    for (marker, prefix, data) in segments.into_iter().rev() {
+        if let Some(data) = data {
+            let contents = [prefix, data.as_slice()].concat();
+            jpeg.segments_mut()
+                .insert(position, JpegSegment::new_with_contents(marker, Bytes::from(contents)));
+        }
+    }
+}
+
+fn write_png_metadata(png: &mut Png, metadata: &ImageMetadata) {
+    png.set_icc_profile(metadata.icc_profile.clone().map(Bytes::from));
+    png.remove_chunks_by_type(PNG_EXIF_CHUNK);
+    png.chunks_mut()
+        .retain(|chunk| !(chunk.kind() == PNG_TEXT_CHUNK && chunk.contents().starts_with(PNG_XMP_KEYWORD)));
+
+    // Placed ahead of the image data, otherwise most decoders never get to read them
+    if let Some(xmp) = &metadata.xmp {
+        // Keyword, then uncompressed text with empty language and translated keyword
+        let contents = [PNG_XMP_KEYWORD, &[0, 0, 0, 0, 0], xmp.as_slice()].concat();
+        png.chunks_mut()
+            .insert(1, PngChunk::new(PNG_TEXT_CHUNK, Bytes::from(contents)));
+    }
+    if let Some(exif) = &metadata.exif {
+        png.chunks_mut()
+            .insert(1, PngChunk::new(PNG_EXIF_CHUNK, Bytes::from(exif.clone())));
+    }
+}
+
+fn write_webp_metadata(webp: &mut WebP, metadata: &ImageMetadata) {
+    webp.set_icc_profile(metadata.icc_profile.clone().map(Bytes::from));
+    webp.set_exif(metadata.exif.clone().map(Bytes::from));
+    webp.remove_chunks_by_id(WEBP_XMP_CHUNK);
+    if let Some(xmp) = &metadata.xmp {
+        webp.chunks_mut().push(RiffChunk::new(
+            WEBP_XMP_CHUNK,
+            RiffContent::Data(Bytes::from(xmp.clone())),
+        ));
+    }
+    update_webp_flags(webp);
+}
+
+/// img-parts leaves the VP8X flags as they are when it does not have to create the chunk, and never creates it
+/// for XMP alone, so the extended header is fixed up here to match the chunks actually present
+fn update_webp_flags(webp: &mut WebP) {
+    let current = webp
+        .chunk_by_id(WEBP_VP8X_CHUNK)
+        .and_then(|chunk| chunk.content().data())
+        .filter(|data| data.len() >= 10)
+        .map(|data| data.to_vec());
+
+    let mut header = match current {
+        Some(header) => header,
+        None => {
+            let has_metadata = [WEBP_ICC_CHUNK, WEBP_EXIF_CHUNK, WEBP_XMP_CHUNK]
+                .iter()
+                .any(|id| webp.has_chunk(*id));
+            let Some((width, height)) = webp.dimensions().filter(|_| has_metadata) else {
+                return;
+            };
+            let mut header = vec![0; 4];
+            header.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            header.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+            if has_webp_alpha(webp) {
+                header[0] |= 0x10;
+            }
+            header
+        }
+    };
+
+    for (id, flag) in [(WEBP_ICC_CHUNK, 0x20), (WEBP_EXIF_CHUNK, 0x08), (WEBP_XMP_CHUNK, 0x04)] {
+        if webp.has_chunk(id) {
+            header[0] |= flag;
+        } else {
+            header[0] &= !flag;
+        }
+    }
+
+    webp.remove_chunks_by_id(WEBP_VP8X_CHUNK);
+    webp.chunks_mut().insert(
+        0,
+        RiffChunk::new(WEBP_VP8X_CHUNK, RiffContent::Data(Bytes::from(header))),
+    );
+}
+
+fn has_webp_alpha(webp: &WebP) -> bool {
+    if webp.has_chunk(WEBP_ALPHA_CHUNK) {
+        return true;
+    }
+
+    // The lossless bitstream header holds an "alpha is used" bit right after the image size
+    webp.chunk_by_id(WEBP_VP8L_CHUNK)
+        .and_then(|chunk| chunk.content().data())
+        .and_then(|data| data.get(4))
+        .is_some_and(|byte| byte & 0x10 != 0)
+}
+
+/// Replaces the ICC profile of the JPEG, PNG or WebP image in `buffer`, keeping the rest of its metadata
+pub fn set_icc_profile(buffer: Vec<u8>, icc_profile: Option<Vec<u8>>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut metadata = ImageMetadata::read(&buffer).unwrap_or_default();
+    metadata.icc_profile = icc_profile;
+
+    write_metadata(buffer, &metadata)
+}
+
+/// Sets the requested fields in both the EXIF and the XMP data of the JPEG, PNG or WebP image in `buffer`,
+/// keeping the rest of its metadata. Other formats are returned untouched.
+pub fn set_metadata_fields(
+    buffer: Vec<u8>,
+    values: &[MetadataValue],
+    input_file: &Path,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if values.is_empty()
+        || !(infer::image::is_jpeg(&buffer) || infer::image::is_png(&buffer) || infer::image::is_webp(&buffer))
+    {
+        return Ok(buffer);
+    }
+
+    // The last value given for a field wins
+    let mut fields: Vec<(MetadataField, String)> = Vec::new();
+    for value in values {
+        fields.retain(|(field, _)| *field != value.field);
+        fields.push((value.field, render_template(&value.template, input_file)));
+    }
+
+    let mut metadata = ImageMetadata::read(&buffer).unwrap_or_default();
+    // A malformed EXIF block cannot be extended, so it gets replaced by one holding only the new fields
+    metadata.exif = metadata
+        .exif
+        .as_deref()
+        .and_then(|exif| set_exif_fields(exif, &fields))
+        .or_else(|| set_exif_fields(&EMPTY_EXIF, &fields));
+    metadata.xmp = Some(set_xmp_fields(metadata.xmp.as_deref(), &fields));
+
+    write_metadata(buffer, &metadata)
+}
+
+/// Fills the `{name}`, `{ext}` and `{dir}` placeholders with the input file name, extension and parent folder name
+fn render_template(template: &str, input_file: &Path) -> String {
+    let name = input_file.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = input_file.extension().unwrap_or_default().to_string_lossy();
+    let dir = input_file
+        .parent()
+        .and_then(|parent| parent.file_name())
+        .unwrap_or_default()
+        .to_string_lossy();
+
+    template
+        .replace("{name}", &name)
+        .replace("{ext}", &ext)
+        .replace("{dir}", &dir)
+}
+
+fn exif_tag(field: MetadataField) -> u16 {
+    match field {
+        MetadataField::Artist => EXIF_ARTIST_TAG,
+        MetadataField::Copyright => EXIF_COPYRIGHT_TAG,
+        MetadataField::ImageDescription => EXIF_IMAGE_DESCRIPTION_TAG,
+        MetadataField::Software => EXIF_SOFTWARE_TAG,
+    }
+}
+
+/// Big-endian TIFF header followed by an empty IFD0
+const EMPTY_EXIF: [u8; 14] = [
+    0x4d, 0x4d, 0x00, 0x2a, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+fn set_exif_fields(exif: &[u8], fields: &[(MetadataField, String)]) -> Option<Vec<u8>> {
+    let entries: Vec<(u16, IfdValue)> = fields
+        .iter()
+        .map(|(field, value)| (exif_tag(*field), IfdValue::Ascii(value.clone())))
+        .collect();
+
+    set_ifd0_entries(exif, &entries)
+}
+
+/// Sets the pixel density of the image in `buffer`, in dots per inch: JFIF and EXIF for JPEG, pHYs and EXIF for PNG,
+/// EXIF for WebP and the image directory for TIFF. Other formats are returned untouched.
+pub fn set_density(buffer: Vec<u8>, dpi: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+    let entries = [
+        (EXIF_X_RESOLUTION_TAG, IfdValue::Rational(dpi, 1)),
+        (EXIF_Y_RESOLUTION_TAG, IfdValue::Rational(dpi, 1)),
+        (EXIF_RESOLUTION_UNIT_TAG, IfdValue::Short(EXIF_INCH_UNIT)),
+    ];
+
+    if infer::image::is_tiff(&buffer) {
+        // A TIFF file has the same layout as an EXIF block, with the density in its first directory
+        return Ok(set_ifd0_entries(&buffer, &entries).ok_or("Malformed TIFF file")?);
+    }
+    if !(infer::image::is_jpeg(&buffer) || infer::image::is_png(&buffer) || infer::image::is_webp(&buffer)) {
+        return Ok(buffer);
+    }
+
+    let mut metadata = ImageMetadata::read(&buffer).unwrap_or_default();
+    metadata.exif = match metadata.exif {
+        Some(exif) => Some(set_ifd0_entries(&exif, &entries).unwrap_or(exif)),
+        // WebP has no density field of its own, so the EXIF block is created
+        None if infer::image::is_webp(&buffer) => set_ifd0_entries(&EMPTY_EXIF, &entries),
+        None => None,
+    };
+    let buffer = write_metadata(buffer, &metadata)?;
+
+    let mut output = Vec::new();
+    if infer::image::is_jpeg(&buffer) {
+        let mut jpeg = Jpeg::from_bytes(Bytes::from(buffer))?;
+        set_jfif_density(&mut jpeg, u16::try_from(dpi)?);
+        jpeg.encoder().write_to(&mut output)?;
+    } else if infer::image::is_png(&buffer) {
+        let mut png = Png::from_bytes(Bytes::from(buffer))?;
+        let pixels_per_meter = (dpi as f64 * INCHES_PER_METER).round() as u32;
+        // Horizontal and vertical pixels per unit, then the unit, 1 being the meter
+        let contents = [
+            &pixels_per_meter.to_be_bytes()[..],
+            &pixels_per_meter.to_be_bytes(),
+            &[1],
+        ]
+        .concat();
+        png.remove_chunks_by_type(PNG_DENSITY_CHUNK);
+        png.chunks_mut()
+            .insert(1, PngChunk::new(PNG_DENSITY_CHUNK, Bytes::from(contents)));
+        png.encoder().write_to(&mut output)?;
+    } else {
+        return Ok(buffer);
+    }
+
+    Ok(output)
+}
+
+/// Updates the density of the JFIF header, adding one if missing
+fn set_jfif_density(jpeg: &mut Jpeg, dpi: u16) {
+    let segments = jpeg.segments_mut();
+    let existing = segments.iter().position(|segment| {
+        segment.marker() == markers::APP0
+            && segment.contents().starts_with(JPEG_JFIF_PREFIX)
+            && segment.contents().len() >= 14
+    });
+
+    // Identifier and version, then the unit (1 being the inch), the densities and the thumbnail
+    let (header, thumbnail) = match existing {
+        Some(index) => {
+            let contents = segments[index].contents();
+            (contents[..7].to_vec(), contents[12..].to_vec())
+        }
+        None => ([JPEG_JFIF_PREFIX, &[1, 1]].concat(), vec![0, 0]),
+    };
+    let mut contents = header;
+    contents.push(1);
+    contents.extend(dpi.to_be_bytes());
+    contents.extend(dpi.to_be_bytes());
+    contents.extend(thumbnail);
+
+    let segment = JpegSegment::new_with_contents(markers::APP0, Bytes::from(contents));
+    match existing {
+        Some(index) => segments[index] = segment,
+        None => segments.insert(0, segment),
+    }
+}
+
+/// Value of an IFD entry, along with its type
+enum IfdValue {
+    Ascii(String),
+    Short(u16),
+    Rational(u32, u32),
+}
+
+impl IfdValue {
+    /// Type, count and encoded data of the value
+    fn encode(&self, little_endian: bool) -> (u16, usize, Vec<u8>) {
+        match self {
+            IfdValue::Ascii(value) => {
+                let mut data = value.as_bytes().to_vec();
+                data.push(0);
+                (EXIF_ASCII_TYPE, data.len(), data)
+            }
+            IfdValue::Short(value) => (EXIF_SHORT_TYPE, 1, encode_u16(*value, little_endian).to_vec()),
+            IfdValue::Rational(numerator, denominator) => (
+                EXIF_RATIONAL_TYPE,
+                1,
+                [
+                    encode_u32(*numerator, little_endian),
+                    encode_u32(*denominator, little_endian),
+                ]
+                .concat(),
+            ),
+        }
+    }
+}
+
+/// Returns a copy of the TIFF structure with the entries set in a new IFD0 appended at the end.
+/// Offsets are absolute, so the values of the existing entries and the other IFDs stay where they are.
+fn set_ifd0_entries(tiff: &[u8], values: &[(u16, IfdValue)]) -> Option<Vec<u8>> {
+    let mut output = tiff.to_vec();
+    let tiff = Tiff::new(&mut output)?;
+    let little_endian = tiff.little_endian;
+    let ifd0 = tiff.read_u32(4)? as usize;
+    let count = tiff.read_u16(ifd0)? as usize;
+    let next_ifd = tiff.read_u32(ifd0 + 2 + count * IFD_ENTRY_SIZE)?;
+
+    let replaced_tags: Vec<u16> = values.iter().map(|(tag, _)| *tag).collect();
+    let mut entries: Vec<(u16, Vec<u8>)> = (0..count)
+        .map(|index| {
+            let entry = ifd0 + 2 + index * IFD_ENTRY_SIZE;
+            Some((
+                tiff.read_u16(entry)?,
+                tiff.data.get(entry..entry + IFD_ENTRY_SIZE)?.to_vec(),
+            ))
+        })
+        .collect::<Option<_>>()?;
+    entries.retain(|(tag, _)| !replaced_tags.contains(tag));
+
+    if output.len() % 2 == 1 {
+        output.push(0);
+    }
+    let new_ifd0 = output.len();
+    let ifd_size = 2 + (entries.len() + values.len()) * IFD_ENTRY_SIZE + 4;
+    let mut data_area = Vec::new();
+
+    for (tag, value) in values {
+        let (kind, count, mut data) = value.encode(little_endian);
+
+        let mut entry = Vec::with_capacity(IFD_ENTRY_SIZE);
+        entry.extend(encode_u16(*tag, little_endian));
+        entry.extend(encode_u16(kind, little_endian));
+        entry.extend(encode_u32(u32::try_from(count).ok()?, little_endian));
+        if data.len() <= 4 {
+            data.resize(4, 0);
+            entry.extend(data);
+        } else {
+            let offset = new_ifd0 + ifd_size + data_area.len();
+            entry.extend(encode_u32(u32::try_from(offset).ok()?, little_endian));
+            data_area.extend(data);
+            if data_area.len() % 2 == 1 {
+                data_area.push(0);
+            }
+        }
+        entries.push((*tag, entry));
+    }
+    entries.sort_by_key(|(tag, _)| *tag);
+
+    output.extend(encode_u16(u16::try_from(entries.len()).ok()?, little_endian));
+    for (_, entry) in entries {
+        output.extend(entry);
+    }
+    output.extend(encode_u32(next_ifd, little_endian));
+    output.extend(data_area);
+    output[4..8].copy_from_slice(&encode_u32(u32::try_from(new_ifd0).ok()?, little_endian));
+
+    Some(output)
+}
+
+fn encode_u16(value: u16, little_endian: bool) -> [u8; 2] {
+    if little_endian {
+        value.to_le_bytes()
+    } else {
+        value.to_be_bytes()
+    }
+}
+
+fn encode_u32(value: u32, little_endian: bool) -> [u8; 4] {
+    if little_endian {
+        value.to_le_bytes()
+    } else {
+        value.to_be_bytes()
+    }
+}
+
+/// XMP property holding the field and its serialized value
+fn xmp_property(field: MetadataField, value: &str) -> (&'static str, String) {
+    let value = escape_xml(value);
+    match field {
+        MetadataField::Artist => ("dc:creator", format!("<rdf:Seq><rdf:li>{value}</rdf:li></rdf:Seq>")),
+        MetadataField::Copyright => (
+            "dc:rights",
+            format!("<rdf:Alt><rdf:li xml:lang=\"x-default\">{value}</rdf:li></rdf:Alt>"),
+        ),
+        MetadataField::ImageDescription => (
+            "dc:description",
+            format!("<rdf:Alt><rdf:li xml:lang=\"x-default\">{value}</rdf:li></rdf:Alt>"),
+        ),
+        MetadataField::Software => ("xmp:CreatorTool", value),
+    }
+}
+
+/// Adds the fields to the XMP packet in a new description, dropping any previous value of the same properties.
+/// Builds a new packet if there is none or it cannot be edited.
+fn set_xmp_fields(xmp: Option<&[u8]>, fields: &[(MetadataField, String)]) -> Vec<u8> {
+    let mut properties = String::new();
+    for (field, value) in fields {
+        let (name, value) = xmp_property(*field, value);
+        properties.push_str(&format!("<{name}>{value}</{name}>"));
+    }
+    let description = format!(
+        "<rdf:Description rdf:about=\"\" xmlns:dc=\"{XMP_DC_NAMESPACE}\" xmlns:xmp=\"{XMP_BASIC_NAMESPACE}\">{properties}</rdf:Description>"
+    );
+
+    let existing = xmp
+        .and_then(|xmp| std::str::from_utf8(xmp).ok())
+        .filter(|xmp| xmp.contains(XMP_RDF_END));
+    let Some(existing) = existing else {
+        return format!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?><x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">{description}</rdf:RDF></x:xmpmeta><?xpacket end=\"w\"?>"
+        )
+        .into_bytes();
+    };
+
+    let mut xmp = existing.to_string();
+    for (field, _) in fields {
+        remove_xmp_property(&mut xmp, xmp_property(*field, "").0);
+    }
+    if let Some(position) = xmp.rfind(XMP_RDF_END) {
+        xmp.insert_str(position, &description);
+    }
+
+    xmp.into_bytes()
+}
+
+/// Removes a property written either as an element or as an attribute of its description
+fn remove_xmp_property(xmp: &mut String, name: &str) {
+    let open_tag = format!("<{name}");
+    let close_tag = format!("</{name}>");
+    let mut search_from = 0;
+    while let Some(start) = xmp[search_from..].find(&open_tag).map(|index| index + search_from) {
+        let rest = &xmp[start + open_tag.len()..];
+        if !rest.starts_with(['>', '/', ' ', '\t', '\r', '\n']) {
+            search_from = start + open_tag.len();
+            continue;
+        }
+        let Some(tag_end) = rest.find('>').map(|index| index + start + open_tag.len()) else {
+            return;
+        };
+        let end = if xmp[..tag_end].ends_with('/') {
+            tag_end + 1
+        } else {
+            match xmp[tag_end..].find(&close_tag) {
+                Some(index) => tag_end + index + close_tag.len(),
+                None => return,
+            }
+        };
+        xmp.replace_range(start..end, "");
+        search_from = start;
+    }
+
+    let attribute = format!(" {name}=\"");
+    while let Some(start) = xmp.find(&attribute) {
+        let value_start = start + attribute.len();
+        let Some(end) = xmp[value_start..].find('"').map(|index| index + value_start + 1) else {
+            return;
+        };
+        xmp.replace_range(start..end, "");
+    }
+}
+
+/// Names of the properties of the XMP packet, written either as elements or as attributes
+fn xmp_property_names(xmp: &str) -> Vec<&str> {
+    let is_name_char = |c: char| c.is_alphanumeric() || matches!(c, ':' | '_' | '-' | '.');
+    let mut names = Vec::new();
+    for (index, c) in xmp.char_indices() {
+        if !(c == '<' || c.is_whitespace()) {
+            continue;
+        }
+        let rest = &xmp[index + c.len_utf8()..];
+        let name = &rest[..rest.find(|c| !is_name_char(c)).unwrap_or(rest.len())];
+        let follows = rest[name.len()..].chars().next();
+        let is_property = if c == '<' {
+            matches!(follows, Some('>' | '/')) || follows.is_some_and(char::is_whitespace)
+        } else {
+            follows == Some('=')
+        };
+        if is_property && name.contains(':') && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    names
+}
+
+fn is_stripped_xmp_property(name: &str, tags: &[StripTag]) -> bool {
+    // Namespace prefixes are chosen by the writer, so only the local name can be relied upon
+    let local_name = name.rsplit(':').next().unwrap_or(name);
+    tags.iter().any(|tag| match tag {
+        StripTag::Gps => local_name.starts_with("GPS"),
+        StripTag::Serial => XMP_SERIAL_NUMBER_PROPERTIES.contains(&local_name),
+        // XMP has no counterpart of the maker notes
+        StripTag::MakerNotes => false,
+    })
+}
+
+/// Removes the properties matching the requested tags from the XMP packet.
+/// Returns `None` if the packet cannot be edited or some of them could not be removed.
+fn strip_xmp_tags(xmp: &[u8], tags: &[StripTag]) -> Option<Vec<u8>> {
+    if tags.is_empty() {
+        return Some(xmp.to_vec());
+    }
+
+    let mut xmp = String::from_utf8(xmp.to_vec()).ok()?;
+    let stripped: Vec<String> = xmp_property_names(&xmp)
+        .into_iter()
+        .filter(|name| is_stripped_xmp_property(name, tags))
+        .map(str::to_string)
+        .collect();
+    for name in stripped {
+        remove_xmp_property(&mut xmp, &name);
+    }
+
+    if xmp_property_names(&xmp)
+        .into_iter()
+        .any(|name| is_stripped_xmp_property(name, tags))
+    {
+        return None;
+    }
+
+    Some(xmp.into_bytes())
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Removes the requested tags from a raw EXIF (TIFF) block, in place so that every offset stays valid.
+/// The freed data is zeroed out. Returns `None` if the block is malformed.
+pub fn strip_exif_tags(exif: &mut [u8], tags: &[StripTag]) -> Option<()> {
+    if tags.is_empty() {
+        return Some(());
+    }
+
+    let mut tiff = Tiff::new(exif)?;
+    let ifd0 = tiff.read_u32(4)? as usize;
+
+    if tags.contains(&StripTag::Gps) {
+        if let Some(gps_ifd) = tiff.find_entry(ifd0, EXIF_GPS_IFD_TAG)? {
+            let gps_ifd_offset = tiff.read_u32(gps_ifd + 8)? as usize;
+            tiff.clear_ifd(gps_ifd_offset)?;
+            tiff.remove_entry(ifd0, EXIF_GPS_IFD_TAG)?;
+        }
+    }
+
+    let mut exif_ifd_tags = Vec::new();
+    if tags.contains(&StripTag::Serial) {
+        tiff.remove_entry(ifd0, EXIF_CAMERA_SERIAL_NUMBER_TAG)?;
+        exif_ifd_tags.extend([EXIF_BODY_SERIAL_NUMBER_TAG, EXIF_LENS_SERIAL_NUMBER_TAG]);
+    }
+    if tags.contains(&StripTag::MakerNotes) {
+        exif_ifd_tags.push(EXIF_MAKER_NOTE_TAG);
+    }
+
+    if !exif_ifd_tags.is_empty() {
+        if let Some(exif_ifd) = tiff.find_entry(ifd0, EXIF_IFD_TAG)? {
+            let exif_ifd_offset = tiff.read_u32(exif_ifd + 8)? as usize;
+            for tag in exif_ifd_tags {
+                tiff.remove_entry(exif_ifd_offset, tag)?;
+            }
+        }
+    }
+
+    Some(())
+}
+
+const IFD_ENTRY_SIZE: usize = 12;
+
+struct Tiff<'a> {
+    data: &'a mut [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a mut [u8]) -> Option<Self> {
+        let little_endian = match data.get(0..4)? {
+            [0x49, 0x49, 0x2a, 0x00] => true,
+            [0x4d, 0x4d, 0x00, 0x2a] => false,
+            _ => return None,
+        };
+
+        Some(Self { data, little_endian })
+    }
+
+    fn read_u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn read_u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    fn write_u16(&mut self, offset: usize, value: u16) -> Option<()> {
+        let bytes = if self.little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        };
+        self.data.get_mut(offset..offset + 2)?.copy_from_slice(&bytes);
+        Some(())
+    }
+
+    fn zero(&mut self, offset: usize, len: usize) -> Option<()> {
+        self.data.get_mut(offset..offset.checked_add(len)?)?.fill(0);
+        Some(())
+    }
+
+    /// Returns the offset of the entry holding `tag` in the IFD at `ifd`
+    fn find_entry(&self, ifd: usize, tag: u16) -> Option<Option<usize>> {
+        let count = self.read_u16(ifd)? as usize;
+        for index in 0..count {
+            let entry = ifd + 2 + index * IFD_ENTRY_SIZE;
+            if self.read_u16(entry)? == tag {
+                return Some(Some(entry));
+            }
+        }
+
+        Some(None)
+    }
+
+    /// Zeroes the value of an entry if it does not fit in the entry itself
+    fn clear_entry_value(&mut self, entry: usize) -> Option<()> {
+        let type_size = match self.read_u16(entry + 2)? {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 | 13 => 4,
+            5 | 10 | 12 => 8,
+            _ => return Some(()),
+        };
+        let size = type_size * self.read_u32(entry + 4)? as usize;
+        if size > 4 {
+            let offset = self.read_u32(entry + 8)? as usize;
+            // Values pointing out of the block are left alone, there is nothing to clear
+            let _ = self.zero(offset, size);
+        }
+
+        Some(())
+    }
+
+    fn remove_entry(&mut self, ifd: usize, tag: u16) -> Option<()> {
+        let Some(entry) = self.find_entry(ifd, tag)? else {
+            return Some(());
+        };
+        self.clear_entry_value(entry)?;
+
+        // Move the following entries and the next IFD offset up, then drop the now duplicated last entry
+        let count = self.read_u16(ifd)? as usize;
+        let end = ifd + 2 + count * IFD_ENTRY_SIZE + 4;
+        if end > self.data.len() {
+            return None;
+        }
+        self.data.copy_within(entry + IFD_ENTRY_SIZE..end, entry);
+        self.zero(end - IFD_ENTRY_SIZE, IFD_ENTRY_SIZE)?;
+        self.write_u16(ifd, (count - 1) as u16)
+    }
+
+    fn clear_ifd(&mut self, ifd: usize) -> Option<()> {
+        let count = self.read_u16(ifd)? as usize;
+        for index in 0..count {
+            self.clear_entry_value(ifd + 2 + index * IFD_ENTRY_SIZE)?;
+        }
+        self.zero(ifd + 2, count * IFD_ENTRY_SIZE)?;
+        self.write_u16(ifd, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::tests::setup_options;
+    use image::{ImageFormat, RgbImage};
+
+    /// Little-endian EXIF block: IFD0 holds the orientation, the camera serial and pointers to the Exif and GPS IFDs,
+    /// the Exif IFD holds the capture date, the body serial and the maker notes, the GPS IFD holds the latitude
+    fn sample_exif() -> Vec<u8> {
+        fn entry(tag: u16, kind: u16, count: u32, value: u32) -> Vec<u8> {
+            [
+                tag.to_le_bytes().as_slice(),
+                &kind.to_le_bytes(),
+                &count.to_le_bytes(),
+                &value.to_le_bytes(),
+            ]
+            .concat()
+        }
+
+        let mut exif = vec![0x49, 0x49, 0x2a, 0x00, 0x08, 0x00, 0x00, 0x00];
+        // IFD0 at 8, 4 entries: ends at 8 + 2 + 48 + 4 = 62
+        exif.extend_from_slice(&4u16.to_le_bytes());
+        exif.extend(entry(0x0112, 3, 1, 6));
+        exif.extend(entry(EXIF_IFD_TAG, 4, 1, 62));
+        exif.extend(entry(EXIF_GPS_IFD_TAG, 4, 1, 104));
+        exif.extend(entry(EXIF_CAMERA_SERIAL_NUMBER_TAG, 2, 8, 146));
+        exif.extend_from_slice(&[0; 4]);
+        // Exif IFD at 62, 3 entries: ends at 62 + 2 + 36 + 4 = 104
+        exif.extend_from_slice(&3u16.to_le_bytes());
+        exif.extend(entry(0x9003, 2, 20, 154));
+        exif.extend(entry(EXIF_MAKER_NOTE_TAG, 7, 6, 174));
+        exif.extend(entry(EXIF_BODY_SERIAL_NUMBER_TAG, 2, 4, 0x0032_3130));
+        exif.extend_from_slice(&[0; 4]);
+        // GPS IFD at 104, 2 entries: ends at 104 + 2 + 24 + 4 = 134
+        exif.extend_from_slice(&2u16.to_le_bytes());
+        exif.extend(entry(0x0001, 2, 2, 0x4e));
+        exif.extend(entry(0x0002, 5, 1, 138));
+        exif.extend_from_slice(&[0; 4]);
+        // Values
+        exif.extend_from_slice(&[0; 4]);
+        exif.extend_from_slice(&[45, 0, 0, 0, 1, 0, 0, 0]);
+        exif.extend_from_slice(b"CAM1234\0");
+        exif.extend_from_slice(b"2024:01:02 03:04:05\0");
+        exif.extend_from_slice(b"MAKER\0");
+        exif
+    }
+
+    fn read_field(exif: &[u8], tag: exif::Tag, ifd: exif::In) -> Option<String> {
+        let exif = exif::Reader::new().read_raw(exif.to_vec()).ok()?;
+        exif.get_field(tag, ifd).map(|field| field.display_value().to_string())
+    }
+
+    #[test]
+    fn test_strip_exif_tags() {
+        let mut exif = sample_exif();
+        assert!(read_field(&exif, exif::Tag::GPSLatitudeRef, exif::In::PRIMARY).is_some());
+        strip_exif_tags(&mut exif, &[StripTag::Gps]).unwrap();
+        assert_eq!(exif.len(), sample_exif().len());
+        assert!(read_field(&exif, exif::Tag::GPSLatitudeRef, exif::In::PRIMARY).is_none());
+        assert!(read_field(&exif, exif::Tag::GPSLatitude, exif::In::PRIMARY).is_none());
+        assert!(!exif.windows(8).any(|w| w == [45, 0, 0, 0, 1, 0, 0, 0]));
+        assert!(read_field(&exif, exif::Tag::BodySerialNumber, exif::In::PRIMARY).is_some());
+
+        strip_exif_tags(&mut exif, &[StripTag::Serial, StripTag::MakerNotes]).unwrap();
+        assert!(read_field(&exif, exif::Tag::BodySerialNumber, exif::In::PRIMARY).is_none());
+        assert!(read_field(&exif, exif::Tag::MakerNote, exif::In::PRIMARY).is_none());
+        assert!(!exif.windows(7).any(|w| w == b"CAM1234"));
+        assert!(!exif.windows(5).any(|w| w == b"MAKER"));
+        assert_eq!(
+            read_field(&exif, exif::Tag::DateTimeOriginal, exif::In::PRIMARY).as_deref(),
+            Some("2024-01-02 03:04:05")
+        );
+        assert_eq!(
+            read_field(&exif, exif::Tag::Orientation, exif::In::PRIMARY).as_deref(),
+            Some("row 0 at right and column 0 at top")
+        );
+    }
+
+    #[test]
+    fn test_strip_exif_tags_rejects_malformed_data() {
+        assert!(strip_exif_tags(&mut [0x49, 0x49, 0x2a], &[StripTag::Gps]).is_none());
+        let mut exif = sample_exif();
+        exif.truncate(40);
+        assert!(strip_exif_tags(&mut exif, &[StripTag::Gps]).is_none());
+    }
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        RgbImage::new(8, 8)
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_write_metadata_round_trip() {
+        let metadata = ImageMetadata {
+            exif: Some(sample_exif()),
+            icc_profile: Some(vec![1, 2, 3, 4]),
+            xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>".to_vec()),
+            iptc: Some(vec![0x38, 0x42, 0x49, 0x4d]),
+        };
+
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP] {
+            let output = write_metadata(encode(format), &metadata).unwrap();
+            let mut expected = metadata.clone();
+            if format != ImageFormat::Jpeg {
+                expected.iptc = None;
+            }
+            assert_eq!(ImageMetadata::read(&output).unwrap(), expected, "{format:?}");
+        }
+
+        let gif = encode(ImageFormat::Gif);
+        assert_eq!(write_metadata(gif.clone(), &metadata).unwrap(), gif);
+    }
+
+    #[test]
+    fn test_read_kept_metadata() {
+        let mut source = ImageMetadata {
+            exif: Some(sample_exif()),
+            icc_profile: None,
+            xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>".to_vec()),
+            iptc: None,
+        };
+        let buffer = write_metadata(encode(ImageFormat::Jpeg), &source).unwrap();
+
+        let mut options = setup_options();
+        assert!(read_kept_metadata(&buffer, &options).is_none());
+
+        options.keep_metadata = vec![MetadataKind::Exif];
+        options.strip_tags = vec![StripTag::Gps];
+        let kept = read_kept_metadata(&buffer, &options).unwrap();
+        assert!(kept.xmp.is_none());
+        let exif = kept.exif.unwrap();
+        assert!(read_field(&exif, exif::Tag::GPSLatitude, exif::In::PRIMARY).is_none());
+        assert!(read_field(&exif, exif::Tag::BodySerialNumber, exif::In::PRIMARY).is_some());
+
+        source.exif.as_mut().unwrap().truncate(40);
+        let buffer = write_metadata(encode(ImageFormat::Jpeg), &source).unwrap();
+        assert!(read_kept_metadata(&buffer, &options).unwrap().exif.is_none());
+    }
+
+    #[test]
+    fn test_read_kept_metadata_strips_xmp() {
+        let xmp = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF><rdf:Description rdf:about=\"\" exif:GPSLatitude=\"45,30.5N\" aux:SerialNumber=\"CAM1234\"><exif:GPSLongitude>9,11.2E</exif:GPSLongitude><exif:GPSAltitude/><photo:GPSTimeStamp>2024-01-02</photo:GPSTimeStamp><exifEX:BodySerialNumber>0123</exifEX:BodySerialNumber><dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">(c) Agency</rdf:li></rdf:Alt></dc:rights></rdf:Description></rdf:RDF></x:xmpmeta>";
+        let source = ImageMetadata {
+            xmp: Some(xmp.as_bytes().to_vec()),
+            ..Default::default()
+        };
+        let buffer = write_metadata(encode(ImageFormat::Jpeg), &source).unwrap();
+
+        let mut options = setup_options();
+        options.keep_metadata = vec![MetadataKind::Xmp];
+        options.strip_tags = vec![StripTag::Gps];
+        let kept = String::from_utf8(read_kept_metadata(&buffer, &options).unwrap().xmp.unwrap()).unwrap();
+        assert!(!kept.contains("GPS"));
+        assert!(!kept.contains("45,30.5N"));
+        assert!(!kept.contains("9,11.2E"));
+        assert!(kept.contains("aux:SerialNumber=\"CAM1234\""));
+        assert!(kept.contains("(c) Agency"));
+
+        options.strip_tags = vec![StripTag::Gps, StripTag::Serial];
+        let kept = String::from_utf8(read_kept_metadata(&buffer, &options).unwrap().xmp.unwrap()).unwrap();
+        assert!(!kept.contains("CAM1234"));
+        assert!(!kept.contains("0123"));
+        assert!(kept.ends_with("<dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">(c) Agency</rdf:li></rdf:Alt></dc:rights></rdf:Description></rdf:RDF></x:xmpmeta>"));
+
+        // An element left open cannot be removed, so the whole packet goes
+        let source = ImageMetadata {
+            xmp: Some(b"<x:xmpmeta><rdf:RDF><exif:GPSLatitude>45,30.5N</rdf:RDF></x:xmpmeta>".to_vec()),
+            ..Default::default()
+        };
+        let buffer = write_metadata(encode(ImageFormat::Jpeg), &source).unwrap();
+        assert!(read_kept_metadata(&buffer, &options).unwrap().xmp.is_none());
+    }
+
+    fn copyright(template: &str) -> MetadataValue {
+        MetadataValue {
+            field: MetadataField::Copyright,
+            template: template.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render_template() {
+        let path = Path::new("shoots/wedding/IMG_001.jpg");
+        assert_eq!(
+            render_template("(c) Agency - {dir}/{name}.{ext}", path),
+            "(c) Agency - wedding/IMG_001.jpg"
+        );
+        assert_eq!(render_template("{name}", Path::new("photo")), "photo");
+    }
+
+    #[test]
+    fn test_set_exif_fields() {
+        let fields = vec![
+            (MetadataField::Copyright, "(c) Agency".to_string()),
+            (MetadataField::Artist, "Me".to_string()),
+        ];
+
+        let exif = set_exif_fields(&sample_exif(), &fields).unwrap();
+        assert_eq!(
+            read_field(&exif, exif::Tag::Copyright, exif::In::PRIMARY).as_deref(),
+            Some("\"(c) Agency\"")
+        );
+        assert_eq!(
+            read_field(&exif, exif::Tag::Artist, exif::In::PRIMARY).as_deref(),
+            Some("\"Me\"")
+        );
+        assert!(read_field(&exif, exif::Tag::GPSLatitude, exif::In::PRIMARY).is_some());
+        assert!(read_field(&exif, exif::Tag::DateTimeOriginal, exif::In::PRIMARY).is_some());
+
+        let exif = set_exif_fields(&exif, &[(MetadataField::Copyright, "Other".to_string())]).unwrap();
+        assert_eq!(
+            read_field(&exif, exif::Tag::Copyright, exif::In::PRIMARY).as_deref(),
+            Some("\"Other\"")
+        );
+        assert!(read_field(&exif, exif::Tag::Artist, exif::In::PRIMARY).is_some());
+
+        let exif = set_exif_fields(&EMPTY_EXIF, &fields).unwrap();
+        assert!(read_field(&exif, exif::Tag::Copyright, exif::In::PRIMARY).is_some());
+        assert!(set_exif_fields(&[0x49, 0x49], &fields).is_none());
+    }
+
+    #[test]
+    fn test_set_xmp_fields() {
+        let fields = vec![(MetadataField::Copyright, "A & B".to_string())];
+
+        let xmp = String::from_utf8(set_xmp_fields(None, &fields)).unwrap();
+        assert!(
+            xmp.contains("<dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">A &amp; B</rdf:li></rdf:Alt></dc:rights>")
+        );
+
+        let existing = "<x:xmpmeta><rdf:RDF><rdf:Description xmp:CreatorTool=\"Tool\"><dc:rights><rdf:Alt><rdf:li>Old</rdf:li></rdf:Alt></dc:rights><dc:rightsHolder>Kept</dc:rightsHolder></rdf:Description></rdf:RDF></x:xmpmeta>";
+        let fields = vec![
+            (MetadataField::Copyright, "New".to_string()),
+            (MetadataField::Software, "caesiumclt".to_string()),
+        ];
+        let xmp = String::from_utf8(set_xmp_fields(Some(existing.as_bytes()), &fields)).unwrap();
+        assert!(!xmp.contains("Old"));
+        assert!(!xmp.contains("\"Tool\""));
+        assert!(xmp.contains("<dc:rightsHolder>Kept</dc:rightsHolder>"));
+        assert!(xmp.contains("<xmp:CreatorTool>caesiumclt</xmp:CreatorTool>"));
+        assert!(xmp.ends_with("</rdf:Description></rdf:RDF></x:xmpmeta>"));
+    }
+
+    #[test]
+    fn test_set_metadata_fields() {
+        let path = Path::new("samples/IMG_001.jpg");
+        let values = vec![copyright("(c) {name}")];
+
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP] {
+            let source = ImageMetadata {
+                exif: Some(sample_exif()),
+                icc_profile: Some(vec![1, 2, 3, 4]),
+                ..Default::default()
+            };
+            let buffer = write_metadata(encode(format), &source).unwrap();
+
+            let output = set_metadata_fields(buffer, &values, path).unwrap();
+            let metadata = ImageMetadata::read(&output).unwrap();
+            let exif = metadata.exif.unwrap();
+            assert_eq!(
+                read_field(&exif, exif::Tag::Copyright, exif::In::PRIMARY).as_deref(),
+                Some("\"(c) IMG_001\""),
+                "{format:?}"
+            );
+            assert!(read_field(&exif, exif::Tag::DateTimeOriginal, exif::In::PRIMARY).is_some());
+            assert!(String::from_utf8(metadata.xmp.unwrap())
+                .unwrap()
+                .contains("(c) IMG_001"));
+            assert_eq!(metadata.icc_profile, Some(vec![1, 2, 3, 4]));
+        }
+
+        let output = set_metadata_fields(encode(ImageFormat::Jpeg), &values, path).unwrap();
+        let exif = ImageMetadata::read(&output).unwrap().exif.unwrap();
+        assert!(read_field(&exif, exif::Tag::Copyright, exif::In::PRIMARY).is_some());
+
+        let gif = encode(ImageFormat::Gif);
+        assert_eq!(set_metadata_fields(gif.clone(), &values, path).unwrap(), gif);
+    }
+
+    #[test]
+    fn test_set_density() {
+        let output = set_density(encode(ImageFormat::Jpeg), 300).unwrap();
+        let jpeg = Jpeg::from_bytes(Bytes::from(output)).unwrap();
+        let jfif = jpeg
+            .segments()
+            .iter()
+            .find(|segment| segment.marker() == markers::APP0)
+            .unwrap();
+        assert_eq!(jfif.contents()[7..12], [1, 0x01, 0x2c, 0x01, 0x2c]);
+        assert!(jpeg.exif().is_none());
+
+        let source = ImageMetadata {
+            exif: Some(sample_exif()),
+            ..Default::default()
+        };
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP] {
+            let buffer = write_metadata(encode(format), &source).unwrap();
+            let output = set_density(buffer, 300).unwrap();
+            let exif = ImageMetadata::read(&output).unwrap().exif.unwrap();
+            assert_eq!(
+                read_field(&exif, exif::Tag::XResolution, exif::In::PRIMARY).as_deref(),
+                Some("300"),
+                "{format:?}"
+            );
+            assert_eq!(
+                read_field(&exif, exif::Tag::ResolutionUnit, exif::In::PRIMARY).as_deref(),
+                Some("inch")
+            );
+            assert!(read_field(&exif, exif::Tag::DateTimeOriginal, exif::In::PRIMARY).is_some());
+        }
+
+        let output = set_density(encode(ImageFormat::Png), 300).unwrap();
+        let png = Png::from_bytes(Bytes::from(output)).unwrap();
+        assert_eq!(
+            png.chunk_by_type(PNG_DENSITY_CHUNK).unwrap().contents()[..],
+            [0, 0, 0x2e, 0x23, 0, 0, 0x2e, 0x23, 1]
+        );
+        assert!(png.exif().is_none());
+
+        let output = set_density(encode(ImageFormat::WebP), 72).unwrap();
+        let exif = ImageMetadata::read(&output).unwrap().exif.unwrap();
+        assert_eq!(
+            read_field(&exif, exif::Tag::YResolution, exif::In::PRIMARY).as_deref(),
+            Some("72")
+        );
+
+        let output = set_density(encode(ImageFormat::Tiff), 300).unwrap();
+        assert_eq!(
+            read_field(&output, exif::Tag::XResolution, exif::In::PRIMARY).as_deref(),
+            Some("300")
+        );
+        assert_eq!(image::load_from_memory(&output).unwrap().width(), 8);
+
+        let gif = encode(ImageFormat::Gif);
+        assert_eq!(set_density(gif.clone(), 300).unwrap(), gif);
+    }
+}
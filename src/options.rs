@@ -1,33 +1,55 @@
 use bytesize::ByteSize;
-use clap::{Args, Parser, ValueEnum};
-use std::path::PathBuf;
+use caesiumclt::{
+    CollisionPolicy, Dimensions, DryRunMode, FileOrder, Flip, MetadataField, MetadataKind, MetadataValue,
+    MinSavingsThreshold, OutputFormat, OverwritePolicy, Rotation, SortKey, SrgbProfile, StripTag, WatermarkPosition,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Copy, Clone, PartialEq, Debug)]
-pub enum MinSavingsThreshold {
-    /// Percentage-based threshold (0.0-100.0)
-    Percentage(f64),
-    /// Byte-based threshold (absolute size)
-    Bytes(u64),
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum BenchReport {
+    /// Aligned columns, for reading in the terminal
+    Table,
+    Csv,
+    Json,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
-pub enum OverwritePolicy {
-    /// Always overwrite existing files
-    All,
-    /// Never overwrite existing files
-    Never,
-    /// Overwrite only if the existing file is bigger
-    Bigger,
+pub enum ReportFormat {
+    /// One row per file, written at the end
+    Csv,
+    /// One JSON object per file as soon as it is done, then a summary line
+    Ndjson,
+    /// A single JSON document written at the end, same as --json
+    Json,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
-pub enum OutputFormat {
-    Jpeg,
-    Png,
-    Gif,
-    Webp,
-    Tiff,
-    Original,
+pub enum ProgressMode {
+    /// Progress bar in the terminal, hidden by --quiet
+    Bar,
+    /// One JSON object per event on stderr, for other programs to follow the run
+    Json,
+    /// No progress at all
+    None,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum FailOn {
+    /// Files that could not be compressed
+    Error,
+    /// Skipped files too, e.g. because of --min-savings or the overwrite policy
+    Skipped,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum HtmlCompare {
+    /// Thumbnails of the original and the output next to each other
+    SideBySide,
+    /// Output thumbnail over the original one, revealed with a slider
+    Slider,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
@@ -45,8 +67,17 @@ pub enum JpegChromaSubsampling {
 }
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct CommandLineArgs {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[command(flatten)]
     pub compression: Compression,
 
@@ -56,6 +87,17 @@ pub struct CommandLineArgs {
     #[command(flatten)]
     pub output_destination: OutputDestination,
 
+    #[command(flatten)]
+    pub watermark: Watermark,
+
+    /// Rotate the image clockwise by the given degrees
+    #[arg(long, value_enum)]
+    pub rotate: Option<Rotation>,
+
+    /// Flip the image, applied after the rotation
+    #[arg(long, value_enum)]
+    pub flip: Option<Flip>,
+
     /// Convert to the selected output format or keep the original
     #[arg(long, value_enum, default_value = "original")]
     pub format: OutputFormat,
@@ -76,10 +118,52 @@ pub struct CommandLineArgs {
     #[arg(long)]
     pub zopfli: bool,
 
+    /// Convert the pixels from the embedded RGB color profile to sRGB
+    #[arg(long)]
+    pub convert_to_srgb: bool,
+
+    /// Color profile of the images converted by --convert-to-srgb
+    #[arg(long, value_enum, default_value = "strip", requires = "convert_to_srgb")]
+    pub srgb_profile: SrgbProfile,
+
+    /// Convert the image to grayscale
+    #[arg(long)]
+    pub grayscale: bool,
+
+    /// Reduce PNG and still GIF outputs to a palette of at most this many colors [2-256]
+    #[arg(long, value_parser = colors_validator)]
+    pub colors: Option<u32>,
+
+    /// Dithering level used by --colors [0.0-1.0], 0 disables it
+    #[arg(long, default_value = "1.0", value_parser = dithering_validator, requires = "colors")]
+    pub dithering: f32,
+
     /// Keep EXIF metadata during compression
-    #[arg(short, long)]
+    #[arg(short, long, group = "kept_metadata")]
     pub exif: bool,
 
+    /// Keep only the selected metadata, as a comma separated list (e.g. exif,icc)
+    #[arg(long, value_enum, value_delimiter = ',', group = "kept_metadata")]
+    pub keep_metadata: Vec<MetadataKind>,
+
+    /// Remove the selected tags from the EXIF and XMP data being kept, as a comma separated list (e.g. gps,serial).
+    /// Requires -e or --keep-metadata
+    #[arg(long, value_enum, value_delimiter = ',', requires = "kept_metadata")]
+    pub strip_tags: Vec<StripTag>,
+
+    /// Set a metadata field on every output, as Field=Value (Artist, Copyright, ImageDescription, Software).
+    /// The value can use the {name}, {ext} and {dir} placeholders of the input file
+    #[arg(long, value_parser = set_meta_validator)]
+    pub set_meta: Vec<MetadataValue>,
+
+    /// Set the pixel density of every output, in dots per inch [1-65535]
+    #[arg(long, value_parser = dpi_validator)]
+    pub dpi: Option<u32>,
+
+    /// Rotate and flip pixels according to the EXIF orientation, then reset it
+    #[arg(long)]
+    pub auto_orient: bool,
+
     /// Preserve original file timestamps
     #[arg(long)]
     pub keep_dates: bool,
@@ -100,18 +184,98 @@ pub struct CommandLineArgs {
     #[arg(short = 'S', long)]
     pub keep_structure: bool,
 
-    /// Simulate compression without writing files
-    #[arg(long, short, default_value = "false")]
-    pub dry_run: bool,
+    /// Scan folders down to this depth, 1 being the files directly inside them. Overrides -R/--recursive
+    #[arg(long, value_parser = max_depth_validator)]
+    pub max_depth: Option<usize>,
+
+    /// Follow symbolic links to files and folders, skipping the links that loop back to a parent folder
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// Scan hidden files and folders, whose name starts with a dot (default)
+    #[arg(long, overrides_with = "skip_hidden")]
+    pub include_hidden: bool,
+
+    /// Skip hidden files and folders, whose name starts with a dot, unless given as input
+    #[arg(long, overrides_with = "include_hidden")]
+    pub skip_hidden: bool,
+
+    /// Skip the files and folders listed in the .gitignore, .ignore and .caesiumignore files found while scanning
+    #[arg(long)]
+    pub respect_ignore: bool,
+
+    /// Only compress files at least this big (e.g. 200KB, 1MiB)
+    #[arg(long, value_parser = size_validator)]
+    pub min_file_size: Option<u64>,
+
+    /// Only compress files at most this big (e.g. 200KB, 1MiB)
+    #[arg(long, value_parser = size_validator)]
+    pub max_file_size: Option<u64>,
+
+    /// Only compress images at least this wide and tall, as WIDTHxHEIGHT (e.g. 1920x1080)
+    #[arg(long, value_parser = dimensions_validator)]
+    pub min_dimensions: Option<Dimensions>,
+
+    /// Only compress images at most this wide and tall, as WIDTHxHEIGHT (e.g. 1920x1080)
+    #[arg(long, value_parser = dimensions_validator)]
+    pub max_dimensions: Option<Dimensions>,
+
+    /// Only compress files modified after a date (e.g. 2024-05-01 or "2024-05-01 18:30", UTC), a duration ago (e.g.
+    /// 1d, 12h) or the modification of a file
+    #[arg(long, value_parser = newer_than_validator)]
+    pub newer_than: Option<SystemTime>,
+
+    /// Simulate compression without writing files. With =estimate, compress in memory to report the projected savings
+    #[arg(
+        long,
+        short,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "simulate"
+    )]
+    pub dry_run: Option<DryRunMode>,
+
+    /// Estimate on a random sample of this many files and extrapolate the savings (requires --dry-run=estimate)
+    #[arg(long, value_parser = sample_validator, requires = "dry_run")]
+    pub sample: Option<usize>,
 
     /// Number of parallel jobs (0 = auto-detect, max = available processors)
     #[arg(long, default_value = "0")]
     pub threads: u32,
 
+    /// Order of the files in the results and reports, ties being sorted by path
+    #[arg(long, value_enum, default_value = "path")]
+    pub sort: SortKey,
+
+    /// Order in which the files are compressed. Results are reported in the input order regardless
+    #[arg(long, value_enum, default_value = "size-desc")]
+    pub order: FileOrder,
+
+    /// Skip files bigger than this size (e.g. '2GB'), or 'unlimited'
+    #[arg(long, default_value = "500MiB", value_parser = max_input_size_validator)]
+    pub max_input_size: u64,
+
+    /// Run only as many files at once as fit in this amount of memory (e.g. '4GB'), estimated from their pixel count
+    #[arg(long, value_parser = size_validator)]
+    pub memory_limit: Option<u64>,
+
+    /// Give up on a file after this duration (e.g. '90s', '5m') and skip it
+    #[arg(long, value_parser = duration_validator)]
+    pub file_timeout: Option<Duration>,
+
+    /// Retry the PNG outputs that timed out once with faster PNG settings before skipping them
+    #[arg(long, requires = "file_timeout")]
+    pub timeout_fallback: bool,
+
     /// Trust file extensions instead of reading magic bytes (significantly faster on large directories)
     #[arg(long, default_value = "false")]
     pub check_extension_only: bool,
 
+    /// What to do when several files would be written to the same output path, all but the first one in input order
+    #[arg(long, value_enum, default_value = "error")]
+    pub on_collision: CollisionPolicy,
+
     /// Policy for handling existing output files
     #[arg(short = 'O', long, value_enum, default_value = "all")]
     pub overwrite: OverwritePolicy,
@@ -121,6 +285,10 @@ pub struct CommandLineArgs {
     #[arg(long, value_parser = min_savings_validator)]
     pub min_savings: Option<MinSavingsThreshold>,
 
+    /// Which files count as failed in the exit code
+    #[arg(long, value_enum, default_value = "error")]
+    pub fail_on: FailOn,
+
     /// Suppress all output
     #[arg(short = 'Q', long, group = "verbosity")]
     pub quiet: bool,
@@ -129,14 +297,79 @@ pub struct CommandLineArgs {
     #[arg(long, default_value = "1", group = "verbosity", value_parser = verbosity_validator)]
     pub verbose: u8,
 
+    /// How the progress of the compression is shown
+    #[arg(long, value_enum, default_value = "bar")]
+    pub progress: ProgressMode,
+
     /// Output results as structured JSON
     #[arg(long, group = "verbosity")]
     pub json: bool,
 
+    /// Write a machine-readable report of the results, to stdout unless --report-file is set
+    #[arg(long, value_enum, conflicts_with = "json")]
+    pub report: Option<ReportFormat>,
+
+    /// Write the report to this file, keeping the usual terminal output
+    #[arg(long, requires = "report")]
+    pub report_file: Option<PathBuf>,
+
+    /// Write a self-contained HTML report of the results to this file
+    #[arg(long)]
+    pub html_report: Option<PathBuf>,
+
+    /// Add thumbnails of the originals and the outputs to the HTML report
+    #[arg(long, value_enum, requires = "html_report")]
+    pub html_compare: Option<HtmlCompare>,
+
     /// Input files or directories to process
     pub files: Vec<String>,
 }
 
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Compare the size, time and quality of a grid of settings on a set of images, without writing any file
+    Bench(BenchArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Qualities to try, as a comma separated list [0-100]
+    #[arg(long, value_delimiter = ',', default_value = "80", value_parser = quality_validator)]
+    pub qualities: Vec<u32>,
+
+    /// Output formats to try, as a comma separated list
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "original")]
+    pub formats: Vec<OutputFormat>,
+
+    /// PNG optimization levels to try, as a comma separated list [0-6]
+    #[arg(long, value_delimiter = ',', default_value = "3", value_parser = png_opt_level_validator)]
+    pub png_opt_levels: Vec<u8>,
+
+    /// Zopfli settings to try, as a comma separated list (e.g. false,true)
+    #[arg(long, value_delimiter = ',', default_value = "false")]
+    pub zopfli: Vec<bool>,
+
+    /// Chroma subsamplings to try for JPEG files, as a comma separated list
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "auto")]
+    pub jpeg_chroma_subsamplings: Vec<JpegChromaSubsampling>,
+
+    /// Also compute the SSIM of every output against its input, 1 meaning identical
+    #[arg(long)]
+    pub ssim: bool,
+
+    /// Format of the results printed to stdout
+    #[arg(long, value_enum, default_value = "table")]
+    pub report: BenchReport,
+
+    /// Scan subfolders recursively when input is a directory
+    #[arg(short = 'R', long)]
+    pub recursive: bool,
+
+    /// Input files or directories to benchmark
+    #[arg(required = true)]
+    pub files: Vec<String>,
+}
+
 #[derive(Args, Debug)]
 #[group(required = true, multiple = false)]
 pub struct Compression {
@@ -189,6 +422,26 @@ pub struct OutputDestination {
     pub same_folder_as_input: bool,
 }
 
+#[derive(Args, Debug)]
+#[group(required = false, multiple = true)]
+pub struct Watermark {
+    /// Image to overlay on every output, after resizing (e.g. a PNG with transparency)
+    #[arg(long = "watermark", id = "watermark")]
+    pub image: Option<PathBuf>,
+
+    /// Position of the watermark
+    #[arg(long, value_enum, default_value = "bottom-right", requires = "watermark")]
+    pub watermark_position: WatermarkPosition,
+
+    /// Opacity of the watermark [0.0-1.0]
+    #[arg(long, default_value = "1.0", value_parser = watermark_opacity_validator, requires = "watermark")]
+    pub watermark_opacity: f32,
+
+    /// Width of the watermark relative to the output width [0.01-1.0]
+    #[arg(long, default_value = "0.2", value_parser = watermark_scale_validator, requires = "watermark")]
+    pub watermark_scale: f32,
+}
+
 /// Validates quality values are within the valid range [0-100]
 fn quality_validator(val: &str) -> Result<u32, String> {
     validate_range(val, 0, 100, "Quality")
@@ -204,6 +457,41 @@ fn png_opt_level_validator(val: &str) -> Result<u8, String> {
     validate_range(val, 0, 6, "PNG optimization level")
 }
 
+/// Validates palette sizes are within the valid range [2-256]
+fn colors_validator(val: &str) -> Result<u32, String> {
+    validate_range(val, 2, 256, "Colors")
+}
+
+/// Validates sample sizes are at least 1
+fn sample_validator(val: &str) -> Result<usize, String> {
+    validate_range(val, 1, usize::MAX, "Sample size")
+}
+
+/// Validates scan depths are at least 1, counted from the input folder whose own files are at depth 1
+fn max_depth_validator(val: &str) -> Result<usize, String> {
+    validate_range(val, 1, usize::MAX, "Max depth")
+}
+
+/// Validates pixel densities are within the valid range [1-65535]
+fn dpi_validator(val: &str) -> Result<u32, String> {
+    validate_range(val, 1, 65535, "DPI")
+}
+
+/// Validates dithering levels are within the valid range [0.0-1.0]
+fn dithering_validator(val: &str) -> Result<f32, String> {
+    validate_range(val, 0.0, 1.0, "Dithering level")
+}
+
+/// Validates watermark opacities are within the valid range [0.0-1.0]
+fn watermark_opacity_validator(val: &str) -> Result<f32, String> {
+    validate_range(val, 0.0, 1.0, "Watermark opacity")
+}
+
+/// Validates watermark scales are within the valid range [0.01-1.0]
+fn watermark_scale_validator(val: &str) -> Result<f32, String> {
+    validate_range(val, 0.01, 1.0, "Watermark scale")
+}
+
 /// Generic validator for numeric ranges
 fn validate_range<T>(val: &str, min: T, max: T, field_name: &str) -> Result<T, String>
 where
@@ -256,10 +544,200 @@ fn min_savings_validator(val: &str) -> Result<MinSavingsThreshold, String> {
         ))
 }
 
+/// Validates and parses sizes in bytes (supports both raw bytes and human-readable formats)
+fn size_validator(val: &str) -> Result<u64, String> {
+    val.parse::<ByteSize>()
+        .map(|bs| bs.as_u64())
+        .map_err(|e| format!("Invalid size format: {e}"))
+}
+
+/// Validates and parses max_input_size values, "unlimited" removing the limit
+fn max_input_size_validator(val: &str) -> Result<u64, String> {
+    if val.eq_ignore_ascii_case("unlimited") {
+        return Ok(u64::MAX);
+    }
+    size_validator(val)
+}
+
+/// Validates and parses durations such as '90s', '5m', '1h' or '500ms', plain numbers being seconds
+fn duration_validator(val: &str) -> Result<Duration, String> {
+    let trimmed = val.trim();
+    let (number, unit) = trimmed.split_at(
+        trimmed
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(trimmed.len()),
+    );
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration: '{val}'. Use e.g. '500ms', '90s', '5m' or '1h'"))?;
+
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format!("Invalid duration unit: '{unit}'. Use 'ms', 's', 'm' or 'h'")),
+    };
+    if seconds <= 0.0 {
+        return Err("Duration must be greater than 0".to_string());
+    }
+
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("Duration '{val}' is too long"))
+}
+
+/// Validates and parses dimensions in the WIDTHxHEIGHT form
+fn dimensions_validator(val: &str) -> Result<Dimensions, String> {
+    let (width, height) = val
+        .trim()
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("Invalid dimensions '{val}', expected WIDTHxHEIGHT (e.g. 1920x1080)"))?;
+    let parse = |side: &str| {
+        side.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid dimensions '{val}', expected WIDTHxHEIGHT (e.g. 1920x1080)"))
+    };
+
+    Ok(Dimensions {
+        width: parse(width)?,
+        height: parse(height)?,
+    })
+}
+
+/// Validates and parses newer_than values: the modification time of an existing file, a UTC date, or a duration
+/// ago, which may be given in days ('7d') on top of the units of duration_validator
+fn newer_than_validator(val: &str) -> Result<SystemTime, String> {
+    let path = Path::new(val);
+    if path.exists() {
+        return fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e| format!("Cannot read the modification time of '{val}': {e}"));
+    }
+    if let Some(time) = parse_utc_date(val.trim()) {
+        return Ok(time);
+    }
+
+    let age = match val.trim().strip_suffix('d') {
+        Some(days) => days
+            .parse::<f64>()
+            .ok()
+            .filter(|days| *days > 0.0)
+            .ok_or_else(|| format!("Invalid duration: '{val}'"))
+            .and_then(|days| {
+                Duration::try_from_secs_f64(days * 86400.0).map_err(|_| format!("Duration '{val}' is too long"))
+            })?,
+        None => duration_validator(val).map_err(|_| {
+            format!("Invalid value '{val}', expected a date (e.g. 2024-05-01), a duration (e.g. 1d, 12h) or a file")
+        })?,
+    };
+    SystemTime::now()
+        .checked_sub(age)
+        .ok_or_else(|| format!("Duration '{val}' is too long"))
+}
+
+/// Parses 'YYYY-MM-DD', optionally followed by ' HH:MM[:SS]' or 'THH:MM[:SS]', as a UTC time
+fn parse_utc_date(val: &str) -> Option<SystemTime> {
+    let (date, time) = match val.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (val, None),
+    };
+
+    let mut date_parts = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date_parts.next()??, date_parts.next()??, date_parts.next()??);
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) {
+        return None;
+    }
+    let leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=month_days).contains(&day) {
+        return None;
+    }
+
+    let mut seconds = 0;
+    if let Some(time) = time {
+        let parts = time
+            .split(':')
+            .map(|part| part.parse::<i64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let (hours, minutes, secs) = match parts[..] {
+            [hours, minutes] => (hours, minutes, 0),
+            [hours, minutes, secs] => (hours, minutes, secs),
+            _ => return None,
+        };
+        if !(0..24).contains(&hours) || !(0..60).contains(&minutes) || !(0..60).contains(&secs) {
+            return None;
+        }
+        seconds = hours * 3600 + minutes * 60 + secs;
+    }
+
+    // Days since the epoch of the proleptic Gregorian date, with years starting in March
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    Some(UNIX_EPOCH + Duration::from_secs((days * 86400 + seconds) as u64))
+}
+
+/// Validates and parses set_meta values in the Field=Value form, field names being case-insensitive
+fn set_meta_validator(val: &str) -> Result<MetadataValue, String> {
+    let (field, template) = val
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid metadata '{val}', expected Field=Value"))?;
+
+    let field = match field.trim().to_lowercase().as_str() {
+        "artist" => MetadataField::Artist,
+        "copyright" => MetadataField::Copyright,
+        "imagedescription" => MetadataField::ImageDescription,
+        "software" => MetadataField::Software,
+        _ => {
+            return Err(format!(
+                "Unsupported metadata field '{field}', use Artist, Copyright, ImageDescription or Software"
+            ))
+        }
+    };
+
+    Ok(MetadataValue {
+        field,
+        template: template.to_string(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_set_meta_validator() {
+        assert_eq!(
+            set_meta_validator("Copyright=(c) {name} Agency").unwrap(),
+            MetadataValue {
+                field: MetadataField::Copyright,
+                template: "(c) {name} Agency".to_string()
+            }
+        );
+        assert_eq!(
+            set_meta_validator("artist=A=B").unwrap(),
+            MetadataValue {
+                field: MetadataField::Artist,
+                template: "A=B".to_string()
+            }
+        );
+        assert_eq!(set_meta_validator("Software=").unwrap().template, "");
+        assert!(set_meta_validator("Copyright").is_err());
+        assert!(set_meta_validator("Make=Canon").is_err());
+    }
+
     #[test]
     fn test_quality_validator() {
         assert!(quality_validator("50").is_ok());
@@ -286,6 +764,26 @@ mod tests {
         assert!(png_opt_level_validator("7").is_err());
     }
 
+    #[test]
+    fn test_colors_and_dithering_validators() {
+        assert_eq!(colors_validator("16").unwrap(), 16);
+        assert!(colors_validator("2").is_ok());
+        assert!(colors_validator("256").is_ok());
+        assert!(colors_validator("1").is_err());
+        assert!(colors_validator("257").is_err());
+        assert_eq!(dithering_validator("0.5").unwrap(), 0.5);
+        assert!(dithering_validator("0").is_ok());
+        assert!(dithering_validator("1.1").is_err());
+    }
+
+    #[test]
+    fn test_watermark_validators() {
+        assert!(watermark_opacity_validator("0.5").is_ok());
+        assert!(watermark_opacity_validator("1.5").is_err());
+        assert!(watermark_scale_validator("0.25").is_ok());
+        assert!(watermark_scale_validator("0").is_err());
+    }
+
     #[test]
     fn test_validate_range() {
         // Test with u32
@@ -384,6 +882,83 @@ mod tests {
         assert!(max_size_validator("").is_err());
     }
 
+    #[test]
+    fn test_max_input_size_validator() {
+        assert_eq!(max_input_size_validator("500MiB").unwrap(), 524_288_000);
+        assert_eq!(max_input_size_validator("2GB").unwrap(), 2_000_000_000);
+        assert_eq!(max_input_size_validator("unlimited").unwrap(), u64::MAX);
+        assert_eq!(max_input_size_validator("Unlimited").unwrap(), u64::MAX);
+        assert!(max_input_size_validator("infinite").is_err());
+        assert_eq!(size_validator("4GiB").unwrap(), 4_294_967_296);
+        assert!(size_validator("unlimited").is_err());
+    }
+
+    #[test]
+    fn test_duration_validator() {
+        assert_eq!(duration_validator("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(duration_validator("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(duration_validator("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(duration_validator("1.5m").unwrap(), Duration::from_secs(90));
+        assert_eq!(duration_validator("2h").unwrap(), Duration::from_secs(7200));
+        assert!(duration_validator("0s").is_err());
+        assert!(duration_validator("5d").is_err());
+        assert!(duration_validator("s").is_err());
+        assert!(duration_validator("").is_err());
+        assert!(duration_validator(&format!("1{}", "0".repeat(300))).is_err());
+    }
+
+    #[test]
+    fn test_dimensions_validator() {
+        assert_eq!(
+            dimensions_validator("1920x1080").unwrap(),
+            Dimensions {
+                width: 1920,
+                height: 1080
+            }
+        );
+        assert_eq!(dimensions_validator("800X0").unwrap().height, 0);
+        assert!(dimensions_validator("1920").is_err());
+        assert!(dimensions_validator("1920x").is_err());
+        assert!(dimensions_validator("-1x10").is_err());
+    }
+
+    #[test]
+    fn test_newer_than_validator() {
+        let seconds = |val: &str| {
+            newer_than_validator(val)
+                .unwrap()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+        assert_eq!(seconds("1970-01-01"), 0);
+        assert_eq!(seconds("2024-02-29"), 1_709_164_800);
+        assert_eq!(seconds("2024-05-01 18:30"), 1_714_588_200);
+        assert_eq!(seconds("2024-05-01T18:30:15"), 1_714_588_215);
+        assert!(newer_than_validator("2024-13-01").is_err());
+        assert!(newer_than_validator("2024-02-31").is_err());
+        assert!(newer_than_validator("2023-02-29").is_err());
+        assert!(newer_than_validator("2024-04-31").is_err());
+        assert_eq!(seconds("2000-02-29"), 951_782_400);
+        assert!(newer_than_validator("2024-05-01 25:00").is_err());
+
+        let age = |val: &str| {
+            SystemTime::now()
+                .duration_since(newer_than_validator(val).unwrap())
+                .unwrap()
+                .as_secs()
+        };
+        assert!((86340..86460).contains(&age("1d")));
+        assert!((43140..43260).contains(&age("12h")));
+        assert!(newer_than_validator("0d").is_err());
+        assert!(newer_than_validator("1e300d").is_err());
+        assert!(newer_than_validator("infd").is_err());
+        assert!(newer_than_validator("yesterday").is_err());
+
+        let modified = fs::metadata("samples/p0.png").unwrap().modified().unwrap();
+        assert_eq!(newer_than_validator("samples/p0.png").unwrap(), modified);
+    }
+
     #[test]
     fn test_min_savings_validator() {
         // Test percentage format (integer)
@@ -449,4 +1024,17 @@ mod tests {
         // assert!(min_savings_validator("1B").is_err());
         assert!(min_savings_validator("1 bytes").is_err());
     }
+
+    #[test]
+    fn test_strip_tags_requires_kept_metadata() {
+        let parse = |args: &[&str]| {
+            CommandLineArgs::try_parse_from(["caesiumclt", "-q", "80", "-o", "out", "image.jpg"].iter().chain(args))
+        };
+
+        let error = parse(&["--strip-tags", "gps"]).unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+        assert!(parse(&["--strip-tags", "gps", "-e"]).is_ok());
+        assert!(parse(&["--strip-tags", "gps", "--keep-metadata", "exif"]).is_ok());
+        assert!(parse(&["-e", "--keep-metadata", "exif"]).is_err());
+    }
 }
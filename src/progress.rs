@@ -0,0 +1,279 @@
+use crate::stats::CompressionStats;
+use caesiumclt::{CompressionEvents, CompressionResult};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Progress bar of the whole run, with a spinner for each file being compressed. Files found while scanning make the
+/// bar longer
+pub struct TerminalProgress {
+    multi_progress: MultiProgress,
+    progress_bar: ProgressBar,
+    /// Spinners of the files being compressed, by path as shown in the results
+    spinners: Mutex<HashMap<String, ProgressBar>>,
+    /// Shown while all the files are being found, before compressing them
+    scan_spinner: Mutex<Option<ProgressBar>>,
+}
+
+impl TerminalProgress {
+    pub fn new(multi_progress: MultiProgress, progress_bar: ProgressBar) -> Self {
+        Self {
+            multi_progress,
+            progress_bar,
+            spinners: Mutex::new(HashMap::new()),
+            scan_spinner: Mutex::new(None),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn progress_bar(&self) -> &ProgressBar {
+        &self.progress_bar
+    }
+}
+
+impl CompressionEvents for TerminalProgress {
+    fn scan_started(&self) {
+        let spinner = self
+            .multi_progress
+            .insert(0, init_progress_bar(self.multi_progress.is_hidden()));
+        *self.scan_spinner.lock().unwrap_or_else(PoisonError::into_inner) = Some(spinner);
+    }
+
+    fn scan_finished(&self, _input_files: &[PathBuf]) {
+        if let Some(spinner) = self.scan_spinner.lock().unwrap_or_else(PoisonError::into_inner).take() {
+            spinner.finish_and_clear();
+        }
+    }
+
+    fn file_found(&self, _input_file: &Path) {
+        self.progress_bar.inc_length(1);
+    }
+
+    fn file_started(&self, input_file: &Path) {
+        let spinner = self.multi_progress.add(ProgressBar::new_spinner());
+        spinner.set_style(
+            ProgressStyle::default_spinner()
+                .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
+                .template("{spinner:.cyan} {msg}")
+                .unwrap_or(ProgressStyle::default_spinner()),
+        );
+        spinner.set_message(format!("{}", input_file.display()));
+        spinner.enable_steady_tick(Duration::from_millis(100));
+
+        self.spinners
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(input_file.display().to_string(), spinner);
+    }
+
+    fn file_finished(&self, result: &CompressionResult) {
+        let spinner = self
+            .spinners
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&result.original_path);
+        if let Some(spinner) = spinner {
+            spinner.finish_and_clear();
+        }
+        self.progress_bar.inc(1);
+    }
+
+    fn batch_finished(&self, _results: &[CompressionResult]) {
+        self.progress_bar.finish();
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum JsonEvent<'a> {
+    FileFound {
+        path: String,
+    },
+    FileStarted {
+        path: String,
+    },
+    FileFinished {
+        #[serde(flatten)]
+        result: &'a CompressionResult,
+    },
+    BatchFinished {
+        total_files: usize,
+        success: usize,
+        skipped: usize,
+        errors: usize,
+        original_size: u64,
+        compressed_size: u64,
+    },
+}
+
+/// Writes each event as a line of JSON with an `event` field, so that another program can follow the run
+pub struct JsonEvents {
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonEvents {
+    pub fn new(output: Box<dyn Write + Send>) -> Self {
+        Self {
+            output: Mutex::new(output),
+        }
+    }
+
+    fn write(&self, event: &JsonEvent) {
+        let Ok(line) = serde_json::to_string(event) else {
+            return;
+        };
+        let mut output = self.output.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = writeln!(output, "{line}").and_then(|_| output.flush());
+    }
+}
+
+impl CompressionEvents for JsonEvents {
+    fn file_found(&self, input_file: &Path) {
+        self.write(&JsonEvent::FileFound {
+            path: input_file.display().to_string(),
+        });
+    }
+
+    fn file_started(&self, input_file: &Path) {
+        self.write(&JsonEvent::FileStarted {
+            path: input_file.display().to_string(),
+        });
+    }
+
+    fn file_finished(&self, result: &CompressionResult) {
+        self.write(&JsonEvent::FileFinished { result });
+    }
+
+    fn batch_finished(&self, results: &[CompressionResult]) {
+        let stats = CompressionStats::from_results(results);
+        self.write(&JsonEvent::BatchFinished {
+            total_files: results.len(),
+            success: stats.success,
+            skipped: stats.skipped,
+            errors: stats.errors,
+            original_size: stats.total_original_size,
+            compressed_size: stats.total_compressed_size,
+        });
+    }
+}
+
+/// Spinner shown while the files are being collected, hidden when `quiet`
+pub fn init_progress_bar(quiet: bool) -> ProgressBar {
+    let progress_bar = ProgressBar::new_spinner();
+    if quiet {
+        progress_bar.set_draw_target(ProgressDrawTarget::hidden());
+        return progress_bar;
+    }
+    let style = ProgressStyle::default_spinner()
+        .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
+        .template("{spinner:.cyan} {msg}")
+        .unwrap_or_else(|_| ProgressStyle::default_spinner());
+
+    progress_bar.set_message("Collecting files...");
+    progress_bar.enable_steady_tick(Duration::from_millis(100));
+    progress_bar.set_style(style);
+
+    progress_bar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::result;
+    use caesiumclt::CompressionStatus;
+    use std::io;
+    use std::sync::Arc;
+
+    /// Output shared with the test, to read back what was written
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_events() {
+        let output = SharedOutput::default();
+        let events = JsonEvents::new(Box::new(output.clone()));
+        let results = vec![
+            result("a.jpg", CompressionStatus::Success),
+            result("b.jpg", CompressionStatus::Error),
+        ];
+
+        events.file_found(Path::new("a.jpg"));
+        events.file_started(Path::new("a.jpg"));
+        events.file_finished(&results[0]);
+        events.batch_finished(&results);
+
+        let content = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["event"], "file_found");
+        assert_eq!(lines[1]["event"], "file_started");
+        assert_eq!(lines[1]["path"], "a.jpg");
+        assert_eq!(lines[2]["event"], "file_finished");
+        assert_eq!(lines[2]["original_path"], "a.jpg");
+        assert_eq!(lines[2]["status"], "success");
+        assert_eq!(lines[3]["event"], "batch_finished");
+        assert_eq!(lines[3]["total_files"], 2);
+        assert_eq!(lines[3]["errors"], 1);
+        assert_eq!(lines[3]["compressed_size"], 1200);
+    }
+
+    #[test]
+    fn test_terminal_progress() {
+        let multi_progress = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let progress_bar = multi_progress.add(ProgressBar::new(0));
+        let progress = TerminalProgress::new(multi_progress, progress_bar);
+
+        // Combined with other receivers, every one gets the events
+        let output = SharedOutput::default();
+        let json_events = JsonEvents::new(Box::new(output.clone()));
+        let events = (&progress, Some(&json_events));
+        events.scan_started();
+        assert!(progress.scan_spinner.lock().unwrap().is_some());
+        events.scan_finished(&[PathBuf::from("a.jpg"), PathBuf::from("b.jpg")]);
+        assert!(progress.scan_spinner.lock().unwrap().is_none());
+        events.file_found(Path::new("a.jpg"));
+        events.file_found(Path::new("b.jpg"));
+        events.file_started(Path::new("a.jpg"));
+        assert_eq!(progress.spinners.lock().unwrap().len(), 1);
+        events.file_finished(&result("a.jpg", CompressionStatus::Success));
+        assert!(progress.spinners.lock().unwrap().is_empty());
+
+        assert_eq!(progress.progress_bar().length(), Some(2));
+        assert_eq!(progress.progress_bar().position(), 1);
+        assert_eq!(
+            String::from_utf8(output.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .count(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_init_progress_bar() {
+        // Test with quiet = true
+        let progress_bar = init_progress_bar(true);
+        assert!(progress_bar.is_hidden());
+
+        // Test with quiet = false
+        // let progress_bar = init_progress_bar(false);
+        // assert_eq!(progress_bar.is_hidden(), false);
+    }
+}
@@ -0,0 +1,124 @@
+use crate::options::ReportFormat;
+use caesiumclt::{CompressionEvents, CompressionResult};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+const CSV_HEADER: &str = "original_path,output_path,original_size,compressed_size,status,error_code,message,cause";
+
+/// Destination of the machine-readable report, shared by the compression threads
+pub struct Report {
+    pub format: ReportFormat,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Report {
+    /// Writes to the file at `path`, or to stdout if not set
+    pub fn new(format: ReportFormat, path: Option<&Path>) -> io::Result<Self> {
+        let output: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stdout()),
+        };
+
+        Ok(Self {
+            format,
+            output: Mutex::new(output),
+        })
+    }
+
+    /// Writes the text followed by a new line, flushing so that readers see it right away
+    pub fn write(&self, text: &str) {
+        if let Ok(mut output) = self.output.lock() {
+            let _ = writeln!(output, "{text}").and_then(|_| output.flush());
+        }
+    }
+}
+
+impl CompressionEvents for Report {
+    /// NDJSON reports get a line as soon as a file is done, so they can be followed during long runs
+    fn file_finished(&self, result: &CompressionResult) {
+        if self.format != ReportFormat::Ndjson {
+            return;
+        }
+        if let Ok(line) = serde_json::to_string(result) {
+            self.write(&line);
+        }
+    }
+}
+
+/// One row per file, in the order of the results
+pub fn build_csv_report(compression_results: &[CompressionResult]) -> String {
+    let mut lines = vec![CSV_HEADER.to_string()];
+    for result in compression_results {
+        lines.push(format!(
+            "{},{},{},{},{},{},{},{}",
+            escape_csv(&result.original_path),
+            escape_csv(&result.output_path),
+            result.original_size,
+            result.compressed_size,
+            result.status.to_string().to_lowercase(),
+            result.error_code.map(|code| code.to_string()).unwrap_or_default(),
+            escape_csv(&result.message),
+            escape_csv(result.cause.as_deref().unwrap_or_default()),
+        ));
+    }
+
+    lines.join("\n")
+}
+
+/// Quotes the field if it contains a separator, a quote or a line break, doubling the quotes
+fn escape_csv(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::result;
+    use caesiumclt::{CompressionStatus, ErrorCode};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_build_csv_report() {
+        let mut error = result("b, \"c\".jpg", CompressionStatus::Error);
+        error.message = "Error reading input file".to_string();
+        error.error_code = Some(ErrorCode::PermissionDenied);
+        error.cause = Some("Permission denied (os error 13)".to_string());
+        let results = vec![result("a.jpg", CompressionStatus::Success), error];
+
+        let csv = build_csv_report(&results);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(lines[1], "a.jpg,out/a.jpg,1000,600,success,,,");
+        assert_eq!(
+            lines[2],
+            "\"b, \"\"c\"\".jpg\",out/a.jpg,1000,600,error,permission_denied,Error reading input file,\
+             Permission denied (os error 13)"
+        );
+        assert_eq!(build_csv_report(&[]), CSV_HEADER);
+    }
+
+    #[test]
+    fn test_ndjson_report_streams_files() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("report.ndjson");
+        let report = Report::new(ReportFormat::Ndjson, Some(&path)).unwrap();
+
+        report.file_finished(&result("a.jpg", CompressionStatus::Success));
+        let content = std::fs::read_to_string(&path).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(content.lines().next().unwrap()).unwrap();
+        assert_eq!(parsed["original_path"], "a.jpg");
+        assert_eq!(parsed["status"], "success");
+
+        let report = Report::new(ReportFormat::Csv, Some(&path)).unwrap();
+        report.file_finished(&result("a.jpg", CompressionStatus::Success));
+        assert!(std::fs::read_to_string(&path).unwrap().is_empty());
+    }
+}
@@ -1,9 +1,19 @@
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::hash::{BuildHasher, RandomState};
 use std::path::{absolute, Path, PathBuf};
-use std::time::Duration;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
 
-use indicatif::ProgressStyle;
-use indicatif::{ProgressBar, ProgressDrawTarget, ProgressIterator};
-use walkdir::WalkDir;
+use clap::ValueEnum;
+use ignore::{WalkBuilder, WalkParallel, WalkState};
+
+use crate::events::CompressionEvents;
+
+/// Ignore file of caesiumclt, read along with the `.gitignore` and `.ignore` files and taking precedence over them
+const CUSTOM_IGNORE_FILE_NAME: &str = ".caesiumignore";
 
 fn has_supported_extension(path: &Path) -> bool {
     match path.extension().and_then(|e| e.to_str()) {
@@ -47,48 +57,284 @@ fn is_valid_file(path: &Path, check_extension_only: bool) -> bool {
     is_filetype_supported(path)
 }
 
+/// Size of an image in pixels, given as WIDTHxHEIGHT
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Dimensions {
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug, Default)]
+pub enum SortKey {
+    /// By path
+    #[default]
+    Path,
+    /// By file name, then by path
+    Name,
+    /// Smallest files first
+    Size,
+    /// Least recently modified files first
+    Mtime,
+}
+
+/// Filters on the size, dimensions and modification time of the files, unset bounds matching every file
+#[derive(Default)]
+pub struct FileFilters {
+    pub min_file_size: Option<u64>,
+    pub max_file_size: Option<u64>,
+    pub min_dimensions: Option<Dimensions>,
+    pub max_dimensions: Option<Dimensions>,
+    pub newer_than: Option<SystemTime>,
+}
+
+impl FileFilters {
+    /// Dimensions are read from the image header, only when filtering on them
+    fn matches(&self, path: &Path) -> bool {
+        let Ok(metadata) = fs::metadata(path) else {
+            return false;
+        };
+        if self.min_file_size.is_some_and(|size| metadata.len() < size)
+            || self.max_file_size.is_some_and(|size| metadata.len() > size)
+        {
+            return false;
+        }
+        if let Some(newer_than) = self.newer_than {
+            if metadata.modified().map_or(true, |modified| modified <= newer_than) {
+                return false;
+            }
+        }
+
+        if self.min_dimensions.is_none() && self.max_dimensions.is_none() {
+            return true;
+        }
+        let Ok(size) = imagesize::size(path) else {
+            return false;
+        };
+        let fits = |min: Dimensions, max: Dimensions| {
+            (min.width..=max.width).contains(&size.width) && (min.height..=max.height).contains(&size.height)
+        };
+        fits(
+            self.min_dimensions.unwrap_or(Dimensions { width: 0, height: 0 }),
+            self.max_dimensions.unwrap_or(Dimensions {
+                width: usize::MAX,
+                height: usize::MAX,
+            }),
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct ScanOptions {
+    pub recursive: bool,
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: bool,
+    pub skip_hidden: bool,
+    pub respect_ignore: bool,
+    pub check_extension_only: bool,
+    pub filters: FileFilters,
+    pub sort_key: SortKey,
+    /// Compresses only this many files, picked at random among the files found
+    pub sample: Option<usize>,
+}
+
+/// Finds all the supported files, sorted by the sort key, reporting the start and the end of the scan to `events`
 pub fn scan_files(
     args: &[String],
-    recursive: bool,
-    quiet: bool,
-    check_extension_only: bool,
+    options: &ScanOptions,
+    events: &dyn CompressionEvents,
 ) -> (Option<PathBuf>, Vec<PathBuf>) {
     if args.is_empty() {
         return (None, vec![]);
     }
+    events.scan_started();
+
+    // Canonical paths are resolved by the scan threads, and only when a file can be found twice
+    let deduplicates = inputs_can_overlap(args, options);
+    let found_files = Mutex::new(vec![]);
+    find_files(args, options, |input_index, path| {
+        let canonical_path = deduplicates.then(|| canonical_path(&path));
+        found_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((input_index, path, canonical_path));
+    });
+    let mut found_files = found_files.into_inner().unwrap_or_else(PoisonError::into_inner);
+    // Sorted so that the path kept for a file reachable from several inputs does not depend on the scan timing
+    found_files.sort_unstable();
+
+    // Canonical paths of the files kept so far, so that overlapping inputs do not add a file twice
+    let mut seen_files: HashSet<PathBuf> = HashSet::new();
     let mut files: Vec<PathBuf> = vec![];
+    for (_, path, canonical_path) in found_files {
+        if canonical_path.is_none_or(|canonical_path| seen_files.insert(canonical_path)) {
+            files.push(path);
+        }
+    }
+    sort_files(&mut files, options.sort_key);
+    events.scan_finished(&files);
+    let base_path = input_base_path(args).filter(|_| !files.is_empty());
+    (base_path, files)
+}
+
+/// Sends each supported file once to `sender` with its sort rank, as soon as it is found and in no particular order, so
+/// that it can be compressed while the scan goes on. The scan runs on its own threads, which wait while the channel is
+/// full
+pub(crate) fn stream_files(args: &[String], options: &ScanOptions, sender: SyncSender<(PathBuf, SortRank)>) {
+    // Only remembered when a file can be found twice, as the list grows with every file found
+    let seen_files: Option<Mutex<HashSet<PathBuf>>> =
+        inputs_can_overlap(args, options).then(|| Mutex::new(HashSet::new()));
+    find_files(args, options, |_, path| {
+        let is_new_file = seen_files.as_ref().is_none_or(|seen_files| {
+            let canonical_path = canonical_path(&path);
+            seen_files
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(canonical_path)
+        });
+        // The receiver only stops listening when the run is over
+        if is_new_file {
+            let _ = sender.send((path.clone(), SortRank::of(&path, options.sort_key)));
+        }
+    });
+}
+
+/// Picks `count` files at random, keeping their order
+pub(crate) fn sample_files(files: &[PathBuf], count: usize) -> Vec<PathBuf> {
+    if count >= files.len() {
+        return files.to_vec();
+    }
+
+    // Partial Fisher-Yates shuffle, using the randomly seeded hasher of the standard library as the random source
+    let random_state = RandomState::new();
+    let mut indices: Vec<usize> = (0..files.len()).collect();
+    for i in 0..count {
+        let j = i + (random_state.hash_one(i) as usize) % (files.len() - i);
+        indices.swap(i, j);
+    }
+    let mut picked = indices[..count].to_vec();
+    picked.sort_unstable();
+
+    picked.into_iter().map(|index| files[index].clone()).collect()
+}
+
+/// Folder holding all the inputs, the base path of the files whatever the order they are found in, so that
+/// `stream_files` does not need to wait for all the files. Missing inputs are left out
+pub(crate) fn input_base_path(args: &[String]) -> Option<PathBuf> {
     let mut base_path: Option<PathBuf> = None;
-    let progress_bar = init_progress_bar(quiet);
-
-    for path in args.iter().progress_with(progress_bar) {
-        let input = PathBuf::from(path);
-        if input.exists() && input.is_dir() {
-            let mut walk_dir = WalkDir::new(&input).follow_links(false);
-            if !recursive {
-                walk_dir = walk_dir.max_depth(1);
-            }
-            for entry in walk_dir.into_iter().filter_map(|e| e.ok()) {
-                if entry.file_type().is_file() {
-                    let path = entry.into_path();
-                    if is_valid_file(&path, check_extension_only) {
-                        base_path = match compute_base_path(&path, base_path.clone()) {
-                            Some(p) => Some(p),
-                            None => continue,
-                        };
-                        files.push(path);
+    for arg in args {
+        let path = Path::new(arg);
+        base_path = match base_path {
+            None if path.is_dir() => absolute(path).ok(),
+            base_path => compute_base_path(path, base_path.clone()).or(base_path),
+        };
+    }
+
+    base_path
+}
+
+/// Walks the inputs one after the other, listing the folders and checking the files on the threads of the walker, and
+/// calls `on_file` from these threads with the index of the input and the path of each supported file as soon as it
+/// is found. A file reachable from several inputs is reported for each of them
+pub(crate) fn find_files(args: &[String], options: &ScanOptions, on_file: impl Fn(usize, PathBuf) + Sync) {
+    let check_file = |input_index: usize, path: PathBuf| {
+        if is_valid_file(&path, options.check_extension_only) && options.filters.matches(&path) {
+            on_file(input_index, path);
+        }
+    };
+
+    for (input_index, arg) in args.iter().enumerate() {
+        let input = Path::new(arg);
+        if input.is_dir() {
+            build_folder_walker(input, options).run(|| {
+                let check_file = &check_file;
+                Box::new(move |entry| {
+                    if let Ok(entry) = entry {
+                        if entry.file_type().is_some_and(|file_type| file_type.is_file()) {
+                            check_file(input_index, entry.into_path());
+                        }
                     }
-                }
-            }
-        } else if input.is_file() && is_valid_file(&input, check_extension_only) {
-            base_path = match compute_base_path(&input, base_path.clone()) {
-                Some(p) => Some(p),
-                None => continue,
-            };
-            files.push(input);
+                    WalkState::Continue
+                })
+            });
+        } else if input.is_file() {
+            check_file(input_index, input.to_path_buf());
         }
     }
+}
 
-    (base_path, files)
+/// Folders that cannot be read, broken links and links looping back to a parent folder are skipped. The ignore files
+/// are read in every folder, `.caesiumignore` taking precedence over `.ignore`, which takes
+/// precedence over `.gitignore`
+fn build_folder_walker(input_folder: &Path, options: &ScanOptions) -> WalkParallel {
+    let mut builder = WalkBuilder::new(input_folder);
+    builder
+        .standard_filters(false)
+        .hidden(options.skip_hidden)
+        .follow_links(options.follow_symlinks)
+        .max_depth(options.max_depth.or((!options.recursive).then_some(1)))
+        .threads(rayon::current_num_threads());
+    if options.respect_ignore {
+        builder
+            .parents(true)
+            .git_ignore(true)
+            .git_exclude(true)
+            .git_global(true)
+            .ignore(true)
+            .require_git(false)
+            .add_custom_ignore_filename(CUSTOM_IGNORE_FILE_NAME)
+            .filter_entry(|entry| entry.file_name() != ".git");
+    }
+
+    builder.build_parallel()
+}
+
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Whether a file can be found more than once, through the links when they are followed or through inputs holding
+/// one another. Otherwise every path found is a different file and there is no need to remember them
+fn inputs_can_overlap(args: &[String], options: &ScanOptions) -> bool {
+    if options.follow_symlinks {
+        return true;
+    }
+
+    let inputs: Vec<PathBuf> = args.iter().map(|arg| canonical_path(Path::new(arg))).collect();
+    inputs.iter().enumerate().any(|(index, input)| {
+        inputs[index + 1..]
+            .iter()
+            .any(|other| input.starts_with(other) || other.starts_with(input))
+    })
+}
+
+/// Sorts the files so that the results do not depend on the order in which the filesystem lists them
+fn sort_files(files: &mut [PathBuf], sort_key: SortKey) {
+    files.sort_by_cached_key(|path| SortRank::of(path, sort_key));
+}
+
+/// Where a file goes among the sorted files, read when the file is found so that compressing it in place, which
+/// changes its size and modification time, does not move it
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) enum SortRank {
+    Path(PathBuf),
+    Name(Option<OsString>, PathBuf),
+    Size(u64, PathBuf),
+    Mtime(Option<SystemTime>, PathBuf),
+}
+
+impl SortRank {
+    pub fn of(path: &Path, sort_key: SortKey) -> Self {
+        let path_buf = path.to_path_buf();
+        match sort_key {
+            SortKey::Path => SortRank::Path(path_buf),
+            SortKey::Name => SortRank::Name(path.file_name().map(OsStr::to_os_string), path_buf),
+            SortKey::Size => SortRank::Size(fs::metadata(path).map_or(0, |metadata| metadata.len()), path_buf),
+            SortKey::Mtime => SortRank::Mtime(
+                fs::metadata(path).and_then(|metadata| metadata.modified()).ok(),
+                path_buf,
+            ),
+        }
+    }
 }
 
 fn compute_base_path(path: &Path, base_path: Option<PathBuf>) -> Option<PathBuf> {
@@ -142,33 +388,31 @@ fn compute_base_folder(bf: Option<PathBuf>, new_path: &Path) -> Option<PathBuf>
     Some(folder)
 }
 
-fn init_progress_bar(quiet: bool) -> ProgressBar {
-    let progress_bar = ProgressBar::new_spinner();
-    if quiet {
-        progress_bar.set_draw_target(ProgressDrawTarget::hidden());
-        return progress_bar;
-    }
-    let style = ProgressStyle::default_spinner()
-        .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
-        .template("{spinner:.cyan} {msg}")
-        .unwrap_or_else(|_| ProgressStyle::default_spinner());
-
-    progress_bar.set_message("Collecting files...");
-    progress_bar.enable_steady_tick(Duration::from_millis(100));
-    progress_bar.set_style(style);
-
-    progress_bar
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::events::SilentEvents;
     use image::RgbImage;
     use std::fs::File;
     use std::io::{Cursor, Write};
     use std::path::Path;
+    use std::time::Duration;
     use tempfile::NamedTempFile;
 
+    fn scan_options(recursive: bool, check_extension_only: bool) -> ScanOptions {
+        ScanOptions {
+            recursive,
+            max_depth: None,
+            follow_symlinks: false,
+            skip_hidden: false,
+            respect_ignore: false,
+            check_extension_only,
+            filters: FileFilters::default(),
+            sort_key: SortKey::Path,
+            sample: None,
+        }
+    }
+
     #[test]
     fn test_has_supported_extension() {
         assert!(has_supported_extension(Path::new("test.jpg")));
@@ -322,6 +566,19 @@ mod tests {
         assert!(result.is_none());
     }
 
+    #[test]
+    fn test_sample_files() {
+        let files: Vec<PathBuf> = (0..20).map(|i| PathBuf::from(format!("{i:02}.jpg"))).collect();
+
+        let sampled = sample_files(&files, 5);
+        assert_eq!(sampled.len(), 5);
+        assert!(sampled.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(sampled.iter().all(|file| files.contains(file)));
+
+        assert_eq!(sample_files(&files, 20), files);
+        assert_eq!(sample_files(&files, 50), files);
+    }
+
     #[test]
     fn test_scan_files() {
         // Create a temporary directory with some image files
@@ -363,43 +620,345 @@ mod tests {
 
         // Test with recursive = false, quiet = true, check_extension_only = false
         let args = vec![temp_path.to_string_lossy().to_string()];
-        let (base_path, files) = scan_files(&args, false, true, false);
+        let (base_path, files) = scan_files(&args, &scan_options(false, false), &SilentEvents);
         assert!(!base_path.unwrap().as_os_str().is_empty());
         assert_eq!(files.len(), 3); // Should find 3 image files (jpg, png, and the extensionless one)
 
         // Test with recursive = false, quiet = true, check_extension_only = true
         let args = vec![temp_path.to_string_lossy().to_string()];
-        let (base_path, files) = scan_files(&args, false, true, true);
+        let (base_path, files) = scan_files(&args, &scan_options(false, true), &SilentEvents);
         assert!(!base_path.unwrap().as_os_str().is_empty());
         assert_eq!(files.len(), 2); // Should find ONLY the 2 files with extensions
 
         // Test with empty args
         let args: Vec<String> = vec![];
-        let (base_path, files) = scan_files(&args, false, true, false);
+        let (base_path, files) = scan_files(&args, &scan_options(false, false), &SilentEvents);
         assert!(base_path.is_none());
         assert_eq!(files.len(), 0);
 
         // Test with a non-existent path
         let args = vec!["/non/existent/path".to_string()];
-        let (base_path, files) = scan_files(&args, false, true, false);
+        let (base_path, files) = scan_files(&args, &scan_options(false, false), &SilentEvents);
         assert!(base_path.is_none());
         assert_eq!(files.len(), 0);
 
         // Test with a file path directly
         let args = vec![jpeg_path.to_string_lossy().to_string()];
-        let (base_path, files) = scan_files(&args, false, true, false);
+        let (base_path, files) = scan_files(&args, &scan_options(false, false), &SilentEvents);
         assert!(!base_path.unwrap().as_os_str().is_empty());
         assert_eq!(files.len(), 1);
     }
 
     #[test]
-    fn test_init_progress_bar() {
-        // Test with quiet = true
-        let progress_bar = init_progress_bar(true);
-        assert!(progress_bar.is_hidden());
-
-        // Test with quiet = false
-        // let progress_bar = init_progress_bar(false);
-        // assert_eq!(progress_bar.is_hidden(), false);
+    fn test_sort_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = ["b/a.jpg", "a/c.jpg", "c/b.jpg"]
+            .iter()
+            .map(|path| temp_dir.path().join(path))
+            .collect();
+        for (path, size) in paths.iter().zip([30, 10, 20]) {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, vec![0; size]).unwrap();
+        }
+
+        let sorted = |sort_key| {
+            let mut files = paths.clone();
+            sort_files(&mut files, sort_key);
+            files
+        };
+        assert_eq!(
+            sorted(SortKey::Path),
+            vec![paths[1].clone(), paths[0].clone(), paths[2].clone()]
+        );
+        assert_eq!(
+            sorted(SortKey::Name),
+            vec![paths[0].clone(), paths[2].clone(), paths[1].clone()]
+        );
+        assert_eq!(
+            sorted(SortKey::Size),
+            vec![paths[1].clone(), paths[2].clone(), paths[0].clone()]
+        );
+        assert_eq!(sorted(SortKey::Mtime).len(), 3);
+
+        // Overlapping inputs add each file once
+        let args = vec![
+            "samples".to_string(),
+            "samples/p0.png".to_string(),
+            "./samples".to_string(),
+        ];
+        let (_, files) = scan_files(&args, &scan_options(false, false), &SilentEvents);
+        let (_, expected_files) = scan_files(&args[..1], &scan_options(false, false), &SilentEvents);
+        assert_eq!(files, expected_files);
+
+        // Files listed by the filesystem come out sorted
+        let args = vec!["samples".to_string()];
+        let (_, files) = scan_files(&args, &scan_options(true, false), &SilentEvents);
+        assert!(!files.is_empty());
+        assert!(files.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_find_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        for path in ["a.png", "b/c.png", "b/d/e.png", "b/f.txt"] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::copy("samples/p0.png", path).unwrap();
+        }
+        let args = vec![
+            root.to_string_lossy().to_string(),
+            root.join("b/c.png").to_string_lossy().to_string(),
+            root.join("missing.png").to_string_lossy().to_string(),
+        ];
+
+        let found_files = Mutex::new(vec![]);
+        find_files(&args, &scan_options(true, true), |input_index, path| {
+            found_files.lock().unwrap().push((input_index, path));
+        });
+        let mut found_files = found_files.into_inner().unwrap();
+        found_files.sort();
+        assert_eq!(
+            found_files,
+            vec![
+                (0, root.join("a.png")),
+                (0, root.join("b/c.png")),
+                (0, root.join("b/d/e.png")),
+                (1, root.join("b/c.png")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inputs_can_overlap() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::create_dir_all(root.join("c")).unwrap();
+        let arg = |path: &str| root.join(path).to_string_lossy().to_string();
+
+        let mut options = scan_options(true, false);
+        assert!(!inputs_can_overlap(&[arg("a")], &options));
+        assert!(!inputs_can_overlap(&[arg("a"), arg("c")], &options));
+        assert!(!inputs_can_overlap(&[arg("a/b"), arg("a/bc.png")], &options));
+        assert!(inputs_can_overlap(&[arg("a"), arg("c"), arg("a/b")], &options));
+        assert!(inputs_can_overlap(&[arg("c"), arg("c/../c")], &options));
+        assert!(inputs_can_overlap(&[arg("a/b/x.png"), arg("a")], &options));
+
+        options.follow_symlinks = true;
+        assert!(inputs_can_overlap(&[arg("a")], &options));
+
+        fs::copy("samples/p0.png", root.join("a/b/x.png")).unwrap();
+        let (_, files) = scan_files(&[arg("a/b/x.png"), arg("a")], &scan_options(true, false), &SilentEvents);
+        assert_eq!(files, vec![root.join("a/b/x.png")]);
+    }
+
+    #[test]
+    fn test_stream_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        for path in ["a.png", "b/c.png"] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::copy("samples/p0.png", path).unwrap();
+        }
+        let args = vec![
+            root.to_string_lossy().to_string(),
+            root.join("b").to_string_lossy().to_string(),
+        ];
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(args.len() * 2);
+        stream_files(&args, &scan_options(true, false), sender);
+        let mut files: Vec<PathBuf> = receiver.into_iter().map(|(path, _)| path).collect();
+        files.sort();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0], root.join("a.png"));
+        assert!(files[1].ends_with("b/c.png"));
+
+        assert_eq!(input_base_path(&args), Some(root.clone()));
+        // Same base path when all the files are known first, even if they are all in a subfolder of the input
+        let parent_args = vec![temp_dir.path().to_string_lossy().to_string()];
+        let (base_path, _) = scan_files(&parent_args, &scan_options(true, false), &SilentEvents);
+        assert_eq!(base_path, input_base_path(&parent_args));
+        assert_eq!(base_path, Some(temp_dir.path().to_path_buf()));
+        let args = vec![
+            root.join("b/c.png").to_string_lossy().to_string(),
+            root.join("missing.png").to_string_lossy().to_string(),
+        ];
+        assert_eq!(input_base_path(&args), Some(root.join("b")));
+        assert_eq!(input_base_path(&args[1..]), None);
+    }
+
+    #[test]
+    fn test_scan_depth_and_hidden_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        for path in ["a.png", ".b.png", "one/c.png", "one/two/d.png", ".thumbnails/e.png"] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::copy("samples/p0.png", path).unwrap();
+        }
+        let args = vec![root.to_string_lossy().to_string()];
+        let names = |options: &ScanOptions| -> Vec<String> {
+            let (_, files) = scan_files(&args, options, &SilentEvents);
+            files
+                .iter()
+                .map(|file| file.file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+
+        let mut options = scan_options(false, false);
+        assert_eq!(names(&options), vec![".b.png", "a.png"]);
+        options.recursive = true;
+        assert_eq!(names(&options), vec![".b.png", "e.png", "a.png", "c.png", "d.png"]);
+        options.max_depth = Some(2);
+        assert_eq!(names(&options), vec![".b.png", "e.png", "a.png", "c.png"]);
+        options.recursive = false;
+        assert_eq!(names(&options), vec![".b.png", "e.png", "a.png", "c.png"]);
+        options.skip_hidden = true;
+        assert_eq!(names(&options), vec!["a.png", "c.png"]);
+
+        // Hidden inputs are scanned when given explicitly
+        let args = vec![root.join(".thumbnails").to_string_lossy().to_string()];
+        let (_, files) = scan_files(&args, &options, &SilentEvents);
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn test_scan_respect_ignore() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        for path in ["a.png", "b.tmp.png", "build/c.png", "icons/d.png", "icons/e.png"] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::copy("samples/p0.png", path).unwrap();
+        }
+        fs::write(root.join(".gitignore"), "build/\n*.tmp.png\n").unwrap();
+        fs::write(root.join("icons/.caesiumignore"), "d.png\n").unwrap();
+        let args = vec![root.to_string_lossy().to_string()];
+
+        let mut options = scan_options(true, false);
+        let (_, files) = scan_files(&args, &options, &SilentEvents);
+        assert_eq!(files.len(), 5);
+
+        options.respect_ignore = true;
+        let (_, files) = scan_files(&args, &options, &SilentEvents);
+        assert_eq!(files, vec![root.join("a.png"), root.join("icons/e.png")]);
+
+        // Files given explicitly are scanned even if ignored
+        let args = vec![root.join("b.tmp.png").to_string_lossy().to_string()];
+        let (_, files) = scan_files(&args, &options, &SilentEvents);
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn test_scan_respect_ignore_in_repository() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let repository = temp_dir.path().join("repository");
+        let assets = repository.join("assets");
+        for path in [
+            "a.png",
+            "a.tmp.png",
+            "keep.tmp.png",
+            "vendor/b.png",
+            "icons/a.local.png",
+            "icons/c.png",
+        ] {
+            let path = assets.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::copy("samples/p0.png", path).unwrap();
+        }
+        fs::create_dir_all(repository.join(".git/info")).unwrap();
+        fs::copy("samples/p0.png", repository.join(".git/d.png")).unwrap();
+        // The ignore files of the parent folders apply too, the later ones taking precedence
+        fs::write(repository.join(".gitignore"), "assets/vendor/\n*.tmp.png\n").unwrap();
+        fs::write(repository.join(".git/info/exclude"), "*.local.png\n").unwrap();
+        fs::write(assets.join(".ignore"), "!keep.tmp.png\n").unwrap();
+        fs::write(assets.join("icons/.caesiumignore"), "/c.png\n").unwrap();
+
+        let mut options = scan_options(true, false);
+        options.respect_ignore = true;
+        let (_, files) = scan_files(&[assets.to_string_lossy().to_string()], &options, &SilentEvents);
+        assert_eq!(files, vec![assets.join("a.png"), assets.join("keep.tmp.png")]);
+        let (_, files) = scan_files(&[repository.to_string_lossy().to_string()], &options, &SilentEvents);
+        assert_eq!(files, vec![assets.join("a.png"), assets.join("keep.tmp.png")]);
+    }
+
+    #[test]
+    fn test_scan_filters() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let small = temp_dir.path().join("small.png");
+        let large = temp_dir.path().join("large.png");
+        RgbImage::new(10, 20).save(&small).unwrap();
+        RgbImage::from_fn(400, 300, |x, y| image::Rgb([(x * y) as u8, x as u8, y as u8]))
+            .save(&large)
+            .unwrap();
+        let small_size = fs::metadata(&small).unwrap().len();
+        let args = vec![temp_dir.path().to_string_lossy().to_string()];
+        let scan = |filters: FileFilters| {
+            let mut options = scan_options(false, false);
+            options.filters = filters;
+            scan_files(&args, &options, &SilentEvents).1
+        };
+
+        assert_eq!(scan(FileFilters::default()).len(), 2);
+        let min_file_size = FileFilters {
+            min_file_size: Some(small_size + 1),
+            ..Default::default()
+        };
+        assert_eq!(scan(min_file_size), vec![large.clone()]);
+        let max_file_size = FileFilters {
+            max_file_size: Some(small_size),
+            ..Default::default()
+        };
+        assert_eq!(scan(max_file_size), vec![small.clone()]);
+        let min_dimensions = FileFilters {
+            min_dimensions: Some(Dimensions { width: 10, height: 21 }),
+            ..Default::default()
+        };
+        assert_eq!(scan(min_dimensions), vec![large.clone()]);
+        let max_dimensions = FileFilters {
+            max_dimensions: Some(Dimensions {
+                width: 400,
+                height: 299,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(scan(max_dimensions), vec![small.clone()]);
+
+        let newer_than = FileFilters {
+            newer_than: Some(SystemTime::now() + Duration::from_secs(60)),
+            ..Default::default()
+        };
+        assert!(scan(newer_than).is_empty());
+        let newer_than = FileFilters {
+            newer_than: Some(SystemTime::now() - Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        assert_eq!(scan(newer_than).len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_follow_symlinks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        let shared = temp_dir.path().join("shared");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir_all(&shared).unwrap();
+        fs::copy("samples/p0.png", root.join("a.png")).unwrap();
+        fs::copy("samples/p0.png", shared.join("b.png")).unwrap();
+        std::os::unix::fs::symlink(&shared, root.join("shared")).unwrap();
+        // Loops back to the root
+        std::os::unix::fs::symlink(&root, root.join("sub/loop")).unwrap();
+
+        let args = vec![root.to_string_lossy().to_string()];
+        let mut options = scan_options(true, false);
+        let (_, files) = scan_files(&args, &options, &SilentEvents);
+        assert_eq!(files, vec![root.join("a.png")]);
+
+        options.follow_symlinks = true;
+        let (_, files) = scan_files(&args, &options, &SilentEvents);
+        assert_eq!(files, vec![root.join("a.png"), root.join("shared/b.png")]);
     }
 }
@@ -0,0 +1,131 @@
+use caesiumclt::{CompressionResult, CompressionStatus};
+
+/// Totals of a compression run, shared by the recap, the summaries and the reports
+pub struct CompressionStats {
+    pub total_original_size: u64,
+    pub total_compressed_size: u64,
+    pub success: usize,
+    pub skipped: usize,
+    pub errors: usize,
+}
+
+impl CompressionStats {
+    pub fn from_results(results: &[CompressionResult]) -> Self {
+        let (total_original_size, total_compressed_size, success, skipped, errors) = results.iter().fold(
+            (0u64, 0u64, 0usize, 0usize, 0usize),
+            |(orig, comp, success, skipped, errors), result| {
+                let (new_success, new_skipped, new_errors) = match result.status {
+                    CompressionStatus::Success => (success + 1, skipped, errors),
+                    CompressionStatus::Skipped => (success, skipped + 1, errors),
+                    CompressionStatus::Error => (success, skipped, errors + 1),
+                };
+                (
+                    orig + result.original_size,
+                    comp + result.compressed_size,
+                    new_success,
+                    new_skipped,
+                    new_errors,
+                )
+            },
+        );
+        Self {
+            total_original_size,
+            total_compressed_size,
+            success,
+            skipped,
+            errors,
+        }
+    }
+
+    pub fn savings_bytes(&self) -> i64 {
+        self.total_original_size as i64 - self.total_compressed_size as i64
+    }
+
+    pub fn savings_percent(&self) -> f64 {
+        if self.total_original_size > 0 {
+            (self.savings_bytes() as f64 / self.total_original_size as f64) * 100.0
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_stats_from_results() {
+        let results = vec![
+            CompressionResult {
+                original_path: "a.jpg".to_string(),
+                output_path: "a_out.jpg".to_string(),
+                original_size: 1000,
+                compressed_size: 800,
+                status: CompressionStatus::Success,
+                message: "".to_string(),
+                error_code: None,
+                cause: None,
+            },
+            CompressionResult {
+                original_path: "b.jpg".to_string(),
+                output_path: "b_out.jpg".to_string(),
+                original_size: 2000,
+                compressed_size: 2000,
+                status: CompressionStatus::Skipped,
+                message: "".to_string(),
+                error_code: None,
+                cause: None,
+            },
+            CompressionResult {
+                original_path: "c.jpg".to_string(),
+                output_path: "c_out.jpg".to_string(),
+                original_size: 500,
+                compressed_size: 0,
+                status: CompressionStatus::Error,
+                message: "".to_string(),
+                error_code: None,
+                cause: None,
+            },
+        ];
+
+        let stats = CompressionStats::from_results(&results);
+        assert_eq!(stats.success, 1);
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.total_original_size, 3500);
+        assert_eq!(stats.total_compressed_size, 2800);
+        assert_eq!(stats.savings_bytes(), 700);
+        assert!((stats.savings_percent() - 20.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_compression_stats_empty() {
+        let stats = CompressionStats::from_results(&[]);
+        assert_eq!(stats.success, 0);
+        assert_eq!(stats.skipped, 0);
+        assert_eq!(stats.errors, 0);
+        assert_eq!(stats.total_original_size, 0);
+        assert_eq!(stats.total_compressed_size, 0);
+        assert_eq!(stats.savings_bytes(), 0);
+        assert_eq!(stats.savings_percent(), 0.0);
+    }
+
+    #[test]
+    fn test_compression_stats_size_increase() {
+        let results = vec![CompressionResult {
+            original_path: "a.jpg".to_string(),
+            output_path: "a_out.jpg".to_string(),
+            original_size: 800,
+            compressed_size: 1000,
+            status: CompressionStatus::Success,
+            message: "".to_string(),
+            error_code: None,
+            cause: None,
+        }];
+
+        let stats = CompressionStats::from_results(&results);
+        assert_eq!(stats.savings_bytes(), -200);
+        assert!(stats.savings_percent() < 0.0);
+    }
+}
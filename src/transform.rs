@@ -0,0 +1,886 @@
+use crate::compressor::{self, CompressionOptions, OutputFormat, WatermarkOptions};
+use crate::metadata::{self, ImageMetadata};
+use clap::ValueEnum;
+use image::codecs::gif::GifDecoder;
+use image::imageops::{self, FilterType};
+use image::metadata::Orientation;
+use image::{AnimationDecoder, ColorType, DynamicImage, ImageDecoder, ImageFormat, ImageReader, RgbaImage};
+use imagequant::RGBA;
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+use mozjpeg_sys::*;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+use std::mem;
+use std::panic::catch_unwind;
+use std::sync::OnceLock;
+
+/// Space between the watermark and the image edges, relative to the shortest side
+const WATERMARK_MARGIN: f32 = 0.02;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum Rotation {
+    #[value(name = "90")]
+    Rotate90,
+    #[value(name = "180")]
+    Rotate180,
+    #[value(name = "270")]
+    Rotate270,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum Flip {
+    Horizontal,
+    Vertical,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum SrgbProfile {
+    /// Leave the output untagged, which viewers treat as sRGB
+    Strip,
+    /// Embed a compact sRGB profile
+    Embed,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum WatermarkPosition {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+struct DecodedImage {
+    image: DynamicImage,
+    orientation: Orientation,
+    exif: Option<Vec<u8>>,
+    icc_profile: Option<Vec<u8>>,
+}
+
+/// The requested transforms cannot be applied to this image, which must not be written without them
+#[derive(Debug)]
+pub struct UnsupportedTransform(&'static str);
+
+impl Display for UnsupportedTransform {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for UnsupportedTransform {}
+
+pub fn is_transform_needed(options: &CompressionOptions) -> bool {
+    options.auto_orient
+        || options.rotate.is_some()
+        || options.flip.is_some()
+        || options.convert_to_srgb
+        || options.grayscale
+        || options.colors.is_some()
+        || options.watermark.is_some()
+}
+
+/// The watermark must be applied to the resized image and resizing before the color reduction would bring
+/// intermediate colors back, so in those cases it is done here instead of by the compressor
+pub fn is_resize_done_by_transform(buffer: &[u8], options: &CompressionOptions) -> bool {
+    compressor::is_resize_needed(options) && (is_color_reduction_needed(buffer, options) || options.watermark.is_some())
+}
+
+/// Applies the requested pixel operations to the image in `buffer`.
+/// The result is a lossless PNG carrying over the original EXIF data and the color profile, unless the pixels were
+/// converted to sRGB, ready to be converted to the final format, or a JPEG if only a rotation/flip was needed and it
+/// could be done without decoding.
+/// Returns `None` if there is nothing to do on this specific image, and an [`UnsupportedTransform`] error for animated
+/// GIF files kept as GIF, as only their first frame could be transformed.
+pub fn apply_transforms(buffer: &[u8], options: &CompressionOptions) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    if !is_transform_needed(options) {
+        return Ok(None);
+    }
+
+    let requested_orientation = requested_orientation(options.rotate, options.flip);
+    let orients = options.auto_orient && read_orientation(buffer) != Orientation::NoTransforms;
+    let converts_to_srgb = is_srgb_conversion_needed(buffer, options);
+    let reduces_colors = is_color_reduction_needed(buffer, options);
+    let resizes = is_resize_done_by_transform(buffer, options);
+
+    if !orients && !converts_to_srgb && !options.grayscale && !reduces_colors && options.watermark.is_none() {
+        if requested_orientation == Orientation::NoTransforms {
+            return Ok(None);
+        }
+        if infer::image::is_jpeg(buffer) {
+            if let Some(transformed) = transform_jpeg_lossless(buffer, requested_orientation)? {
+                return Ok(Some(transformed));
+            }
+        }
+    }
+
+    // Converting to another format keeps only the first frame anyway
+    if infer::image::is_gif(buffer) && output_format(buffer, options) == OutputFormat::Gif && is_animated_gif(buffer) {
+        return Err(UnsupportedTransform("Transforms cannot be applied to animated GIF files").into());
+    }
+
+    let mut decoded = decode_image(buffer)?;
+
+    if converts_to_srgb {
+        if let Some(profile) = decoded.icc_profile.as_deref().and_then(non_srgb_profile) {
+            decoded.image = convert_to_srgb(&decoded.image, &profile)?;
+            decoded.icc_profile = srgb_output_profile(options);
+        }
+    }
+
+    if orients {
+        decoded.image.apply_orientation(decoded.orientation);
+        if let Some(exif) = decoded.exif.as_mut() {
+            // Viewers would rotate the image again if the tag were left behind
+            if Orientation::remove_from_exif_chunk(exif).is_none() && decoded.orientation != Orientation::NoTransforms {
+                return Err("Could not remove the EXIF orientation of the image".into());
+            }
+        }
+    }
+
+    decoded.image.apply_orientation(requested_orientation);
+
+    if resizes {
+        // Sizes refer to the image as displayed, which may be rotated by the EXIF orientation that is kept
+        let rotated = !orients
+            && metadata::keeps_exif(options)
+            && matches!(
+                decoded.orientation,
+                Orientation::Rotate90
+                    | Orientation::Rotate270
+                    | Orientation::Rotate90FlipH
+                    | Orientation::Rotate270FlipH
+            );
+        decoded.image = resize(&decoded.image, options, rotated);
+    }
+
+    if let Some(watermark) = &options.watermark {
+        decoded.image = apply_watermark(&decoded.image, watermark);
+    }
+
+    if options.grayscale {
+        decoded.image = decoded.image.grayscale();
+    }
+
+    if let Some(colors) = options.colors.filter(|_| reduces_colors) {
+        decoded.image = reduce_colors(&decoded.image, colors, options.dithering)?;
+    }
+
+    Ok(Some(encode_lossless(&decoded)?))
+}
+
+/// Combines the rotation and the flip into a single orientation, the flip being applied last
+fn requested_orientation(rotate: Option<Rotation>, flip: Option<Flip>) -> Orientation {
+    match (rotate, flip) {
+        (None, None) => Orientation::NoTransforms,
+        (None, Some(Flip::Horizontal)) | (Some(Rotation::Rotate180), Some(Flip::Vertical)) => {
+            Orientation::FlipHorizontal
+        }
+        (None, Some(Flip::Vertical)) | (Some(Rotation::Rotate180), Some(Flip::Horizontal)) => Orientation::FlipVertical,
+        (Some(Rotation::Rotate90), None) => Orientation::Rotate90,
+        (Some(Rotation::Rotate180), None) => Orientation::Rotate180,
+        (Some(Rotation::Rotate270), None) => Orientation::Rotate270,
+        (Some(Rotation::Rotate90), Some(Flip::Horizontal)) | (Some(Rotation::Rotate270), Some(Flip::Vertical)) => {
+            Orientation::Rotate90FlipH
+        }
+        (Some(Rotation::Rotate270), Some(Flip::Horizontal)) | (Some(Rotation::Rotate90), Some(Flip::Vertical)) => {
+            Orientation::Rotate270FlipH
+        }
+    }
+}
+
+/// Whether `--colors` applies to this image, only PNG and GIF outputs being reduced to a palette
+fn is_color_reduction_needed(buffer: &[u8], options: &CompressionOptions) -> bool {
+    options.colors.is_some() && matches!(output_format(buffer, options), OutputFormat::Png | OutputFormat::Gif)
+}
+
+fn output_format(buffer: &[u8], options: &CompressionOptions) -> OutputFormat {
+    match options.format {
+        OutputFormat::Original => compressor::output_format_from_buffer(buffer),
+        format => format,
+    }
+}
+
+fn is_animated_gif(buffer: &[u8]) -> bool {
+    GifDecoder::new(Cursor::new(buffer)).is_ok_and(|decoder| decoder.into_frames().take(2).count() > 1)
+}
+
+/// Resizes like the compressor would, keeping the aspect ratio when only one side is known
+fn resize(image: &DynamicImage, options: &CompressionOptions, rotated: bool) -> DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    let (target_width, target_height) = if rotated {
+        let (target_height, target_width) = compressor::resize_target(options, height as usize, width as usize);
+        (target_width, target_height)
+    } else {
+        compressor::resize_target(options, width as usize, height as usize)
+    };
+
+    if target_width == 0 && target_height == 0 {
+        return image.clone();
+    }
+
+    let ratio = width as f32 / height as f32;
+    let (target_width, target_height) = match (target_width, target_height) {
+        (0, h) => (((h as f32) * ratio).round() as u32, h),
+        (w, 0) => (w, ((w as f32) / ratio).round() as u32),
+        (w, h) => (w, h),
+    };
+
+    image.resize_exact(target_width.max(1), target_height.max(1), FilterType::Lanczos3)
+}
+
+/// Overlays the watermark, scaled relative to the image width and kept inside it, with a margin from the edges
+fn apply_watermark(image: &DynamicImage, watermark: &WatermarkOptions) -> DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    let margin = (width.min(height) as f32 * WATERMARK_MARGIN).round() as u32;
+    let ratio = watermark.image.width() as f32 / watermark.image.height() as f32;
+
+    let mut mark_width = (width as f32 * watermark.scale).round().max(1.0);
+    let mut mark_height = (mark_width / ratio).round().max(1.0);
+    if mark_height > height as f32 {
+        mark_height = height as f32;
+        mark_width = (mark_height * ratio).round().max(1.0);
+    }
+    let mut mark = imageops::resize(
+        &watermark.image,
+        mark_width as u32,
+        mark_height as u32,
+        FilterType::Lanczos3,
+    );
+    if watermark.opacity < 1.0 {
+        for pixel in mark.pixels_mut() {
+            pixel[3] = (pixel[3] as f32 * watermark.opacity).round() as u8;
+        }
+    }
+
+    let free_width = width.saturating_sub(mark.width()) as i64;
+    let free_height = height.saturating_sub(mark.height()) as i64;
+    let margin_x = (margin as i64).min(free_width / 2);
+    let margin_y = (margin as i64).min(free_height / 2);
+    let (x, y) = match watermark.position {
+        WatermarkPosition::TopLeft => (margin_x, margin_y),
+        WatermarkPosition::Top => (free_width / 2, margin_y),
+        WatermarkPosition::TopRight => (free_width - margin_x, margin_y),
+        WatermarkPosition::Left => (margin_x, free_height / 2),
+        WatermarkPosition::Center => (free_width / 2, free_height / 2),
+        WatermarkPosition::Right => (free_width - margin_x, free_height / 2),
+        WatermarkPosition::BottomLeft => (margin_x, free_height - margin_y),
+        WatermarkPosition::Bottom => (free_width / 2, free_height - margin_y),
+        WatermarkPosition::BottomRight => (free_width - margin_x, free_height - margin_y),
+    };
+
+    let mut output = image.to_rgba8();
+    imageops::overlay(&mut output, &mark, x, y);
+
+    if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(output)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(output).to_rgb8())
+    }
+}
+
+/// Quantizes the image to a palette of at most `colors` colors, keeping its grayscale or alpha channels
+fn reduce_colors(image: &DynamicImage, colors: u32, dithering: f32) -> Result<DynamicImage, Box<dyn Error>> {
+    let rgba = image.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let pixels: Vec<RGBA> = rgba.pixels().map(|p| RGBA::new(p[0], p[1], p[2], p[3])).collect();
+
+    let mut liq = imagequant::new();
+    liq.set_max_colors(colors)?;
+    let mut liq_image = liq.new_image(pixels, width as usize, height as usize, 0.0)?;
+    let mut quantization = liq.quantize(&mut liq_image)?;
+    quantization.set_dithering_level(dithering)?;
+    let (palette, indexes) = quantization.remapped(&mut liq_image)?;
+
+    let buffer = indexes
+        .iter()
+        .flat_map(|index| {
+            let color = palette[*index as usize];
+            [color.r, color.g, color.b, color.a]
+        })
+        .collect();
+    let reduced = DynamicImage::ImageRgba8(RgbaImage::from_raw(width, height, buffer).ok_or("Invalid palette image")?);
+
+    Ok(match image.color() {
+        ColorType::L8 | ColorType::L16 => DynamicImage::ImageLuma8(reduced.to_luma8()),
+        ColorType::La8 | ColorType::La16 => DynamicImage::ImageLumaA8(reduced.to_luma_alpha8()),
+        color if color.has_alpha() => reduced,
+        _ => DynamicImage::ImageRgb8(reduced.to_rgb8()),
+    })
+}
+
+/// Whether `--convert-to-srgb` applies to this image, which must embed an RGB profile other than sRGB
+pub fn is_srgb_conversion_needed(buffer: &[u8], options: &CompressionOptions) -> bool {
+    if !options.convert_to_srgb || infer::image::is_gif(buffer) {
+        return false;
+    }
+
+    ImageReader::new(Cursor::new(buffer))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .and_then(|mut decoder| decoder.icc_profile().ok().flatten())
+        .and_then(|icc_profile| non_srgb_profile(&icc_profile))
+        .is_some()
+}
+
+/// Profile to embed in the images converted to sRGB, if any
+pub fn srgb_output_profile(options: &CompressionOptions) -> Option<Vec<u8>> {
+    static SRGB_PROFILE: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+
+    match options.srgb_profile {
+        SrgbProfile::Embed if !options.strip_icc => SRGB_PROFILE
+            .get_or_init(|| ColorProfile::new_srgb().encode().ok())
+            .clone(),
+        _ => None,
+    }
+}
+
+/// Parses the ICC profile, returning it only if it is an RGB one that does not match sRGB.
+/// Profiles that cannot be parsed, or describing other color spaces like CMYK, are left alone.
+fn non_srgb_profile(icc_profile: &[u8]) -> Option<ColorProfile> {
+    const TOLERANCE: f64 = 0.001;
+
+    let profile = ColorProfile::new_from_slice(icc_profile).ok()?;
+    if profile.color_space != DataColorSpace::Rgb {
+        return None;
+    }
+
+    let srgb = ColorProfile::new_srgb();
+    let same_colorants = [
+        (profile.red_colorant, srgb.red_colorant),
+        (profile.green_colorant, srgb.green_colorant),
+        (profile.blue_colorant, srgb.blue_colorant),
+    ]
+    .iter()
+    .all(|(a, b)| (a.x - b.x).abs() < TOLERANCE && (a.y - b.y).abs() < TOLERANCE && (a.z - b.z).abs() < TOLERANCE);
+
+    if profile.is_matrix_shaper() && same_colorants {
+        None
+    } else {
+        Some(profile)
+    }
+}
+
+fn convert_to_srgb(image: &DynamicImage, profile: &ColorProfile) -> Result<DynamicImage, Box<dyn Error>> {
+    let srgb = ColorProfile::new_srgb();
+    let transform_options = TransformOptions::default();
+    let high_bit_depth = image.color().bytes_per_pixel() / image.color().channel_count() > 1;
+
+    let converted = match (high_bit_depth, image.color().has_alpha()) {
+        (false, false) => {
+            let source = image.to_rgb8();
+            let mut output = source.clone();
+            profile
+                .create_transform_8bit(Layout::Rgb, &srgb, Layout::Rgb, transform_options)?
+                .transform(&source, &mut output)?;
+            DynamicImage::ImageRgb8(output)
+        }
+        (false, true) => {
+            let source = image.to_rgba8();
+            let mut output = source.clone();
+            profile
+                .create_transform_8bit(Layout::Rgba, &srgb, Layout::Rgba, transform_options)?
+                .transform(&source, &mut output)?;
+            DynamicImage::ImageRgba8(output)
+        }
+        (true, false) => {
+            let source = image.to_rgb16();
+            let mut output = source.clone();
+            profile
+                .create_transform_16bit(Layout::Rgb, &srgb, Layout::Rgb, transform_options)?
+                .transform(&source, &mut output)?;
+            DynamicImage::ImageRgb16(output)
+        }
+        (true, true) => {
+            let source = image.to_rgba16();
+            let mut output = source.clone();
+            profile
+                .create_transform_16bit(Layout::Rgba, &srgb, Layout::Rgba, transform_options)?
+                .transform(&source, &mut output)?;
+            DynamicImage::ImageRgba16(output)
+        }
+    };
+
+    Ok(converted)
+}
+
+fn read_orientation(buffer: &[u8]) -> Orientation {
+    ImageReader::new(Cursor::new(buffer))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .and_then(|mut decoder| decoder.orientation().ok())
+        .unwrap_or(Orientation::NoTransforms)
+}
+
+fn decode_image(buffer: &[u8]) -> Result<DecodedImage, Box<dyn Error>> {
+    let mut decoder = ImageReader::new(Cursor::new(buffer))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let exif = decoder.exif_metadata().unwrap_or(None);
+    let icc_profile = decoder.icc_profile().unwrap_or(None);
+    let image = DynamicImage::from_decoder(decoder)?;
+
+    Ok(DecodedImage {
+        image,
+        orientation,
+        exif,
+        icc_profile,
+    })
+}
+
+fn encode_lossless(decoded: &DecodedImage) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut output = Vec::new();
+    decoded
+        .image
+        .write_to(&mut Cursor::new(&mut output), ImageFormat::Png)?;
+
+    let metadata = ImageMetadata {
+        exif: decoded.exif.clone(),
+        icc_profile: decoded.icc_profile.clone(),
+        ..Default::default()
+    };
+    metadata::write_metadata(output, &metadata)
+}
+
+/// Rotates or flips a JPEG working directly on its DCT coefficients, like jpegtran does.
+/// Returns `None` if the image size is not a multiple of the MCU size, as the edges could not be transformed perfectly.
+fn transform_jpeg_lossless(buffer: &[u8], orientation: Orientation) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let transform = match orientation {
+        Orientation::NoTransforms => JXFORM_CODE_JXFORM_NONE,
+        Orientation::Rotate90 => JXFORM_CODE_JXFORM_ROT_90,
+        Orientation::Rotate180 => JXFORM_CODE_JXFORM_ROT_180,
+        Orientation::Rotate270 => JXFORM_CODE_JXFORM_ROT_270,
+        Orientation::FlipHorizontal => JXFORM_CODE_JXFORM_FLIP_H,
+        Orientation::FlipVertical => JXFORM_CODE_JXFORM_FLIP_V,
+        Orientation::Rotate90FlipH => JXFORM_CODE_JXFORM_TRANSPOSE,
+        Orientation::Rotate270FlipH => JXFORM_CODE_JXFORM_TRANSVERSE,
+    };
+
+    catch_unwind(|| unsafe { jpeg_lossless_transform(buffer, transform) })
+        .map_err(|_| "Internal JPEG error during lossless transform".into())
+}
+
+/// Mirrors `jpeg_transform_info` from the vendored transupp.h: the binding shipped by mozjpeg-sys lacks the `drop_*`
+/// fields, which would let the C side write past the end of the struct.
+#[repr(C)]
+struct TransformInfo {
+    transform: JXFORM_CODE,
+    perfect: boolean,
+    trim: boolean,
+    force_grayscale: boolean,
+    crop: boolean,
+    slow_hflip: boolean,
+    crop_width: JDIMENSION,
+    crop_width_set: JCROP_CODE,
+    crop_height: JDIMENSION,
+    crop_height_set: JCROP_CODE,
+    crop_xoffset: JDIMENSION,
+    crop_xoffset_set: JCROP_CODE,
+    crop_yoffset: JDIMENSION,
+    crop_yoffset_set: JCROP_CODE,
+    drop_ptr: j_decompress_ptr,
+    drop_coef_arrays: *mut jvirt_barray_ptr,
+    num_components: c_int,
+    workspace_coef_arrays: *mut jvirt_barray_ptr,
+    output_width: JDIMENSION,
+    output_height: JDIMENSION,
+    x_crop_offset: JDIMENSION,
+    y_crop_offset: JDIMENSION,
+    drop_width: JDIMENSION,
+    drop_height: JDIMENSION,
+    imcu_sample_width: c_int,
+    imcu_sample_height: c_int,
+}
+
+/// State of a lossless transform, released when it is dropped, whether the transform ended or the error handler
+/// panicked. It is kept boxed, as libjpeg holds pointers to the error managers and to the output buffer fields
+struct LosslessTransform {
+    src_info: jpeg_decompress_struct,
+    src_err: jpeg_error_mgr,
+    dst_info: jpeg_compress_struct,
+    dst_err: jpeg_error_mgr,
+    output_buffer: *mut u8,
+    output_size: c_ulong,
+}
+
+impl Drop for LosslessTransform {
+    fn drop(&mut self) {
+        unsafe {
+            jpeg_destroy_compress(&mut self.dst_info);
+            jpeg_destroy_decompress(&mut self.src_info);
+            // Allocated by jpeg_mem_dest, which leaves it to the caller
+            if !self.output_buffer.is_null() {
+                libc::free(self.output_buffer as *mut c_void);
+            }
+        }
+    }
+}
+
+unsafe fn jpeg_lossless_transform(buffer: &[u8], transform: JXFORM_CODE) -> Option<Vec<u8>> {
+    let mut state: Box<LosslessTransform> = Box::new(mem::zeroed());
+    let LosslessTransform {
+        src_info,
+        src_err,
+        dst_info,
+        dst_err,
+        output_buffer,
+        output_size,
+    } = &mut *state;
+
+    src_info.common.err = jpeg_std_error(src_err);
+    (*src_info.common.err).error_exit = Some(jpeg_error_handler);
+    (*src_info.common.err).output_message = Some(jpeg_message_handler);
+    dst_info.common.err = jpeg_std_error(dst_err);
+    (*dst_info.common.err).error_exit = Some(jpeg_error_handler);
+    (*dst_info.common.err).output_message = Some(jpeg_message_handler);
+
+    jpeg_create_decompress(src_info);
+    jpeg_create_compress(dst_info);
+    jpeg_mem_src(src_info, buffer.as_ptr(), buffer.len() as _);
+    jcopy_markers_setup(src_info, JCOPY_OPTION_JCOPYOPT_ALL);
+    jpeg_read_header(src_info, true as boolean);
+
+    let mut transform_info: TransformInfo = mem::zeroed();
+    transform_info.transform = transform;
+    transform_info.perfect = true as boolean;
+    let transform_info = &mut transform_info as *mut TransformInfo as *mut jpeg_transform_info;
+    if jtransform_request_workspace(src_info, transform_info) == 0 {
+        return None;
+    }
+
+    let src_coef_arrays = jpeg_read_coefficients(src_info);
+    jpeg_copy_critical_parameters(src_info, dst_info);
+    let dst_coef_arrays = jtransform_adjust_parameters(src_info, dst_info, src_coef_arrays, transform_info);
+
+    jpeg_mem_dest(dst_info, output_buffer, output_size);
+    jpeg_write_coefficients(dst_info, dst_coef_arrays);
+    jcopy_markers_execute(src_info, dst_info, JCOPY_OPTION_JCOPYOPT_ALL);
+    jtransform_execute_transform(src_info, dst_info, src_coef_arrays, transform_info);
+
+    jpeg_finish_compress(dst_info);
+    jpeg_finish_decompress(src_info);
+
+    Some(std::slice::from_raw_parts(*output_buffer, *output_size as usize).to_vec())
+}
+
+unsafe extern "C-unwind" fn jpeg_error_handler(cinfo: &mut jpeg_common_struct) {
+    panic!("Internal JPEG error: {}", (*cinfo.err).msg_code);
+}
+
+unsafe extern "C-unwind" fn jpeg_message_handler(_cinfo: &mut jpeg_common_struct) {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::compressor::tests::setup_options;
+    use image::RgbImage;
+    use img_parts::jpeg::Jpeg;
+    use img_parts::{Bytes, ImageEXIF};
+
+    /// Minimal big-endian TIFF header with a single IFD0 entry holding the orientation tag
+    fn exif_with_orientation(orientation: u16) -> Vec<u8> {
+        let mut exif = vec![0x4d, 0x4d, 0x00, 0x2a, 0x00, 0x00, 0x00, 0x08, 0x00, 0x01];
+        exif.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01]);
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        exif
+    }
+
+    fn jpeg_with_orientation(width: u32, height: u32, orientation: u16) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        RgbImage::new(width, height)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg)
+            .unwrap();
+        let mut jpeg = Jpeg::from_bytes(Bytes::from(bytes)).unwrap();
+        jpeg.set_exif(Some(Bytes::from(exif_with_orientation(orientation))));
+        let mut output = Vec::new();
+        jpeg.encoder().write_to(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_auto_orient_rotates_pixels() {
+        let buffer = jpeg_with_orientation(4, 2, 6);
+        let mut options = setup_options();
+        options.auto_orient = true;
+
+        let transformed = apply_transforms(&buffer, &options).unwrap().unwrap();
+        let decoded = decode_image(&transformed).unwrap();
+        assert_eq!((decoded.image.width(), decoded.image.height()), (2, 4));
+        assert_eq!(decoded.orientation, Orientation::NoTransforms);
+        assert!(decoded.exif.is_some());
+    }
+
+    #[test]
+    fn test_requested_orientation() {
+        assert_eq!(requested_orientation(None, None), Orientation::NoTransforms);
+        assert_eq!(
+            requested_orientation(Some(Rotation::Rotate90), None),
+            Orientation::Rotate90
+        );
+        assert_eq!(
+            requested_orientation(None, Some(Flip::Vertical)),
+            Orientation::FlipVertical
+        );
+        assert_eq!(
+            requested_orientation(Some(Rotation::Rotate180), Some(Flip::Horizontal)),
+            Orientation::FlipVertical
+        );
+        assert_eq!(
+            requested_orientation(Some(Rotation::Rotate90), Some(Flip::Horizontal)),
+            Orientation::Rotate90FlipH
+        );
+        assert_eq!(
+            requested_orientation(Some(Rotation::Rotate90), Some(Flip::Vertical)),
+            Orientation::Rotate270FlipH
+        );
+    }
+
+    #[test]
+    fn test_rotate_jpeg_lossless() {
+        // 32x16 is a multiple of the MCU size, so the coefficients can be rotated directly
+        let buffer = jpeg_with_orientation(32, 16, 1);
+        let mut options = setup_options();
+        options.rotate = Some(Rotation::Rotate90);
+
+        let transformed = apply_transforms(&buffer, &options).unwrap().unwrap();
+        assert!(infer::image::is_jpeg(&transformed));
+        let decoded = decode_image(&transformed).unwrap();
+        assert_eq!((decoded.image.width(), decoded.image.height()), (16, 32));
+        assert!(decoded.exif.is_some());
+
+        // libjpeg errors end the transform, the state being released on the way out
+        assert!(transform_jpeg_lossless(&buffer[..20], Orientation::Rotate90).is_err());
+    }
+
+    #[test]
+    fn test_rotate_jpeg_falls_back_to_pixels() {
+        let buffer = jpeg_with_orientation(30, 10, 1);
+        let mut options = setup_options();
+        options.rotate = Some(Rotation::Rotate270);
+        options.flip = Some(Flip::Horizontal);
+
+        let transformed = apply_transforms(&buffer, &options).unwrap().unwrap();
+        assert!(infer::image::is_png(&transformed));
+        let decoded = decode_image(&transformed).unwrap();
+        assert_eq!((decoded.image.width(), decoded.image.height()), (10, 30));
+    }
+
+    #[test]
+    fn test_auto_orient_skips_upright_images() {
+        let mut options = setup_options();
+        options.auto_orient = true;
+
+        let buffer = jpeg_with_orientation(4, 2, 1);
+        assert!(apply_transforms(&buffer, &options).unwrap().is_none());
+
+        let buffer = jpeg_with_orientation(4, 2, 6);
+        options.auto_orient = false;
+        assert!(apply_transforms(&buffer, &options).unwrap().is_none());
+    }
+
+    fn png_with_profile(profile: &ColorProfile) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        RgbImage::from_pixel(4, 4, image::Rgb([200, 100, 50]))
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        let metadata = ImageMetadata {
+            icc_profile: Some(profile.encode().unwrap()),
+            ..Default::default()
+        };
+        metadata::write_metadata(bytes, &metadata).unwrap()
+    }
+
+    #[test]
+    fn test_convert_to_srgb() {
+        let buffer = png_with_profile(&ColorProfile::new_display_p3());
+        let mut options = setup_options();
+        assert!(!is_srgb_conversion_needed(&buffer, &options));
+        options.convert_to_srgb = true;
+        assert!(is_srgb_conversion_needed(&buffer, &options));
+
+        let transformed = apply_transforms(&buffer, &options).unwrap().unwrap();
+        let decoded = decode_image(&transformed).unwrap();
+        let pixel = decoded.image.to_rgb8().get_pixel(0, 0).0;
+        assert_ne!(pixel, [200, 100, 50]);
+        assert!(pixel[0] > 200);
+        assert!(decoded.icc_profile.is_none());
+
+        options.srgb_profile = SrgbProfile::Embed;
+        let transformed = apply_transforms(&buffer, &options).unwrap().unwrap();
+        let icc_profile = decode_image(&transformed).unwrap().icc_profile.unwrap();
+        assert!(non_srgb_profile(&icc_profile).is_none());
+    }
+
+    #[test]
+    fn test_convert_to_srgb_skips_srgb_images() {
+        let mut options = setup_options();
+        options.convert_to_srgb = true;
+
+        let buffer = png_with_profile(&ColorProfile::new_srgb());
+        assert!(!is_srgb_conversion_needed(&buffer, &options));
+        assert!(apply_transforms(&buffer, &options).unwrap().is_none());
+
+        let buffer = jpeg_with_orientation(4, 2, 1);
+        assert!(apply_transforms(&buffer, &options).unwrap().is_none());
+    }
+
+    fn gradient_png() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        RgbImage::from_fn(64, 32, |x, y| image::Rgb([(x * 4) as u8, (y * 8) as u8, 128]))
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    fn count_colors(image: &DynamicImage) -> usize {
+        let mut colors: Vec<[u8; 4]> = image.to_rgba8().pixels().map(|p| p.0).collect();
+        colors.sort_unstable();
+        colors.dedup();
+        colors.len()
+    }
+
+    #[test]
+    fn test_grayscale() {
+        let mut options = setup_options();
+        options.grayscale = true;
+
+        let transformed = apply_transforms(&gradient_png(), &options).unwrap().unwrap();
+        let decoded = decode_image(&transformed).unwrap();
+        assert_eq!(decoded.image.color(), ColorType::L8);
+    }
+
+    #[test]
+    fn test_reduce_colors() {
+        let buffer = gradient_png();
+        let mut options = setup_options();
+        options.colors = Some(16);
+        options.dithering = 0.0;
+
+        let transformed = apply_transforms(&buffer, &options).unwrap().unwrap();
+        let decoded = decode_image(&transformed).unwrap();
+        assert!(count_colors(&decoded.image) <= 16);
+        assert_eq!((decoded.image.width(), decoded.image.height()), (64, 32));
+        assert!(!is_resize_done_by_transform(&buffer, &options));
+
+        options.long_edge = Some(32);
+        assert!(is_resize_done_by_transform(&buffer, &options));
+        let transformed = apply_transforms(&buffer, &options).unwrap().unwrap();
+        let decoded = decode_image(&transformed).unwrap();
+        assert!(count_colors(&decoded.image) <= 16);
+        assert_eq!((decoded.image.width(), decoded.image.height()), (32, 16));
+
+        options.format = OutputFormat::Jpeg;
+        assert!(!is_resize_done_by_transform(&buffer, &options));
+        assert!(apply_transforms(&buffer, &options).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_resize_follows_displayed_orientation() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(40, 20));
+        let mut options = setup_options();
+        options.width = Some(10);
+
+        let resized = resize(&image, &options, false);
+        assert_eq!((resized.width(), resized.height()), (10, 5));
+        let resized = resize(&image, &options, true);
+        assert_eq!((resized.width(), resized.height()), (20, 10));
+    }
+
+    fn watermark(position: WatermarkPosition, opacity: f32) -> WatermarkOptions {
+        WatermarkOptions {
+            image: RgbaImage::from_pixel(20, 10, image::Rgba([255, 0, 0, 255])),
+            position,
+            opacity,
+            scale: 0.25,
+        }
+    }
+
+    #[test]
+    fn test_apply_watermark() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(200, 100));
+
+        let marked = apply_watermark(&image, &watermark(WatermarkPosition::BottomRight, 1.0)).to_rgb8();
+        assert_eq!(marked.dimensions(), (200, 100));
+        // 50x25 watermark, 2px margin from the bottom right corner
+        assert_eq!(marked.get_pixel(148, 73).0, [255, 0, 0]);
+        assert_eq!(marked.get_pixel(197, 97).0, [255, 0, 0]);
+        assert_eq!(marked.get_pixel(198, 98).0, [0, 0, 0]);
+        assert_eq!(marked.get_pixel(147, 73).0, [0, 0, 0]);
+        assert_eq!(marked.get_pixel(100, 50).0, [0, 0, 0]);
+
+        let marked = apply_watermark(&image, &watermark(WatermarkPosition::Center, 0.5)).to_rgb8();
+        assert_eq!(marked.get_pixel(100, 50).0, [128, 0, 0]);
+        assert_eq!(marked.get_pixel(5, 5).0, [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_watermark_after_resize() {
+        let buffer = gradient_png();
+        let mut options = setup_options();
+        options.watermark = Some(watermark(WatermarkPosition::TopLeft, 1.0));
+        assert!(!is_resize_done_by_transform(&buffer, &options));
+
+        options.width = Some(32);
+        options.format = OutputFormat::Jpeg;
+        assert!(is_resize_done_by_transform(&buffer, &options));
+        let transformed = apply_transforms(&buffer, &options).unwrap().unwrap();
+        let decoded = decode_image(&transformed).unwrap();
+        assert_eq!((decoded.image.width(), decoded.image.height()), (32, 16));
+        assert_eq!(decoded.image.to_rgb8().get_pixel(2, 2).0, [255, 0, 0]);
+    }
+
+    pub fn gif_with_frames(frames: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut bytes);
+            let frames = (0..frames).map(|index| {
+                image::Frame::new(RgbaImage::from_pixel(
+                    40,
+                    20,
+                    image::Rgba([0, 0, index as u8 * 50, 255]),
+                ))
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_transform_gif() {
+        let mut options = setup_options();
+        options.watermark = Some(watermark(WatermarkPosition::TopLeft, 1.0));
+
+        let transformed = apply_transforms(&gif_with_frames(1), &options).unwrap().unwrap();
+        assert_eq!(
+            decode_image(&transformed).unwrap().image.to_rgb8().get_pixel(1, 1).0,
+            [255, 0, 0]
+        );
+
+        let animated = gif_with_frames(2);
+        let error = apply_transforms(&animated, &options).unwrap_err();
+        assert!(error.is::<UnsupportedTransform>());
+        options.format = OutputFormat::Gif;
+        assert!(apply_transforms(&animated, &options).is_err());
+
+        options.format = OutputFormat::Webp;
+        let transformed = apply_transforms(&animated, &options).unwrap().unwrap();
+        assert_eq!(
+            decode_image(&transformed).unwrap().image.to_rgb8().get_pixel(1, 1).0,
+            [255, 0, 0]
+        );
+
+        options.watermark = None;
+        options.format = OutputFormat::Original;
+        options.colors = Some(4);
+        assert!(apply_transforms(&gif_with_frames(1), &options).unwrap().is_some());
+        assert!(apply_transforms(&animated, &options).is_err());
+    }
+}